@@ -4,24 +4,32 @@ use std::{
 };
 
 use bitcoind::{
-    miner,
-    node::Node,
-    p2p, rpc,
+    events, logger, metrics, miner,
+    node::{Node, MIN_PRUNE_DEPTH},
+    p2p,
+    rpc::{
+        self,
+        access::{self, Credentials, RpcAccess},
+    },
     settings::{self, ENV_PREFIX},
+    shutdown::{self, Shutdown},
 };
 
-use colored::*;
+use log::info;
 
 fn main() -> std::io::Result<()> {
     let config = settings::Settings::new("config.yml", ENV_PREFIX)
         .unwrap()
         .config;
-    println!("Settings: {config:#?}");
+    logger::init(config.log_level);
+    info!("Settings: {config:?}");
 
-    let data_dir = config.data_dir;
+    // Ctrl-C, SIGTERM and the stop RPC stop every thread
+    let shutdown = Shutdown::new();
+    shutdown::handle_signals(shutdown.clone())?;
 
-    let p2p_data = p2p::server::P2pData::default();
-    let p2p_data_arc = Arc::new(Mutex::new(p2p_data));
+    let data_dir = config.data_dir;
+    let host_addr = format!("{}:{}", config.host_ip, config.tcp_port);
 
     // Broadcast blocks and transactions
     let (block_tx, block_rx) = mpsc::channel();
@@ -30,39 +38,74 @@ fn main() -> std::io::Result<()> {
     // Interrupt the miner when new blocks are received throught the network
     let (miner_interrupt_tx, miner_interrupt_rx) = mpsc::channel();
 
+    // Start Node
+    if config
+        .prune_depth
+        .is_some_and(|depth| depth < MIN_PRUNE_DEPTH)
+    {
+        return Err(std::io::Error::other(format!(
+            "prune_depth must be at least {MIN_PRUNE_DEPTH}"
+        )));
+    }
+    let mut node = Node::new(block_tx, transaction_tx, &data_dir, config.network);
+    node.prune_depth = config.prune_depth;
+    if let Some(export_dir) = config.export_dir {
+        node.export_dir = export_dir;
+    }
+    node.chainstate_hash = config.chainstate_hash;
+    node.checkpoints = config.checkpoints;
+    node.assume_valid = config.assume_valid;
+
+    // The node, P2P and RPC share the node's storage handle
+    let mut p2p_data = p2p::server::P2pData::new(&host_addr, node.store.clone());
+    p2p_data.encryption = config.p2p_encryption;
+    p2p_data.identity = p2p::transport::load_identity(&data_dir)
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    p2p_data.peer_keys = config
+        .p2p_peer_keys
+        .into_iter()
+        .map(|peer| (peer.addr, peer.key))
+        .collect();
+    info!(
+        "Your node key: {}",
+        p2p_data.identity.public_key(&secp256k1::Secp256k1::new())
+    );
+    let p2p_data_arc = Arc::new(Mutex::new(p2p_data));
+
     let receiver_p2p_data_arc = p2p_data_arc.clone();
+    let receiver_shutdown = shutdown.clone();
     let receiver_thread = thread::spawn(move || {
-        p2p::run_receiver(receiver_p2p_data_arc, block_rx, transaction_rx).unwrap();
+        p2p::run_receiver(
+            receiver_p2p_data_arc,
+            block_rx,
+            transaction_rx,
+            receiver_shutdown,
+        )
+        .unwrap();
     });
 
-    // Start Node
-    let node = Node::new(block_tx, transaction_tx, &data_dir);
     let node_arc = Arc::new(Mutex::new(node));
     {
         let mut node_instance = node_arc.lock().unwrap();
         node_instance.start().expect("Started Bitcoind failed");
-        println!(
-            "{}",
-            format!("Your public key: {}", node_instance.keypair.public_key).yellow()
-        );
+        info!("Your public key: {}", node_instance.keypair.public_key);
+        info!("Your address: {}", node_instance.address());
     }
 
     // Start P2P
     let p2p_node_clone = node_arc.clone();
-    let tcp_port = config.tcp_port;
     let server_p2p_data_clone = p2p_data_arc.clone();
-    let host_addr = format!("{}:{}", config.host_ip, tcp_port);
     let run_server_host_addr = host_addr.clone();
 
     let p2p_miner_interrupt_tx = miner_interrupt_tx.clone();
-    let p2p_data_dir = data_dir.clone();
+    let p2p_shutdown = shutdown.clone();
     let p2p_thread = thread::spawn(move || {
         p2p::run(
             p2p_node_clone.clone(),
             server_p2p_data_clone,
             run_server_host_addr,
             p2p_miner_interrupt_tx,
-            &p2p_data_dir,
+            p2p_shutdown,
         )
         .unwrap();
     });
@@ -71,8 +114,9 @@ fn main() -> std::io::Result<()> {
     let p2p_node_miner = node_arc.clone();
     let miner_thread = if config.miner_enabled {
         let miner_node_clone = p2p_node_miner;
+        let miner_shutdown = shutdown.clone();
         Some(thread::spawn(move || {
-            miner::start_miner(miner_node_clone, miner_interrupt_rx)
+            miner::start_miner(miner_node_clone, miner_interrupt_rx, miner_shutdown)
         }))
     } else {
         None
@@ -82,22 +126,61 @@ fn main() -> std::io::Result<()> {
     let p2p_node_clone = node_arc.clone();
     let p2p_data_clone = p2p_data_arc.clone();
     let init_host_addr = &host_addr;
-    let p2p_data_dir = data_dir.clone();
     p2p::init(
         p2p_node_clone.clone(),
         p2p_data_clone,
         miner_interrupt_tx,
         init_host_addr,
         config.bootstrap_nodes,
-        &p2p_data_dir,
     )
     .unwrap();
 
-    // Start RPC
+    // Start metrics
+    let metrics_thread = config.metrics_port.map(|port| {
+        let metrics_node = node_arc.clone();
+        let metrics_p2p_data = p2p_data_arc.clone();
+        let metrics_host = config.host_ip.clone();
+        let metrics_shutdown = shutdown.clone();
+        thread::spawn(move || {
+            metrics::run_server(
+                metrics_node,
+                metrics_p2p_data,
+                metrics_host,
+                port,
+                metrics_shutdown,
+            )
+            .unwrap();
+        })
+    });
+
+    // Start events
+    let events_thread = config.events_port.map(|port| {
+        let events = node_arc.lock().unwrap().events.clone();
+        let events_host = config.host_ip.clone();
+        let events_shutdown = shutdown.clone();
+        thread::spawn(move || {
+            events::run_server(events, events_host, port, events_shutdown).unwrap();
+        })
+    });
+
+    // Start RPC, with the cookie file unless credentials are configured
+    let credentials = match (config.rpc_user, config.rpc_password) {
+        (Some(user), Some(password)) => Credentials::new(user, password.0),
+        _ => Credentials::cookie(&data_dir).map_err(std::io::Error::other)?,
+    };
+    let rpc_access = RpcAccess::new(
+        credentials,
+        &config.rpc_allow_ips,
+        config.rpc_rate_limit,
+        config.rpc_read_only,
+    )
+    .map_err(std::io::Error::other)?;
     let rpc_node_clone = node_arc.clone();
     let rpc_port = config.rpc_port;
+    let rpc_host = config.host_ip;
+    let rpc_shutdown = shutdown.clone();
     let rpc_thread = thread::spawn(move || {
-        rpc::run_server(rpc_node_clone, host_addr, rpc_port).unwrap();
+        rpc::run_server(rpc_node_clone, rpc_host, rpc_port, rpc_access, rpc_shutdown).unwrap();
     });
 
     // // Web
@@ -106,6 +189,9 @@ fn main() -> std::io::Result<()> {
     //     web::run_server(web_port);
     // });
 
+    shutdown.wait();
+    info!("Shutting down");
+
     // // Join threads
     receiver_thread.join().unwrap();
     rpc_thread.join().unwrap();
@@ -113,7 +199,23 @@ fn main() -> std::io::Result<()> {
     if let Some(t) = miner_thread {
         t.join().unwrap();
     }
+    if let Some(t) = metrics_thread {
+        t.join().unwrap();
+    }
+    if let Some(t) = events_thread {
+        t.join().unwrap();
+    }
     // web_thread.join().unwrap();
 
+    access::remove_cookie(&data_dir)?;
+
+    // Every thread is done with the node, persist its state
+    node_arc
+        .lock()
+        .unwrap()
+        .shutdown()
+        .map_err(std::io::Error::other)?;
+    info!("Bitcoind stopped");
+
     Ok(())
 }
@@ -0,0 +1,99 @@
+//! Portable block files, like Bitcoin's `blk*.dat`.
+//!
+//! Blocks are stored one after the other in height order, each record being
+//! the network magic, the length of the block as a little endian `u32` and the
+//! block as JSON.
+
+use std::io::{ErrorKind, Read, Write};
+
+use crate::crypto::Network;
+
+use super::Block;
+
+/// Larger records are rejected before allocating for them
+pub const MAX_RECORD_SIZE: u32 = 32 * 1024 * 1024;
+
+pub fn write_block(writer: &mut impl Write, network: Network, block: &Block) -> Result<(), String> {
+    let data = serde_json::to_vec(block).map_err(|e| e.to_string())?;
+    let len = u32::try_from(data.len()).map_err(|_| "Block too large".to_string())?;
+
+    writer
+        .write_all(&network.magic())
+        .map_err(|e| e.to_string())?;
+    writer
+        .write_all(&len.to_le_bytes())
+        .map_err(|e| e.to_string())?;
+    writer.write_all(&data).map_err(|e| e.to_string())
+}
+
+/// Reads the next block, `None` at the end of the file
+pub fn read_block(reader: &mut impl Read, network: Network) -> Result<Option<Block>, String> {
+    let mut magic = [0u8; 4];
+    match reader.read_exact(&mut magic) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    }
+    if magic != network.magic() {
+        return Err(format!("Block file isn't for {network}"));
+    }
+
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len).map_err(|e| e.to_string())?;
+    let len = u32::from_le_bytes(len);
+    if len > MAX_RECORD_SIZE {
+        return Err(format!("Block record of {len} bytes is too large"));
+    }
+
+    let mut data = vec![0u8; len as usize];
+    reader
+        .read_exact(&mut data)
+        .map_err(|e| format!("Truncated block record: {e}"))?;
+
+    serde_json::from_slice(&data)
+        .map(Some)
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(hash: &str) -> Block {
+        Block {
+            hash: hash.to_string(),
+            prev_block: "prev".to_string(),
+            timestamp: 1,
+            nonce: 2,
+            transactions: vec![],
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut file = vec![];
+        for hash in ["a", "b"] {
+            write_block(&mut file, Network::Regtest, &block(hash)).unwrap();
+        }
+        assert_eq!(&file[..4], &Network::Regtest.magic());
+
+        let mut reader = file.as_slice();
+        let read = |reader: &mut &[u8]| read_block(reader, Network::Regtest).unwrap();
+        assert_eq!(read(&mut reader).unwrap().hash, "a");
+        assert_eq!(read(&mut reader).unwrap().hash, "b");
+        assert!(read(&mut reader).is_none());
+    }
+
+    #[test]
+    fn test_reject_bad_records() {
+        let mut file = vec![];
+        write_block(&mut file, Network::Regtest, &block("a")).unwrap();
+
+        assert!(read_block(&mut file.as_slice(), Network::Mainnet).is_err());
+        assert!(read_block(&mut &file[..file.len() - 1], Network::Regtest).is_err());
+
+        let mut oversized = Network::Regtest.magic().to_vec();
+        oversized.extend((MAX_RECORD_SIZE + 1).to_le_bytes());
+        assert!(read_block(&mut oversized.as_slice(), Network::Regtest).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{crypto, tx::SignedTransaction};
+
+use super::{Block, BlockHeader};
+
+/// Prefixes of the hashed leaves and inner nodes, so a leaf can't pass for a node
+const LEAF_PREFIX: &str = "\u{0}";
+const NODE_PREFIX: &str = "\u{1}";
+
+/// Root of the merkle tree over the transactions, in block order. Leaves
+/// commit to the whole signed transactions, signatures and unlocking
+/// scripts included.
+///
+/// Like Bitcoin, a level with an odd number of nodes pairs its last node with itself.
+pub fn merkle_root(txs: &[SignedTransaction]) -> String {
+    let mut level = leaves(txs);
+    if level.is_empty() {
+        return leaf_hash("", "");
+    }
+
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+
+    level.remove(0)
+}
+
+fn next_level(level: &[String]) -> Vec<String> {
+    level
+        .chunks(2)
+        .map(|pair| node_hash(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+        .collect()
+}
+
+fn leaves(txs: &[SignedTransaction]) -> Vec<String> {
+    txs.iter()
+        .map(|tx| leaf_hash(&tx.tx_id(), &tx.wtx_id()))
+        .collect()
+}
+
+fn leaf_hash(tx_id: &str, wtx_id: &str) -> String {
+    hex::encode(crypto::sha256(format!("{LEAF_PREFIX}{tx_id}{wtx_id}")))
+}
+
+fn node_hash(left: &str, right: &str) -> String {
+    hex::encode(crypto::sha256(format!("{NODE_PREFIX}{left}{right}")))
+}
+
+/// Path from a transaction to the merkle root of its block.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MerkleProof {
+    pub tx_id: String,
+    /// Hash of the signed transaction, see `SignedTransaction::wtx_id`
+    pub wtx_id: String,
+    /// Position of the transaction in the block
+    pub index: u32,
+    /// Sibling hashes from the leaf level up to the root
+    pub siblings: Vec<String>,
+}
+
+impl MerkleProof {
+    pub fn new(txs: &[SignedTransaction], index: usize) -> Option<Self> {
+        let tx = txs.get(index)?;
+        let mut level = leaves(txs);
+        let mut position = index;
+        let mut siblings = vec![];
+
+        while level.len() > 1 {
+            let sibling = level.get(position ^ 1).unwrap_or(&level[position]).clone();
+            siblings.push(sibling);
+
+            level = next_level(&level);
+            position /= 2;
+        }
+
+        Some(Self {
+            tx_id: tx.tx_id(),
+            wtx_id: tx.wtx_id(),
+            index: index as u32,
+            siblings,
+        })
+    }
+
+    /// Merkle root the proof leads to, to compare with the block header's one
+    pub fn root(&self) -> String {
+        let mut position = self.index;
+        self.siblings
+            .iter()
+            .fold(leaf_hash(&self.tx_id, &self.wtx_id), |hash, sibling| {
+                let parent = if position & 1 == 0 {
+                    node_hash(&hash, sibling)
+                } else {
+                    node_hash(sibling, &hash)
+                };
+                position /= 2;
+                parent
+            })
+    }
+}
+
+/// Proof that a transaction is included in a block, checkable with the header alone.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TxOutProof {
+    pub header: BlockHeader,
+    pub proof: MerkleProof,
+}
+
+impl TxOutProof {
+    pub fn new(block: &Block, tx_id: &str) -> Option<Self> {
+        let index = block
+            .transactions
+            .iter()
+            .position(|tx| tx.tx_id() == tx_id)?;
+
+        Some(Self {
+            header: block.header(),
+            proof: MerkleProof::new(&block.transactions, index)?,
+        })
+    }
+
+    /// Checks the header and that the proof leads to its merkle root, returning
+    /// the proven transaction id.
+    pub fn verify(&self) -> Result<&str, String> {
+        self.header.verify()?;
+
+        if self.proof.root() != self.header.merkle_root {
+            return Err("Proof verification failed: Merkle root mismatch".to_string());
+        }
+
+        Ok(&self.proof.tx_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{Address, KeyPair, Network};
+    use crate::tx;
+
+    fn txs(n: usize) -> Vec<SignedTransaction> {
+        let keypair = KeyPair::new();
+        let to = Address::from_public_key(&keypair.public_key, Network::Regtest);
+        (0..n as u32)
+            .map(|amount| tx::create_signed(&keypair, to, amount))
+            .collect()
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        let txs = txs(5);
+        let leaf = |tx: &SignedTransaction| leaf_hash(&tx.tx_id(), &tx.wtx_id());
+        assert_eq!(merkle_root(&txs[..1]), leaf(&txs[0]));
+        assert_eq!(
+            merkle_root(&txs[..3]),
+            node_hash(
+                &node_hash(&leaf(&txs[0]), &leaf(&txs[1])),
+                &node_hash(&leaf(&txs[2]), &leaf(&txs[2]))
+            )
+        );
+        assert_ne!(merkle_root(&txs[..4]), merkle_root(&txs));
+
+        // Signatures are committed to, not only transaction ids
+        let mut resigned = txs.clone();
+        resigned[1].sig = txs[2].sig.clone();
+        assert_ne!(merkle_root(&resigned), merkle_root(&txs));
+
+        // An inner node isn't a leaf
+        let (left, right) = (leaf(&txs[0]), leaf(&txs[1]));
+        assert_ne!(leaf_hash(&left, &right), node_hash(&left, &right));
+    }
+
+    #[test]
+    fn test_merkle_proofs() {
+        for n in 1..=9 {
+            let txs = txs(n);
+            let root = merkle_root(&txs);
+
+            for i in 0..n {
+                let proof = MerkleProof::new(&txs, i).unwrap();
+                assert_eq!(proof.root(), root, "{n} txs, index {i}");
+
+                let mut forged = proof.clone();
+                forged.tx_id = "forged".to_string();
+                assert_ne!(forged.root(), root);
+                let mut forged = proof.clone();
+                forged.wtx_id = "forged".to_string();
+                assert_ne!(forged.root(), root);
+            }
+            assert!(MerkleProof::new(&txs, n).is_none());
+        }
+    }
+}
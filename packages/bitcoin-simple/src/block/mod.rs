@@ -1,37 +1,104 @@
+pub mod file;
+pub mod merkle;
+
 use serde::{Deserialize, Serialize};
 
-use crate::tx::SignedTransaction;
+use crate::{crypto, node::DIFFICULTY, tx::SignedTransaction};
+
+use self::merkle::merkle_root;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Block {
     pub hash: String,
     pub prev_block: String,
+    /// Unix time in seconds the block was proposed at
+    #[serde(default)]
+    pub timestamp: u64,
     pub nonce: u32,
     pub transactions: Vec<SignedTransaction>,
 }
 
 impl Block {
+    /// The hash commits to the signed transactions through their merkle root,
+    /// so a header is enough to check a transaction's inclusion proof.
     pub fn serialize(&self) -> String {
-        let txs = self
-            .transactions
-            .iter()
-            .fold(String::new(), |a, b| a + &b.to_string());
-        format!("{}{}{}", self.prev_block, txs, self.nonce)
+        self.header().serialize()
+    }
+
+    pub fn tx_ids(&self) -> Vec<String> {
+        self.transactions.iter().map(|tx| tx.tx_id()).collect()
+    }
+
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            hash: self.hash.clone(),
+            prev_block: self.prev_block.clone(),
+            merkle_root: merkle_root(&self.transactions),
+            timestamp: self.timestamp,
+            nonce: self.nonce,
+        }
+    }
+}
+
+/// A block without its transactions, what light clients sync.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub hash: String,
+    pub prev_block: String,
+    pub merkle_root: String,
+    #[serde(default)]
+    pub timestamp: u64,
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    pub fn serialize(&self) -> String {
+        format!(
+            "{}{}{}{}",
+            self.prev_block, self.merkle_root, self.timestamp, self.nonce
+        )
+    }
+
+    /// Checks the hash and proof of work, linking to the previous header is up to the caller.
+    pub fn verify(&self) -> Result<(), String> {
+        if !self.hash.starts_with(&"0".repeat(DIFFICULTY)) {
+            return Err("Header verification failed: Insufficient proof of work".to_string());
+        }
+
+        if hex::encode(crypto::sha256(self.serialize())) != self.hash {
+            return Err("Header verification failed: Hash mismatch".to_string());
+        }
+
+        Ok(())
     }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ProposedBlock {
     pub prev_block: String,
+    pub timestamp: u64,
     pub transactions: Vec<SignedTransaction>,
 }
 
 impl ProposedBlock {
+    /// Block serialization without the nonce, which the miner appends.
     pub fn serialize(&self) -> String {
-        let txs = self
-            .transactions
-            .iter()
-            .fold(String::new(), |a, b| a + &b.to_string());
-        format!("{}{}", self.prev_block, txs)
+        format!(
+            "{}{}{}",
+            self.prev_block,
+            merkle_root(&self.transactions),
+            self.timestamp
+        )
+    }
+
+    /// The block once a nonce giving a valid `hash` was found
+    pub fn mined(self, hash: String, nonce: u32) -> Block {
+        Block {
+            hash,
+            prev_block: self.prev_block,
+            timestamp: self.timestamp,
+            nonce,
+            transactions: self.transactions,
+        }
     }
 }
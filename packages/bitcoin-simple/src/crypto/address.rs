@@ -0,0 +1,225 @@
+use std::fmt::{Debug, Display};
+use std::str::FromStr;
+
+use bech32::{FromBase32, ToBase32, Variant};
+use ripemd::Ripemd160;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::key::PublicKey;
+use crate::script::Script;
+
+/// Witness version encoded in front of the hash, like P2WPKH and P2WSH
+const ADDRESS_VERSION: u8 = 0;
+const PUBKEY_HASH_LEN: usize = 20;
+const SCRIPT_HASH_LEN: usize = 32;
+
+/// Chain an address belongs to, encoded as the bech32 human readable part
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+impl Network {
+    pub fn hrp(&self) -> &'static str {
+        match self {
+            Self::Mainnet => "bc",
+            Self::Testnet => "tb",
+            Self::Regtest => "bcrt",
+        }
+    }
+
+    /// Start of every message or record belonging to the network, Bitcoin's values
+    pub fn magic(&self) -> [u8; 4] {
+        match self {
+            Self::Mainnet => [0xf9, 0xbe, 0xb4, 0xd9],
+            Self::Testnet => [0x0b, 0x11, 0x09, 0x07],
+            Self::Regtest => [0xfa, 0xbf, 0xb5, 0xda],
+        }
+    }
+
+    fn from_hrp(hrp: &str) -> Option<Self> {
+        [Self::Mainnet, Self::Testnet, Self::Regtest]
+            .into_iter()
+            .find(|n| n.hrp() == hrp)
+    }
+}
+
+impl Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Mainnet => "mainnet",
+            Self::Testnet => "testnet",
+            Self::Regtest => "regtest",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// What the coins sent to an address are locked to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Payload {
+    PubkeyHash([u8; PUBKEY_HASH_LEN]),
+    /// Spent by revealing a script with this hash and data that satisfies it
+    ScriptHash([u8; SCRIPT_HASH_LEN]),
+}
+
+impl Payload {
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::PubkeyHash(hash) => hash,
+            Self::ScriptHash(hash) => hash,
+        }
+    }
+}
+
+/// Hash of a public key or script on a given network, what balances are stored under.
+///
+/// Encoded with bech32 so a mistyped address fails its checksum instead of
+/// sending coins to a key nobody owns.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Address {
+    pub network: Network,
+    pub payload: Payload,
+}
+
+impl Address {
+    pub fn from_public_key(public_key: &PublicKey, network: Network) -> Self {
+        Self {
+            network,
+            payload: Payload::PubkeyHash(hash160(&public_key.serialize())),
+        }
+    }
+
+    pub fn from_script(script: &Script, network: Network) -> Self {
+        Self {
+            network,
+            payload: Payload::ScriptHash(script.hash()),
+        }
+    }
+
+    /// Checks the address can be used on `network`.
+    pub fn require_network(&self, network: Network) -> Result<(), String> {
+        if self.network != network {
+            return Err(format!(
+                "Address {} is for {}, expected {}",
+                self, self.network, network
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut data = vec![bech32::u5::try_from_u8(ADDRESS_VERSION).unwrap()];
+        data.extend(self.payload.as_bytes().to_base32());
+        let address = bech32::encode(self.network.hrp(), data, Variant::Bech32)
+            .map_err(|_| std::fmt::Error)?;
+
+        write!(f, "{address}")
+    }
+}
+
+impl Debug for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Address({self})")
+    }
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (hrp, data, variant) =
+            bech32::decode(s).map_err(|e| format!("Invalid address {s}: {e}"))?;
+        let network = Network::from_hrp(&hrp)
+            .ok_or_else(|| format!("Invalid address {s}: Unknown network"))?;
+
+        let (version, program) = data
+            .split_first()
+            .ok_or_else(|| format!("Invalid address {s}: Missing version"))?;
+        if version.to_u8() != ADDRESS_VERSION || variant != Variant::Bech32 {
+            return Err(format!("Invalid address {s}: Unsupported version"));
+        }
+
+        let program =
+            Vec::<u8>::from_base32(program).map_err(|e| format!("Invalid address {s}: {e}"))?;
+        let payload = match program.len() {
+            PUBKEY_HASH_LEN => Payload::PubkeyHash(program.try_into().unwrap()),
+            SCRIPT_HASH_LEN => Payload::ScriptHash(program.try_into().unwrap()),
+            _ => return Err(format!("Invalid address {s}: Wrong length")),
+        };
+
+        Ok(Self { network, payload })
+    }
+}
+
+impl Serialize for Address {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// RIPEMD160(SHA256(data)), as used for Bitcoin's pubkey hashes
+pub fn hash160(data: &[u8]) -> [u8; PUBKEY_HASH_LEN] {
+    Ripemd160::digest(Sha256::digest(data)).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::KeyPair;
+
+    use super::*;
+
+    #[test]
+    fn test_address_roundtrip() {
+        let public_key = KeyPair::new().public_key;
+        let address = Address::from_public_key(&public_key, Network::Testnet);
+        let encoded = address.to_string();
+
+        assert!(encoded.starts_with("tb1q"));
+        assert_eq!(encoded.parse::<Address>(), Ok(address));
+        assert_eq!(
+            serde_json::from_str::<Address>(&serde_json::to_string(&address).unwrap()).unwrap(),
+            address
+        );
+        assert!(address.require_network(Network::Testnet).is_ok());
+        assert!(address.require_network(Network::Mainnet).is_err());
+
+        let script = Script::pay_to_pubkey(&public_key);
+        let script_address = Address::from_script(&script, Network::Testnet);
+        assert_eq!(script_address.to_string().parse(), Ok(script_address));
+        assert_ne!(script_address, address);
+    }
+
+    #[test]
+    fn test_address_checksum() {
+        let address = Address::from_public_key(&KeyPair::new().public_key, Network::Mainnet);
+        let encoded = address.to_string();
+
+        // Flip one character of the data part
+        let mut typo: Vec<char> = encoded.chars().collect();
+        typo[10] = if typo[10] == 'q' { 'p' } else { 'q' };
+        let typo: String = typo.into_iter().collect();
+
+        assert!(typo.parse::<Address>().is_err());
+        assert!("xx1qqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqq"
+            .parse::<Address>()
+            .is_err());
+    }
+}
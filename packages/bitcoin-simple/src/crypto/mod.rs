@@ -1,8 +1,13 @@
+pub mod address;
 pub mod key;
+pub mod musig;
+
+pub use address::{Address, Network};
 
 use std::str::FromStr;
 
-use secp256k1::{ecdsa::Signature, rand, All, Message, Secp256k1};
+use secp256k1::{ecdsa::Signature, rand, schnorr, All, Message, Secp256k1};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 pub fn sha256(payload: String) -> Vec<u8> {
@@ -11,6 +16,22 @@ pub fn sha256(payload: String) -> Vec<u8> {
     hasher.finalize().to_vec()
 }
 
+/// How a transaction is signed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureScheme {
+    #[default]
+    Ecdsa,
+    /// BIP340, the scheme of aggregated keys, see `musig`
+    Schnorr,
+}
+
+impl SignatureScheme {
+    pub fn is_ecdsa(&self) -> bool {
+        *self == Self::Ecdsa
+    }
+}
+
 pub struct KeyPair {
     secp: Secp256k1<All>,
     pub public_key: key::PublicKey,
@@ -33,10 +54,17 @@ impl KeyPair {
             private_key,
         })
     }
+
     pub fn sign(&self, message: &[u8]) -> Signature {
         let message = Message::from_digest_slice(message).expect("Invalid message");
         self.secp.sign_ecdsa(&message, &self.private_key)
     }
+
+    pub fn sign_schnorr(&self, message: &[u8]) -> schnorr::Signature {
+        let message = Message::from_digest_slice(message).expect("Invalid message");
+        let keypair = secp256k1::Keypair::from_secret_key(&self.secp, &self.private_key);
+        self.secp.sign_schnorr(&message, &keypair)
+    }
 }
 
 impl Default for KeyPair {
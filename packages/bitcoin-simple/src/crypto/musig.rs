@@ -0,0 +1,321 @@
+//! MuSig2-style key aggregation and two-round signing.
+//!
+//! Several parties combine their keys into one aggregated key, whose address
+//! is a multi-party account. Spending from it takes a BIP340 Schnorr signature
+//! which is only produced when every party contributed its partial signature,
+//! yet looks like any other single-key Schnorr signature on chain.
+//!
+//! Signing rounds:
+//! 1. each party generates a nonce with [`nonce_gen`] and shares the public part
+//! 2. each party creates a [`SigningSession`] from all public nonces and the
+//!    message, then shares its [`PartialSignature`]
+//! 3. anyone aggregates the partial signatures into the final signature
+
+use secp256k1::{
+    constants::CURVE_ORDER, rand, schnorr, Parity, PublicKey, Scalar, Secp256k1, SecretKey,
+    XOnlyPublicKey,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{Address, KeyPair, Network};
+
+/// BIP340 style tagged hash, so hashes of different purposes can't collide
+fn tagged_hash(tag: &str, chunks: &[&[u8]]) -> [u8; 32] {
+    let tag = Sha256::digest(tag.as_bytes());
+    let mut hasher = Sha256::new();
+    hasher.update(tag);
+    hasher.update(tag);
+    chunks.iter().for_each(|chunk| hasher.update(chunk));
+    hasher.finalize().into()
+}
+
+/// Reduces a 256 bit hash modulo the curve order, it is less than twice the order
+fn scalar_mod_order(mut bytes: [u8; 32]) -> Scalar {
+    if bytes >= CURVE_ORDER {
+        let mut borrow = 0i16;
+        for i in (0..32).rev() {
+            let diff = i16::from(bytes[i]) - i16::from(CURVE_ORDER[i]) - borrow;
+            borrow = i16::from(diff < 0);
+            bytes[i] = diff.rem_euclid(256) as u8;
+        }
+    }
+
+    Scalar::from_be_bytes(bytes).expect("Reduced modulo the curve order")
+}
+
+fn mul(key: SecretKey, tweak: &Scalar) -> Result<SecretKey, String> {
+    key.mul_tweak(tweak).map_err(|e| e.to_string())
+}
+
+fn add(key: SecretKey, other: SecretKey) -> Result<SecretKey, String> {
+    key.add_tweak(&Scalar::from(other))
+        .map_err(|e| e.to_string())
+}
+
+/// Aggregated key of a set of signers
+#[derive(Debug, Clone)]
+pub struct KeyAggContext {
+    pub public_keys: Vec<PublicKey>,
+    coefficients: Vec<Scalar>,
+    aggregated_key: PublicKey,
+}
+
+impl KeyAggContext {
+    /// Aggregates the keys, each weighted by a coefficient committing to the
+    /// whole set so a party can't cancel the others' keys out.
+    pub fn new(public_keys: Vec<PublicKey>) -> Result<Self, String> {
+        if public_keys.is_empty() {
+            return Err("Key aggregation failed: No keys".to_string());
+        }
+
+        let secp = Secp256k1::verification_only();
+        let serialized: Vec<_> = public_keys.iter().map(|k| k.serialize()).collect();
+        let keys_hash = tagged_hash(
+            "KeyAgg list",
+            &serialized.iter().map(|k| &k[..]).collect::<Vec<_>>(),
+        );
+
+        let coefficients: Vec<_> = serialized
+            .iter()
+            .map(|key| scalar_mod_order(tagged_hash("KeyAgg coefficient", &[&keys_hash, key])))
+            .collect();
+
+        let weighted = public_keys
+            .iter()
+            .zip(&coefficients)
+            .map(|(key, coefficient)| key.mul_tweak(&secp, coefficient))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        let aggregated_key = PublicKey::combine_keys(&weighted.iter().collect::<Vec<_>>())
+            .map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            public_keys,
+            coefficients,
+            aggregated_key,
+        })
+    }
+
+    /// Key of the multi-party account, its address receives the funds
+    pub fn aggregated_key(&self) -> PublicKey {
+        self.aggregated_key
+    }
+
+    pub fn address(&self, network: Network) -> Address {
+        Address::from_public_key(&self.aggregated_key, network)
+    }
+
+    pub fn x_only_key(&self) -> XOnlyPublicKey {
+        self.aggregated_key.x_only_public_key().0
+    }
+
+    fn coefficient(&self, public_key: &PublicKey) -> Result<&Scalar, String> {
+        self.public_keys
+            .iter()
+            .position(|k| k == public_key)
+            .map(|i| &self.coefficients[i])
+            .ok_or_else(|| "Signing failed: Key isn't part of the aggregated key".to_string())
+    }
+}
+
+/// What the signers share to receive funds on their multi-party account
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AggregatedKey {
+    pub public_key: PublicKey,
+    pub address: Address,
+}
+
+/// Secret part of a signer's nonce, consumed by signing so it is never reused
+pub struct SecretNonce(SecretKey, SecretKey);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublicNonce(pub PublicKey, pub PublicKey);
+
+pub fn nonce_gen() -> (SecretNonce, PublicNonce) {
+    let secp = Secp256k1::new();
+    let mut rng = rand::thread_rng();
+    let (k1, r1) = secp.generate_keypair(&mut rng);
+    let (k2, r2) = secp.generate_keypair(&mut rng);
+
+    (SecretNonce(k1, k2), PublicNonce(r1, r2))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartialSignature(SecretKey);
+
+/// Everything signers need to agree on to produce partial signatures
+pub struct SigningSession {
+    key_agg: KeyAggContext,
+    message: [u8; 32],
+    /// Final nonce, with an even Y coordinate
+    nonce: XOnlyPublicKey,
+    nonce_coefficient: Scalar,
+    negate_nonce: bool,
+    challenge: Scalar,
+}
+
+impl SigningSession {
+    pub fn new(
+        key_agg: KeyAggContext,
+        public_nonces: &[PublicNonce],
+        message: [u8; 32],
+    ) -> Result<Self, String> {
+        let secp = Secp256k1::verification_only();
+        let combine = |keys: Vec<&PublicKey>| {
+            PublicKey::combine_keys(&keys).map_err(|e| format!("Signing failed: {e}"))
+        };
+        let r1 = combine(public_nonces.iter().map(|n| &n.0).collect())?;
+        let r2 = combine(public_nonces.iter().map(|n| &n.1).collect())?;
+
+        let nonce_coefficient = scalar_mod_order(tagged_hash(
+            "MuSig/noncecoef",
+            &[
+                &r1.serialize(),
+                &r2.serialize(),
+                &key_agg.x_only_key().serialize(),
+                &message,
+            ],
+        ));
+        let nonce = r1
+            .combine(
+                &r2.mul_tweak(&secp, &nonce_coefficient)
+                    .map_err(|e| e.to_string())?,
+            )
+            .map_err(|e| e.to_string())?;
+        let (nonce, parity) = nonce.x_only_public_key();
+
+        let challenge = scalar_mod_order(tagged_hash(
+            "BIP0340/challenge",
+            &[
+                &nonce.serialize(),
+                &key_agg.x_only_key().serialize(),
+                &message,
+            ],
+        ));
+
+        Ok(Self {
+            key_agg,
+            message,
+            nonce,
+            nonce_coefficient,
+            negate_nonce: parity == Parity::Odd,
+            challenge,
+        })
+    }
+
+    /// `k1 + b * k2 + e * a * x`, with the nonces and key negated when the final
+    /// nonce or aggregated key has an odd Y coordinate, as BIP340 requires.
+    pub fn partial_sign(
+        &self,
+        keypair: &KeyPair,
+        secret_nonce: SecretNonce,
+    ) -> Result<PartialSignature, String> {
+        let SecretNonce(mut k1, mut k2) = secret_nonce;
+        if self.negate_nonce {
+            k1 = k1.negate();
+            k2 = k2.negate();
+        }
+
+        let mut key = keypair.private_key;
+        if self.key_agg.aggregated_key.x_only_public_key().1 == Parity::Odd {
+            key = key.negate();
+        }
+        let coefficient = self.key_agg.coefficient(&keypair.public_key)?;
+
+        let s = add(k1, mul(k2, &self.nonce_coefficient)?)?;
+        let s = add(s, mul(mul(key, coefficient)?, &self.challenge)?)?;
+
+        Ok(PartialSignature(s))
+    }
+
+    /// Sums the partial signatures of every signer into a BIP340 signature of
+    /// the message by the aggregated key.
+    pub fn aggregate(&self, partials: &[PartialSignature]) -> Result<schnorr::Signature, String> {
+        let (first, rest) = partials
+            .split_first()
+            .ok_or("Signing failed: No partial signatures")?;
+        let s = rest.iter().try_fold(first.0, |s, p| add(s, p.0))?;
+
+        let mut sig = [0u8; 64];
+        sig[..32].copy_from_slice(&self.nonce.serialize());
+        sig[32..].copy_from_slice(&s.secret_bytes());
+        let sig = schnorr::Signature::from_slice(&sig).map_err(|e| e.to_string())?;
+
+        let message = secp256k1::Message::from_digest(self.message);
+        Secp256k1::verification_only()
+            .verify_schnorr(&sig, &message, &self.key_agg.x_only_key())
+            .map_err(|_| "Signing failed: Invalid partial signature".to_string())?;
+
+        Ok(sig)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(keypairs: &[KeyPair], message: [u8; 32]) -> Result<schnorr::Signature, String> {
+        let key_agg = KeyAggContext::new(keypairs.iter().map(|k| k.public_key).collect())?;
+        let (secret_nonces, public_nonces): (Vec<_>, Vec<_>) =
+            keypairs.iter().map(|_| nonce_gen()).unzip();
+        let session = SigningSession::new(key_agg, &public_nonces, message)?;
+
+        let partials = keypairs
+            .iter()
+            .zip(secret_nonces)
+            .map(|(keypair, nonce)| session.partial_sign(keypair, nonce))
+            .collect::<Result<Vec<_>, _>>()?;
+        session.aggregate(&partials)
+    }
+
+    #[test]
+    fn test_scalar_mod_order() {
+        assert_eq!(scalar_mod_order(CURVE_ORDER), Scalar::ZERO);
+        assert_eq!(scalar_mod_order([0xff; 32]).to_be_bytes()[31], 0xff - 0x41);
+    }
+
+    #[test]
+    fn test_aggregated_signature() {
+        let keypairs: Vec<_> = (0..3).map(|_| KeyPair::new()).collect();
+        let key_agg = KeyAggContext::new(keypairs.iter().map(|k| k.public_key).collect()).unwrap();
+        let message = [42; 32];
+
+        // Enough runs to hit odd aggregated keys and nonces
+        for _ in 0..8 {
+            let sig = sign(&keypairs, message).unwrap();
+            let secp = Secp256k1::verification_only();
+            let msg = secp256k1::Message::from_digest(message);
+            assert!(secp
+                .verify_schnorr(&sig, &msg, &key_agg.x_only_key())
+                .is_ok());
+        }
+
+        // The key order is part of the aggregated key
+        let reversed: Vec<_> = keypairs.iter().rev().map(|k| k.public_key).collect();
+        assert_ne!(
+            KeyAggContext::new(reversed).unwrap().aggregated_key(),
+            key_agg.aggregated_key()
+        );
+    }
+
+    #[test]
+    fn test_every_signer_is_required() {
+        let keypairs: Vec<_> = (0..3).map(|_| KeyPair::new()).collect();
+        let key_agg = KeyAggContext::new(keypairs.iter().map(|k| k.public_key).collect()).unwrap();
+        let (secret_nonces, public_nonces): (Vec<_>, Vec<_>) =
+            keypairs.iter().map(|_| nonce_gen()).unzip();
+        let session = SigningSession::new(key_agg, &public_nonces, [1; 32]).unwrap();
+
+        let mut partials: Vec<_> = keypairs
+            .iter()
+            .zip(secret_nonces)
+            .map(|(keypair, nonce)| session.partial_sign(keypair, nonce).unwrap())
+            .collect();
+        partials.pop();
+        assert!(session.aggregate(&partials).is_err());
+
+        let outsider = KeyPair::new();
+        assert!(session.partial_sign(&outsider, nonce_gen().0).is_err());
+    }
+}
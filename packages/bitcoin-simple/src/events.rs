@@ -0,0 +1,255 @@
+//! Push notifications of chain and mempool changes, streamed over TCP to
+//! subscribers as one JSON event per line.
+
+use std::{
+    io::{BufWriter, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, RecvTimeoutError, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{p2p::ResultUnit, shutdown::Shutdown};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RemovalReason {
+    /// Included in a connected block
+    Confirmed,
+    /// No longer valid on top of the new tip, e.g. its sender's balance was spent
+    Evicted,
+    /// A conflicting spend paying a higher fee took its place, or confirmed
+    Replaced,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The chain tip after blocks were connected or disconnected, `None` once
+    /// the chain is empty. A reorganization only emits the final tip.
+    NewTip {
+        hash: Option<String>,
+        height: u32,
+    },
+    BlockConnected {
+        hash: String,
+        height: u32,
+        tx_ids: Vec<String>,
+    },
+    BlockDisconnected {
+        hash: String,
+        height: u32,
+    },
+    MempoolAccepted {
+        tx_id: String,
+    },
+    MempoolRemoved {
+        tx_id: String,
+        reason: RemovalReason,
+    },
+}
+
+/// Events queued for a subscriber before it is considered lagging
+pub const SUBSCRIBER_QUEUE: usize = 1024;
+/// Subscribers served at once, more are turned away
+pub const MAX_SUBSCRIBERS: usize = 32;
+
+/// Fans events out to subscribers, the ones that went away or fell
+/// `SUBSCRIBER_QUEUE` events behind are dropped on the next publish.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<mpsc::SyncSender<Event>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&self) -> Result<mpsc::Receiver<Event>, String> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.len() >= MAX_SUBSCRIBERS {
+            return Err("Too many subscribers".to_string());
+        }
+
+        let (tx, rx) = mpsc::sync_channel(SUBSCRIBER_QUEUE);
+        subscribers.push(tx);
+        Ok(rx)
+    }
+
+    pub fn publish(&self, event: Event) {
+        self.subscribers.lock().unwrap().retain(|subscriber| {
+            match subscriber.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("Events subscriber lagging, disconnecting it");
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+}
+
+/// Writes events to the subscriber until it disconnects or shutdown is requested
+fn handle_subscriber(
+    stream: TcpStream,
+    events: mpsc::Receiver<Event>,
+    shutdown: &Shutdown,
+) -> ResultUnit {
+    let mut writer = BufWriter::new(stream);
+
+    while !shutdown.is_requested() {
+        let event = match events.recv_timeout(Duration::from_millis(500)) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        // Events already queued go out with it
+        for event in std::iter::once(event).chain(events.try_iter()) {
+            serde_json::to_writer(&mut writer, &event)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Streams `events` to every client connecting to `host:port`, until
+/// `shutdown` is requested
+pub fn run_server(events: EventBus, host: String, port: u32, shutdown: Shutdown) -> ResultUnit {
+    let addr = format!("{host}:{port}");
+    let listener = TcpListener::bind(&addr)?;
+    info!("Events listening addr={addr}");
+    shutdown.wake_listener(listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        if shutdown.is_requested() {
+            break;
+        }
+
+        match stream {
+            Ok(stream) => {
+                let client = stream
+                    .peer_addr()
+                    .map_or_else(|e| e.to_string(), |addr| addr.to_string());
+                let subscription = match events.subscribe() {
+                    Ok(subscription) => subscription,
+                    Err(e) => {
+                        warn!("Events subscriber refused client={client} error={e}");
+                        continue;
+                    }
+                };
+                debug!("Events subscriber connected client={client}");
+                let shutdown = shutdown.clone();
+                thread::spawn(move || {
+                    if let Err(e) = handle_subscriber(stream, subscription, &shutdown) {
+                        debug!("Events subscriber gone client={client} error={e}");
+                    }
+                });
+            }
+            Err(e) => warn!("Events connection failed error={e}"),
+        }
+    }
+    info!("Events server stopped");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+
+    use super::*;
+
+    #[test]
+    fn test_bus_drops_gone_subscribers() {
+        let bus = EventBus::new();
+        let first = bus.subscribe().unwrap();
+        let second = bus.subscribe().unwrap();
+        drop(second);
+
+        let event = Event::MempoolAccepted {
+            tx_id: "a".to_string(),
+        };
+        bus.publish(event.clone());
+
+        assert_eq!(first.try_recv(), Ok(event.clone()));
+        assert_eq!(bus.subscriber_count(), 1);
+
+        // Lagging subscribers are disconnected once their queue is full
+        let _lagging = bus.subscribe().unwrap();
+        for _ in 0..SUBSCRIBER_QUEUE {
+            first.try_recv().ok();
+            bus.publish(event.clone());
+        }
+        assert_eq!(bus.subscriber_count(), 2);
+        bus.publish(event.clone());
+        assert_eq!(bus.subscriber_count(), 1);
+
+        let _subscribers: Vec<_> = (1..MAX_SUBSCRIBERS)
+            .map(|_| bus.subscribe().unwrap())
+            .collect();
+        assert!(bus.subscribe().is_err());
+    }
+
+    #[test]
+    fn test_stream() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .unwrap()
+            .port();
+        let bus = EventBus::new();
+        let shutdown = Shutdown::new();
+        let server = {
+            let (bus, shutdown) = (bus.clone(), shutdown.clone());
+            thread::spawn(move || {
+                run_server(bus, "127.0.0.1".to_string(), port.into(), shutdown)
+                    .map_err(|e| e.to_string())
+            })
+        };
+
+        let stream = loop {
+            match TcpStream::connect(("127.0.0.1", port)) {
+                Ok(stream) => break stream,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        while bus.subscriber_count() == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        bus.publish(Event::NewTip {
+            hash: Some("aa".to_string()),
+            height: 1,
+        });
+        bus.publish(Event::MempoolRemoved {
+            tx_id: "b".to_string(),
+            reason: RemovalReason::Confirmed,
+        });
+
+        let mut lines = BufReader::new(stream).lines();
+        assert_eq!(
+            lines.next().unwrap().unwrap(),
+            r#"{"event":"new_tip","hash":"aa","height":1}"#
+        );
+        assert_eq!(
+            lines.next().unwrap().unwrap(),
+            r#"{"event":"mempool_removed","tx_id":"b","reason":"confirmed"}"#
+        );
+
+        shutdown.request();
+        server.join().unwrap().unwrap();
+    }
+}
@@ -1,10 +1,16 @@
 pub mod block;
 pub mod crypto;
+pub mod events;
+pub mod logger;
+pub mod metrics;
 pub mod miner;
 pub mod node;
 pub mod p2p;
 pub mod rpc;
+pub mod script;
 pub mod settings;
+pub mod shutdown;
+pub mod simulator;
 pub mod storage;
 pub mod tx;
 pub mod web;
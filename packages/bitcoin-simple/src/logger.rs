@@ -0,0 +1,50 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use colored::Colorize;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Prints `<unix time> <LEVEL> <module>: <message>` lines, messages carry
+/// their details as `key=value` pairs.
+struct Logger {
+    level: LevelFilter,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let level = match record.level() {
+            Level::Error => "ERROR".red(),
+            Level::Warn => "WARN".yellow(),
+            Level::Info => "INFO".green(),
+            Level::Debug => "DEBUG".blue(),
+            Level::Trace => "TRACE".normal(),
+        };
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        println!(
+            "{time:.3} {level} {}: {}",
+            record.target().dimmed(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger, only the first call in a process takes effect
+pub fn init(level: LevelFilter) {
+    let logger = Box::new(Logger { level });
+    if log::set_boxed_logger(logger).is_ok() {
+        log::set_max_level(level);
+    }
+}
@@ -0,0 +1,423 @@
+//! Node metrics, served in the Prometheus text format on `/metrics`.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use jsonrpc_core::{futures_util::future::Either, BoxFuture, Call, Middleware, Output, Response};
+use log::{info, warn};
+
+use crate::{
+    node::Node,
+    p2p::{server::P2pData, ResultUnit},
+    shutdown::Shutdown,
+};
+
+/// Upper bounds of the RPC latency histogram buckets, in seconds
+pub const RPC_LATENCY_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Metrics of this process, gauges read from the node are added when rendering
+pub static METRICS: Metrics = Metrics::new();
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    buckets: [u64; RPC_LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+pub struct Metrics {
+    blocks_received: AtomicU64,
+    txs_received: AtomicU64,
+    blocks_rejected: AtomicU64,
+    txs_rejected: AtomicU64,
+    hashes: AtomicU64,
+    /// Hashes per second of the last mining round, as `f64` bits
+    hashrate: AtomicU64,
+    rpc_latency: Mutex<BTreeMap<String, Histogram>>,
+}
+
+/// Chain state the metrics are rendered with
+pub struct NodeGauges {
+    pub height: u32,
+    pub mempool_size: usize,
+    pub peers: usize,
+}
+
+impl NodeGauges {
+    pub fn read(node: &Arc<Mutex<Node>>, p2p_data: &Arc<Mutex<P2pData>>) -> Result<Self, String> {
+        let (height, mempool_size) = {
+            let node = node.lock().unwrap();
+            (node.store.get_latest_block_number()?, node.mempool.len())
+        };
+        let peers = p2p_data.lock().unwrap().peers.len();
+
+        Ok(Self {
+            height,
+            mempool_size,
+            peers,
+        })
+    }
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Self {
+            blocks_received: AtomicU64::new(0),
+            txs_received: AtomicU64::new(0),
+            blocks_rejected: AtomicU64::new(0),
+            txs_rejected: AtomicU64::new(0),
+            hashes: AtomicU64::new(0),
+            hashrate: AtomicU64::new(0),
+            rpc_latency: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Blocks received from peers
+    pub fn blocks_received(&self, count: u64) {
+        self.blocks_received.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Transactions received from peers
+    pub fn tx_received(&self) {
+        self.txs_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn block_rejected(&self) {
+        self.blocks_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tx_rejected(&self) {
+        self.txs_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a mining round that tried `hashes` nonces
+    pub fn mined(&self, hashes: u64, elapsed: Duration) {
+        self.hashes.fetch_add(hashes, Ordering::Relaxed);
+        let hashrate = hashes as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+        self.hashrate.store(hashrate.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn observe_rpc(&self, method: &str, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let mut latency = self.rpc_latency.lock().unwrap();
+        let histogram = latency.entry(method.to_string()).or_default();
+
+        RPC_LATENCY_BUCKETS
+            .iter()
+            .zip(histogram.buckets.iter_mut())
+            .filter(|(bound, _)| seconds <= **bound)
+            .for_each(|(_, bucket)| *bucket += 1);
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    /// Prometheus text exposition of the metrics
+    pub fn render(&self, gauges: &NodeGauges) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, String)]| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{name}{labels} {value}");
+            }
+        };
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed).to_string();
+
+        metric(
+            "bitcoind_chain_height",
+            "gauge",
+            "Height of the chain tip",
+            &[("", gauges.height.to_string())],
+        );
+        metric(
+            "bitcoind_mempool_size",
+            "gauge",
+            "Transactions waiting in the mempool",
+            &[("", gauges.mempool_size.to_string())],
+        );
+        metric(
+            "bitcoind_peers",
+            "gauge",
+            "Connected peers",
+            &[("", gauges.peers.to_string())],
+        );
+        metric(
+            "bitcoind_blocks_received_total",
+            "counter",
+            "Blocks received from peers",
+            &[("", load(&self.blocks_received))],
+        );
+        metric(
+            "bitcoind_txs_received_total",
+            "counter",
+            "Transactions received from peers",
+            &[("", load(&self.txs_received))],
+        );
+        metric(
+            "bitcoind_validation_failures_total",
+            "counter",
+            "Blocks and transactions that failed validation",
+            &[
+                ("{kind=\"block\"}", load(&self.blocks_rejected)),
+                ("{kind=\"tx\"}", load(&self.txs_rejected)),
+            ],
+        );
+        metric(
+            "bitcoind_miner_hashes_total",
+            "counter",
+            "Nonces tried by the miner",
+            &[("", load(&self.hashes))],
+        );
+        metric(
+            "bitcoind_miner_hashrate",
+            "gauge",
+            "Hashes per second of the last mining round",
+            &[(
+                "",
+                f64::from_bits(self.hashrate.load(Ordering::Relaxed)).to_string(),
+            )],
+        );
+
+        let latency = self.rpc_latency.lock().unwrap();
+        let mut samples = vec![];
+        for (method, histogram) in latency.iter() {
+            let method = escape_label(method);
+            for (bound, count) in RPC_LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                let labels = format!("_bucket{{method=\"{method}\",le=\"{bound}\"}}");
+                samples.push((labels, count.to_string()));
+            }
+            let labels = format!("_bucket{{method=\"{method}\",le=\"+Inf\"}}");
+            samples.push((labels, histogram.count.to_string()));
+            samples.push((
+                format!("_sum{{method=\"{method}\"}}"),
+                histogram.sum.to_string(),
+            ));
+            samples.push((
+                format!("_count{{method=\"{method}\"}}"),
+                histogram.count.to_string(),
+            ));
+        }
+        let samples: Vec<_> = samples
+            .iter()
+            .map(|(l, v)| (l.as_str(), v.clone()))
+            .collect();
+        metric(
+            "bitcoind_rpc_request_duration_seconds",
+            "histogram",
+            "Time taken to answer RPC calls",
+            &samples,
+        );
+
+        out
+    }
+}
+
+/// Escapes a label value of the text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Times every RPC call into the latency histogram of its method, calls of
+/// methods that aren't served go to "unknown" so clients can't add series
+#[derive(Debug, Default)]
+pub struct RpcTimer {
+    methods: HashSet<String>,
+}
+
+impl RpcTimer {
+    pub fn new(methods: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            methods: methods.into_iter().collect(),
+        }
+    }
+
+    fn method_label(&self, method: &str) -> String {
+        if self.methods.contains(method) {
+            method.to_string()
+        } else {
+            "unknown".to_string()
+        }
+    }
+}
+
+impl Middleware<()> for RpcTimer {
+    type Future = BoxFuture<Option<Response>>;
+    type CallFuture = BoxFuture<Option<Output>>;
+
+    fn on_call<F, X>(&self, call: Call, meta: (), next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, ()) -> X + Send + Sync,
+        X: std::future::Future<Output = Option<Output>> + Send + 'static,
+    {
+        let method = match &call {
+            Call::MethodCall(call) => self.method_label(&call.method),
+            Call::Notification(notification) => self.method_label(&notification.method),
+            Call::Invalid { .. } => "invalid".to_string(),
+        };
+        let start = std::time::Instant::now();
+        let output = next(call, meta);
+
+        Either::Left(Box::pin(async move {
+            let output = output.await;
+            METRICS.observe_rpc(&method, start.elapsed());
+            output
+        }))
+    }
+}
+
+/// HTTP response to a request for `path`
+fn respond(path: &str, metrics: impl FnOnce() -> Result<String, String>) -> String {
+    let (status, body) = match path {
+        "/metrics" => match metrics() {
+            Ok(body) => ("200 OK", body),
+            Err(e) => ("500 Internal Server Error", e),
+        },
+        _ => ("404 Not Found", "Not found\n".to_string()),
+    };
+
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    node: &Arc<Mutex<Node>>,
+    p2p_data: &Arc<Mutex<P2pData>>,
+) -> ResultUnit {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Headers are read so the client isn't reset while still sending them
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let response = respond(path, || {
+        NodeGauges::read(node, p2p_data).map(|gauges| METRICS.render(&gauges))
+    });
+    (&stream).write_all(response.as_bytes())?;
+
+    Ok(())
+}
+
+pub fn run_server(
+    node: Arc<Mutex<Node>>,
+    p2p_data: Arc<Mutex<P2pData>>,
+    host: String,
+    port: u32,
+    shutdown: Shutdown,
+) -> ResultUnit {
+    let listener = TcpListener::bind(format!("{host}:{port}"))?;
+    info!("Metrics listening addr={}/metrics", listener.local_addr()?);
+    shutdown.wake_listener(listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        if shutdown.is_requested() {
+            break;
+        }
+
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to accept metrics connection error={e}");
+                continue;
+            }
+        };
+
+        let node = node.clone();
+        let p2p_data = p2p_data.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, &node, &p2p_data) {
+                warn!("Metrics request failed error={e}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.blocks_received(2);
+        metrics.tx_rejected();
+        metrics.mined(50, Duration::from_secs(5));
+        metrics.observe_rpc("send", Duration::from_millis(20));
+        metrics.observe_rpc("send", Duration::from_secs(2));
+
+        let gauges = NodeGauges {
+            height: 7,
+            mempool_size: 3,
+            peers: 1,
+        };
+        let text = metrics.render(&gauges);
+        for line in [
+            "bitcoind_chain_height 7",
+            "bitcoind_mempool_size 3",
+            "bitcoind_peers 1",
+            "bitcoind_blocks_received_total 2",
+            "bitcoind_validation_failures_total{kind=\"block\"} 0",
+            "bitcoind_validation_failures_total{kind=\"tx\"} 1",
+            "bitcoind_miner_hashes_total 50",
+            "bitcoind_miner_hashrate 10",
+            "bitcoind_rpc_request_duration_seconds_bucket{method=\"send\",le=\"0.01\"} 0",
+            "bitcoind_rpc_request_duration_seconds_bucket{method=\"send\",le=\"0.05\"} 1",
+            "bitcoind_rpc_request_duration_seconds_bucket{method=\"send\",le=\"5\"} 2",
+            "bitcoind_rpc_request_duration_seconds_bucket{method=\"send\",le=\"+Inf\"} 2",
+            "bitcoind_rpc_request_duration_seconds_count{method=\"send\"} 2",
+            "# TYPE bitcoind_rpc_request_duration_seconds histogram",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {line} in\n{text}");
+        }
+
+        metrics.observe_rpc("a\"b\\", Duration::from_millis(20));
+        let text = metrics.render(&gauges);
+        let line = r#"bitcoind_rpc_request_duration_seconds_count{method="a\"b\\"} 1"#;
+        assert!(text.lines().any(|l| l == line), "missing {line} in\n{text}");
+    }
+
+    #[test]
+    fn test_unknown_rpc_methods() {
+        let timer = RpcTimer::new(["send".to_string()]);
+        assert_eq!(timer.method_label("send"), "send");
+        assert_eq!(timer.method_label("random-1234"), "unknown");
+    }
+
+    #[test]
+    fn test_respond() {
+        let ok = respond("/metrics", || Ok("bitcoind_peers 0\n".to_string()));
+        assert!(ok.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(ok.ends_with("\r\n\r\nbitcoind_peers 0\n"));
+
+        assert!(respond("/", || unreachable!()).starts_with("HTTP/1.1 404"));
+        assert!(respond("/metrics", || Err("down".to_string())).starts_with("HTTP/1.1 500"));
+    }
+}
@@ -2,44 +2,48 @@ use core::time;
 use std::{
     sync::{mpsc, Arc, Mutex},
     thread,
+    time::Instant,
 };
 
-use colored::Colorize;
+use log::info;
 
 use crate::{
     block::{Block, ProposedBlock},
     crypto,
+    metrics::METRICS,
     node::{Node, DIFFICULTY},
+    shutdown::Shutdown,
 };
 
-pub fn start_miner(node: Arc<Mutex<Node>>, interrupt_tx: mpsc::Receiver<()>) {
+/// Mines on top of the node's chain until `shutdown` is requested
+pub fn start_miner(node: Arc<Mutex<Node>>, interrupt_tx: mpsc::Receiver<()>, shutdown: Shutdown) {
     let (out_tx, out_rx) = mpsc::channel();
     let (in_tx, in_rx) = mpsc::channel();
 
-    thread::spawn(move || loop {
+    let worker_shutdown = shutdown.clone();
+    let worker = thread::spawn(move || loop {
+        if worker_shutdown.is_requested() {
+            break;
+        }
+
         if let Ok(proposed_block) = out_rx.try_recv() {
             let proposed_block: ProposedBlock = proposed_block;
             let mut nonce = 0u32;
             let block_string = proposed_block.serialize();
+            let started = Instant::now();
 
             loop {
-                if let Ok(()) = interrupt_tx.try_recv() {
-                    in_tx.send(None).unwrap();
+                if interrupt_tx.try_recv().is_ok() || worker_shutdown.is_requested() {
+                    METRICS.mined(u64::from(nonce), started.elapsed());
+                    let _ = in_tx.send(None);
                     break;
                 }
 
-                let block = format!("{}{}", block_string, nonce);
-                let block_hash = hex::encode(crypto::sha256(block.clone()));
-
-                if block_hash.starts_with(&"0".repeat(DIFFICULTY)) {
-                    let mined_block = Block {
-                        hash: block_hash,
-                        nonce,
-                        prev_block: proposed_block.prev_block,
-                        transactions: proposed_block.transactions,
-                    };
+                if let Some(block_hash) = check_nonce(&block_string, nonce) {
+                    METRICS.mined(u64::from(nonce) + 1, started.elapsed());
+                    let mined_block = proposed_block.mined(block_hash, nonce);
 
-                    in_tx.send(Some(mined_block)).unwrap();
+                    let _ = in_tx.send(Some(mined_block));
 
                     break;
                 }
@@ -58,18 +62,39 @@ pub fn start_miner(node: Arc<Mutex<Node>>, interrupt_tx: mpsc::Receiver<()>) {
         out_tx.send(proposed_block).unwrap();
     }
 
-    loop {
+    while !shutdown.wait_timeout(time::Duration::from_millis(1000)) {
         if let Ok(block) = in_rx.try_recv() {
             let mut node = node.lock().unwrap();
             block.iter().for_each(|b| {
-                println!("{} {}", "Minted block:".green(), b.hash);
+                info!("Minted block hash={}", b.hash);
                 node.receive_block(b).unwrap();
             });
 
             let proposed_block = node.get_proposed_block().unwrap();
             out_tx.send(proposed_block).unwrap();
         }
-
-        thread::sleep(time::Duration::from_millis(1000));
     }
+
+    worker.join().unwrap();
+    info!("Miner stopped");
+}
+
+/// Mines the proposed block without pausing between nonces.
+pub fn mine(proposed_block: ProposedBlock) -> Block {
+    let block_string = proposed_block.serialize();
+    let (nonce, hash) = (0u32..)
+        .find_map(|nonce| check_nonce(&block_string, nonce).map(|hash| (nonce, hash)))
+        .expect("Nonce space exhausted");
+
+    proposed_block.mined(hash, nonce)
+}
+
+/// Returns the block hash if `nonce` satisfies the proof of work.
+fn check_nonce(block_string: &str, nonce: u32) -> Option<String> {
+    let block = format!("{}{}", block_string, nonce);
+    let block_hash = hex::encode(crypto::sha256(block));
+
+    block_hash
+        .starts_with(&"0".repeat(DIFFICULTY))
+        .then_some(block_hash)
 }
@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{block::Block, crypto};
+
+use super::Node;
+
+/// A block the active chain must have at `height`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Checkpoint {
+    pub height: u32,
+    pub hash: String,
+}
+
+impl Node {
+    /// Checks the block going at `height` is the checkpoint's, if there is one
+    pub fn verify_checkpoint(&self, block_hash: &str, height: u32) -> Result<(), String> {
+        match self.checkpoints.iter().find(|c| c.height == height) {
+            Some(checkpoint) if checkpoint.hash != block_hash => Err(format!(
+                "Block verificatoin failed: Checkpoint mismatch at height {height}"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Height of the last checkpoint the chain reached, 0 if none. Forks below
+    /// it are refused.
+    pub fn last_checkpoint_height(&self) -> Result<u32, String> {
+        let height = self.store.get_latest_block_number()?;
+        Ok(self
+            .checkpoints
+            .iter()
+            .map(|c| c.height)
+            .filter(|&h| h <= height)
+            .max()
+            .unwrap_or_default())
+    }
+
+    /// Records which of the `blocks` about to be connected the assume-valid
+    /// block descends from, their signatures and scripts aren't checked. The
+    /// blocks must link up to it, each hash committing to the parent's.
+    pub fn mark_assumed_valid(&mut self, blocks: &[Block]) {
+        // Left over by a batch that failed to connect
+        self.assumed_valid.clear();
+        let Some(assume_valid) = &self.assume_valid else {
+            return;
+        };
+        let Some(end) = blocks.iter().position(|b| &b.hash == assume_valid) else {
+            return;
+        };
+
+        let ancestors = &blocks[..=end];
+        let linked = ancestors.windows(2).all(|w| w[1].prev_block == w[0].hash)
+            && ancestors
+                .iter()
+                .all(|b| hex::encode(crypto::sha256(b.serialize())) == b.hash);
+        if linked {
+            self.assumed_valid
+                .extend(ancestors.iter().map(|b| b.hash.clone()));
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{block::Block, crypto::Address, storage::TxLocation, tx::SignedTransaction};
+
+use super::Node;
+
+/// Address index format, 1 records spends on the sender's entries and the
+/// height of every transaction, 2 also keeps each spend under its own key
+pub const INDEX_VERSION: u32 = 2;
+
+/// Transactions `listtransactions` returns when no count is given, like Bitcoin's
+pub const DEFAULT_LIST_COUNT: usize = 10;
+
+/// Transaction of an address' history
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AddressTransaction {
+    pub height: u32,
+    pub block_hash: String,
+    pub confirmations: u32,
+    pub tx: SignedTransaction,
+}
+
+/// Addresses whose history includes the `index`th transaction of a block
+fn touched_addresses(index: usize, tx: &SignedTransaction) -> Vec<Address> {
+    // Coinbases have no sender
+    let mut addresses: Vec<_> = (index > 0)
+        .then(|| tx.transaction.sender())
+        .into_iter()
+        .collect();
+    for (receiver, _) in tx.transaction.payments() {
+        if !addresses.contains(&receiver) {
+            addresses.push(receiver);
+        }
+    }
+
+    addresses
+}
+
+fn locations(block: &Block, height: u32) -> impl Iterator<Item = (Address, TxLocation)> + '_ {
+    block
+        .transactions
+        .iter()
+        .enumerate()
+        .flat_map(move |(i, tx)| {
+            let sender = (i > 0).then(|| tx.transaction.sender());
+            touched_addresses(i, tx).into_iter().map(move |address| {
+                let location = TxLocation {
+                    height,
+                    index: i as u32,
+                    tx_id: tx.tx_id(),
+                    spends: (Some(address) == sender)
+                        .then(|| tx.transaction.spend_id().to_string()),
+                };
+                (address, location)
+            })
+        })
+}
+
+impl Node {
+    pub(super) fn index_block(&self, block: &Block, height: u32) -> Result<(), String> {
+        for (address, location) in locations(block, height) {
+            self.store.add_address_tx(address, &location)?;
+        }
+        for (i, tx) in block.transactions.iter().enumerate() {
+            self.store.add_tx_height(&tx.tx_id(), height)?;
+            // Coinbases spend nothing
+            if i > 0 {
+                let spend_id = tx.transaction.spend_id();
+                self.store
+                    .add_spend(tx.transaction.sender(), spend_id, &tx.tx_id())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn unindex_block(&self, block: &Block, height: u32) -> Result<(), String> {
+        for (address, location) in locations(block, height) {
+            self.store.remove_address_tx(address, &location)?;
+        }
+        for (i, tx) in block.transactions.iter().enumerate() {
+            // A transaction repeated in an earlier block keeps that height
+            if self.store.get_tx_height(&tx.tx_id())? == Some(height) {
+                self.store.remove_tx_height(&tx.tx_id())?;
+            }
+            if i > 0 {
+                let sender = tx.transaction.sender();
+                let spend_id = tx.transaction.spend_id();
+                if self.store.get_spend(sender, spend_id)? == Some(tx.tx_id()) {
+                    self.store.remove_spend(sender, spend_id)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stores whose index predates `INDEX_VERSION`, or created before there
+    /// was one, are indexed again from their blocks. Those with pruned blocks
+    /// can't be, their spends would be misread, so the node must resync.
+    /// Version 1 indexes already record spends, their keys are taken from them.
+    pub fn init_address_index(&self) -> Result<(), String> {
+        match self.store.get_index_version()? {
+            INDEX_VERSION.. => return Ok(()),
+            1 => {
+                self.index_spends()?;
+                return self.store.set_index_version(INDEX_VERSION);
+            }
+            _ => {}
+        }
+
+        let height = self.store.get_latest_block_number()?;
+        if height > 0 && self.store.get_prune_height()? > 0 {
+            return Err(
+                "Address index predates spend tracking and blocks are pruned, resync the node"
+                    .to_string(),
+            );
+        }
+        for block_number in 1..=height {
+            let block = self
+                .store
+                .get_block_hash(block_number)?
+                .and_then(|hash| self.store.get_block(&hash).transpose())
+                .ok_or_else(|| format!("Missing block at height {block_number}"))??;
+            self.index_block(&block, block_number)?;
+        }
+        self.store.set_index_version(INDEX_VERSION)?;
+        if height > 0 {
+            info!("Indexed address transactions height={height}");
+        }
+
+        Ok(())
+    }
+
+    /// Spend keys of the spends on the sender entries of the address index.
+    /// Every sender has a balance entry, even once it is spent.
+    fn index_spends(&self) -> Result<(), String> {
+        for address in self.store.get_balances()?.into_keys() {
+            for location in self.store.get_address_txs(address)? {
+                if let Some(spend_id) = &location.spends {
+                    self.store.add_spend(address, spend_id, &location.tx_id)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Confirmed transactions sending to or from `address`, newest first,
+    /// skipping the `skip` newest ones. Fails on those of pruned blocks.
+    pub fn list_transactions(
+        &self,
+        address: Address,
+        count: usize,
+        skip: usize,
+    ) -> Result<Vec<AddressTransaction>, String> {
+        let tip = self.store.get_latest_block_number()?;
+
+        self.store
+            .get_address_txs(address)?
+            .into_iter()
+            .rev()
+            .skip(skip)
+            .take(count)
+            .map(|location| {
+                let block_hash = self
+                    .store
+                    .get_block_hash(location.height)?
+                    .ok_or_else(|| format!("Missing block at height {}", location.height))?;
+                if self.is_pruned(location.height)? {
+                    return Err(format!("Block at height {} is pruned", location.height));
+                }
+                let tx = self
+                    .store
+                    .get_block(&block_hash)?
+                    .and_then(|block| block.transactions.into_iter().nth(location.index as usize))
+                    .filter(|tx| tx.tx_id() == location.tx_id)
+                    .ok_or_else(|| format!("Address index out of sync for {}", location.tx_id))?;
+
+                Ok(AddressTransaction {
+                    height: location.height,
+                    block_hash,
+                    confirmations: tip + 1 - location.height,
+                    tx,
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,550 @@
+//! Mempool policy: opt-in replacement of unconfirmed spends by higher fee
+//! ones, like BIP125, and block templates picking transactions by the fee
+//! rate of their package, so a child can pay for its parent.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet},
+    iter,
+};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crypto::Address,
+    events::{Event, RemovalReason},
+    tx::{self, SignedTransaction},
+};
+
+use super::Node;
+
+/// How much more a replacement pays at least than the transactions it evicts
+pub const MIN_FEE_INCREMENT: u32 = 1;
+
+/// Mempool transactions a block template takes at most
+pub const MAX_BLOCK_TRANSACTIONS: usize = 1000;
+
+/// Balances of the chain tip changed by unconfirmed transactions
+pub struct PendingBalances<'a> {
+    node: &'a Node,
+    /// Whether the changes of all the mempool transactions count, on top of `changes`
+    mempool: bool,
+    /// Confirmed balance and immature coinbase rewards
+    confirmed: HashMap<Address, (i64, i64)>,
+    changes: HashMap<Address, i64>,
+}
+
+impl<'a> PendingBalances<'a> {
+    pub fn new(node: &'a Node) -> Self {
+        Self {
+            node,
+            mempool: false,
+            confirmed: HashMap::new(),
+            changes: HashMap::new(),
+        }
+    }
+
+    fn change(&self, address: Address) -> i64 {
+        let mempool = if self.mempool {
+            self.node.mempool_changes.get(&address).copied()
+        } else {
+            None
+        };
+        mempool.unwrap_or_default() + self.changes.get(&address).copied().unwrap_or_default()
+    }
+
+    fn confirmed(&mut self, address: Address) -> Result<(i64, i64), String> {
+        if let Some(confirmed) = self.confirmed.get(&address) {
+            return Ok(*confirmed);
+        }
+
+        let balance = self.node.store.get_balance(address)?.unwrap_or_default();
+        let immature = self.node.immature_balance(address)?;
+        let confirmed = (i64::from(balance), i64::from(immature));
+        self.confirmed.insert(address, confirmed);
+
+        Ok(confirmed)
+    }
+
+    /// Checks the sender can pay the amount and fee of the transaction
+    pub fn check(&mut self, tx: &SignedTransaction) -> Result<(), String> {
+        let sender = tx.transaction.sender();
+        let (balance, immature) = self.confirmed(sender)?;
+        let change = self.change(sender);
+        let debit = tx.transaction.debit() as i64;
+
+        if balance + change < debit {
+            return Err("Transaction verification failed: Insufficient balance".to_string());
+        }
+        if balance - immature + change < debit {
+            return Err("Transaction verification failed: Immature coinbase".to_string());
+        }
+
+        Ok(())
+    }
+
+    pub fn apply(&mut self, tx: &SignedTransaction) {
+        apply_change(&mut self.changes, tx, 1);
+    }
+}
+
+/// Adds the balance changes of `tx` to `changes`, or takes them off with a
+/// `sign` of -1
+fn apply_change(changes: &mut HashMap<Address, i64>, tx: &SignedTransaction, sign: i64) {
+    *changes.entry(tx.transaction.sender()).or_default() -= sign * tx.transaction.debit() as i64;
+    for (to, amount) in tx.transaction.payments() {
+        *changes.entry(to).or_default() += sign * i64::from(amount);
+    }
+}
+
+/// Ids of the mempool transactions in an order they can be paid in, in the
+/// order they arrived otherwise. Kept as they come and go, so it is never sorted.
+#[derive(Default)]
+pub(super) struct MempoolOrder {
+    positions: HashMap<String, u64>,
+    ids: BTreeMap<u64, String>,
+    next: u64,
+}
+
+impl MempoolOrder {
+    fn push(&mut self, tx_id: String) {
+        self.positions.insert(tx_id.clone(), self.next);
+        self.ids.insert(self.next, tx_id);
+        self.next += 1;
+    }
+
+    fn remove(&mut self, tx_id: &str) {
+        if let Some(position) = self.positions.remove(tx_id) {
+            self.ids.remove(&position);
+        }
+    }
+
+    fn ids(&self) -> impl DoubleEndedIterator<Item = &String> {
+        self.ids.values()
+    }
+
+    /// Ids before `tx_id`, none if it isn't in the order
+    fn ids_before(&self, tx_id: &str) -> impl DoubleEndedIterator<Item = &String> {
+        let end = self.positions.get(tx_id).copied().unwrap_or_default();
+        self.ids.range(..end).map(|(_, tx_id)| tx_id)
+    }
+
+    /// Replaces the order by `tx_ids`
+    fn reset(&mut self, tx_ids: impl IntoIterator<Item = String>) {
+        *self = Self::default();
+        for tx_id in tx_ids {
+            self.push(tx_id);
+        }
+    }
+}
+
+/// A mempool transaction along with the unconfirmed ones paying its sender,
+/// which a miner includes together
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MempoolEntry {
+    pub tx: SignedTransaction,
+    /// Ids of the unconfirmed transactions it may spend the amounts of
+    pub ancestors: Vec<String>,
+    /// Fees of the transaction and its ancestors
+    pub package_fee: u64,
+    pub package_size: usize,
+}
+
+/// Indexes of the transactions before each one in `order` paying its
+/// sender, directly or through others
+fn ancestor_sets(order: &[&SignedTransaction]) -> Vec<BTreeSet<usize>> {
+    // Transactions so far paying each address, along with their ancestors
+    let mut paying: HashMap<Address, BTreeSet<usize>> = HashMap::new();
+    let mut sets = vec![];
+    for (i, tx) in order.iter().enumerate() {
+        let ancestors = paying
+            .get(&tx.transaction.sender())
+            .cloned()
+            .unwrap_or_default();
+        for (to, _) in tx.transaction.payments() {
+            let recipient = paying.entry(to).or_default();
+            recipient.insert(i);
+            recipient.extend(&ancestors);
+        }
+        sets.push(ancestors);
+    }
+
+    sets
+}
+
+/// Fees and size of a package
+fn package(order: &[&SignedTransaction], txs: &[usize]) -> (u64, usize) {
+    let fee = txs
+        .iter()
+        .map(|i| u64::from(order[*i].transaction.fee))
+        .sum();
+    (fee, txs.len())
+}
+
+/// Compares the fee rates, fee per transaction, of two packages
+fn cmp_fee_rate((fee_a, size_a): (u64, usize), (fee_b, size_b): (u64, usize)) -> Ordering {
+    (u128::from(fee_a) * size_b as u128).cmp(&(u128::from(fee_b) * size_a as u128))
+}
+
+/// A transaction of a block template with its package, the fees and size of
+/// its ancestors not selected yet. The highest fee rate comes first, the
+/// oldest among equal ones.
+#[derive(Debug, PartialEq, Eq)]
+struct Candidate {
+    package: (u64, usize),
+    index: usize,
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        cmp_fee_rate(self.package, other.package)
+            .then_with(|| other.index.cmp(&self.index))
+            .then_with(|| self.package.cmp(&other.package))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Node {
+    /// Orders `txs` so that each can be paid for after the ones before it,
+    /// keeping their order otherwise. Returns them and those that can't be
+    /// paid for.
+    fn payable_order<'a>(
+        &self,
+        txs: impl IntoIterator<Item = &'a SignedTransaction>,
+    ) -> (Vec<&'a SignedTransaction>, Vec<&'a SignedTransaction>) {
+        let mut balances = PendingBalances::new(self);
+        let mut ordered = vec![];
+        // Transactions their sender can't pay for yet, tried again once it
+        // receives an amount
+        let mut waiting: HashMap<Address, Vec<&SignedTransaction>> = HashMap::new();
+        for tx in txs {
+            let mut next = vec![tx];
+            while let Some(tx) = next.pop() {
+                if balances.check(tx).is_err() {
+                    waiting.entry(tx.transaction.sender()).or_default().push(tx);
+                    continue;
+                }
+
+                balances.apply(tx);
+                ordered.push(tx);
+                for (to, _) in tx.transaction.payments() {
+                    if let Some(retried) = waiting.remove(&to) {
+                        next.extend(retried.into_iter().rev());
+                    }
+                }
+            }
+        }
+
+        let unpaid = waiting.into_values().flatten().collect();
+
+        (ordered, unpaid)
+    }
+
+    /// Mempool transactions in an order they can be included in a block
+    pub fn mempool_order(&self) -> Vec<&SignedTransaction> {
+        self.mempool_order
+            .ids()
+            .filter_map(|tx_id| self.mempool.get(tx_id))
+            .collect()
+    }
+
+    pub fn mempool_entry(&self, tx_id: &str) -> Option<MempoolEntry> {
+        let tx = self.mempool.get(tx_id)?;
+        // Walking back, addresses the transaction or one of its ancestors spends from
+        let mut spending = HashSet::from([tx.transaction.sender()]);
+        let mut ancestors = vec![];
+        for other in self
+            .mempool_order
+            .ids_before(tx_id)
+            .rev()
+            .filter_map(|tx_id| self.mempool.get(tx_id))
+        {
+            if other
+                .transaction
+                .payments()
+                .any(|(to, _)| spending.contains(&to))
+            {
+                spending.insert(other.transaction.sender());
+                ancestors.push(other);
+            }
+        }
+        ancestors.reverse();
+
+        let package_fee = ancestors
+            .iter()
+            .chain(iter::once(&tx))
+            .map(|tx| u64::from(tx.transaction.fee))
+            .sum();
+        Some(MempoolEntry {
+            tx: tx.clone(),
+            ancestors: ancestors.iter().map(|tx| tx.tx_id()).collect(),
+            package_fee,
+            package_size: ancestors.len() + 1,
+        })
+    }
+
+    /// Picks at most `limit` transactions of a block out of `candidates`, in
+    /// mempool order, those with the highest package fee rates first, in an
+    /// order they can be paid in
+    pub fn select_transactions<'a>(
+        &self,
+        candidates: impl IntoIterator<Item = &'a SignedTransaction>,
+        limit: usize,
+    ) -> Vec<SignedTransaction> {
+        let (order, _) = self.payable_order(candidates);
+        let ancestors = ancestor_sets(&order);
+        // Packages shrink as their ancestors get selected
+        let mut descendants = vec![vec![]; order.len()];
+        for (i, set) in ancestors.iter().enumerate() {
+            for &ancestor in set {
+                descendants[ancestor].push(i);
+            }
+        }
+        let mut packages: Vec<_> = ancestors
+            .iter()
+            .enumerate()
+            .map(|(i, set)| {
+                let txs: Vec<_> = set.iter().copied().chain(iter::once(i)).collect();
+                package(&order, &txs)
+            })
+            .collect();
+
+        let mut queue: BinaryHeap<_> = (0..order.len())
+            .map(|index| Candidate {
+                package: packages[index],
+                index,
+            })
+            .collect();
+        let mut selected = BTreeSet::new();
+        while let Some(Candidate { package, index }) = queue.pop() {
+            // Left over from before an ancestor was selected
+            if selected.contains(&index) || package != packages[index] {
+                continue;
+            }
+            if selected.len() + package.1 > limit {
+                continue;
+            }
+
+            let txs: Vec<_> = ancestors[index]
+                .iter()
+                .filter(|a| !selected.contains(*a))
+                .copied()
+                .chain(iter::once(index))
+                .collect();
+            for i in txs {
+                selected.insert(i);
+                for &descendant in &descendants[i] {
+                    if selected.contains(&descendant) {
+                        continue;
+                    }
+                    let (fee, size) = &mut packages[descendant];
+                    *fee -= u64::from(order[i].transaction.fee);
+                    *size -= 1;
+                    queue.push(Candidate {
+                        package: packages[descendant],
+                        index: descendant,
+                    });
+                }
+            }
+        }
+
+        // Indexes in `order` keep the transactions payable
+        selected.into_iter().map(|i| order[i].clone()).collect()
+    }
+
+    /// Balances after the mempool transactions, except the `excluded` ones and
+    /// those that can't be paid for without them
+    pub(super) fn pending_balances(&self, excluded: &HashSet<String>) -> PendingBalances<'_> {
+        // All of the mempool can be paid for, `revalidate_mempool` evicts the rest
+        if excluded.is_empty() {
+            return PendingBalances {
+                mempool: true,
+                ..PendingBalances::new(self)
+            };
+        }
+
+        let remaining = self
+            .mempool_order()
+            .into_iter()
+            .filter(|tx| !excluded.contains(&tx.tx_id()));
+        let mut balances = PendingBalances::new(self);
+        for tx in self.payable_order(remaining).0 {
+            balances.apply(tx);
+        }
+
+        balances
+    }
+
+    /// Id of the mempool transaction spending what `tx` spends, see
+    /// `Transaction::spend_id`
+    fn mempool_spend(&self, tx: &SignedTransaction) -> Option<&String> {
+        let spend = (
+            tx.transaction.sender(),
+            tx.transaction.spend_id().to_string(),
+        );
+        self.mempool_spends.get(&spend)
+    }
+
+    /// Ids of the mempool transactions `tx` conflicts with, which it replaces
+    fn conflicts(&self, tx: &SignedTransaction) -> Result<Vec<String>, String> {
+        let Some(other) = self.mempool_spend(tx).and_then(|id| self.mempool.get(id)) else {
+            return Ok(vec![]);
+        };
+        if !other.transaction.replaceable {
+            return Err(format!(
+                "Transaction rejected: Conflicts with non-replaceable {}",
+                other.tx_id()
+            ));
+        }
+
+        Ok(vec![other.tx_id()])
+    }
+
+    /// Adds the transaction to the mempool, replacing the ones it conflicts
+    /// with when it pays more than them and the transactions spending from them
+    pub(super) fn accept_to_mempool(&mut self, tx: &SignedTransaction) -> Result<(), String> {
+        if self.mempool.contains_key(&tx.tx_id()) {
+            return Err("Transaction rejected: Already in the mempool".to_string());
+        }
+
+        let replaced = self.conflicts(tx)?;
+        let excluded: HashSet<_> = replaced.iter().cloned().collect();
+        self.verify_reg_tx(tx, &mut self.pending_balances(&excluded), true)?;
+
+        let mut evicted = vec![];
+        // Order the mempool keeps after the replacement
+        let mut order = None;
+        if !replaced.is_empty() {
+            let remaining = self
+                .mempool_order()
+                .into_iter()
+                .filter(|m| !excluded.contains(&m.tx_id()))
+                .chain(iter::once(tx));
+            let (ordered, unpaid) = self.payable_order(remaining);
+            evicted = unpaid
+                .into_iter()
+                .map(|m| m.tx_id())
+                .filter(|tx_id| *tx_id != tx.tx_id())
+                .collect();
+            order = Some(ordered.into_iter().map(|m| m.tx_id()).collect::<Vec<_>>());
+
+            let replaced_fees: u64 = replaced
+                .iter()
+                .chain(&evicted)
+                .filter_map(|tx_id| self.mempool.get(tx_id))
+                .map(|m| u64::from(m.transaction.fee))
+                .sum();
+            let min_fee = replaced_fees + u64::from(MIN_FEE_INCREMENT);
+            if u64::from(tx.transaction.fee) < min_fee {
+                return Err(format!(
+                    "Transaction rejected: Replacement fee too low, {min_fee} needed"
+                ));
+            }
+        }
+
+        for tx_id in replaced {
+            debug!("Replaced transaction tx_id={tx_id} by={}", tx.tx_id());
+            self.remove_from_mempool(&tx_id, RemovalReason::Replaced);
+        }
+        for tx_id in evicted {
+            debug!("Evicted transaction tx_id={tx_id}");
+            self.remove_from_mempool(&tx_id, RemovalReason::Evicted);
+        }
+        apply_change(&mut self.mempool_changes, tx, 1);
+        let spend = (
+            tx.transaction.sender(),
+            tx.transaction.spend_id().to_string(),
+        );
+        self.mempool_spends.insert(spend, tx.tx_id());
+        self.mempool.insert(tx.tx_id(), tx.clone());
+        match order {
+            Some(order) => self.mempool_order.reset(order),
+            None => self.mempool_order.push(tx.tx_id()),
+        }
+
+        Ok(())
+    }
+
+    fn remove_from_mempool(&mut self, tx_id: &str, reason: RemovalReason) {
+        if let Some(tx) = self.mempool.remove(tx_id) {
+            self.mempool_order.remove(tx_id);
+            if self.mempool_spend(&tx) == Some(&tx.tx_id()) {
+                let spend = (
+                    tx.transaction.sender(),
+                    tx.transaction.spend_id().to_string(),
+                );
+                self.mempool_spends.remove(&spend);
+            }
+            apply_change(&mut self.mempool_changes, &tx, -1);
+            let payments = tx.transaction.payments().map(|(to, _)| to);
+            for address in iter::once(tx.transaction.sender()).chain(payments) {
+                if self.mempool_changes.get(&address) == Some(&0) {
+                    self.mempool_changes.remove(&address);
+                }
+            }
+            self.events.publish(Event::MempoolRemoved {
+                tx_id: tx_id.to_string(),
+                reason,
+            });
+        }
+    }
+
+    /// Drops the confirmed transaction from the mempool, along with the
+    /// transactions it conflicts with
+    pub(super) fn remove_confirmed(&mut self, tx: &SignedTransaction) {
+        self.remove_from_mempool(&tx.tx_id(), RemovalReason::Confirmed);
+
+        if let Some(tx_id) = self.mempool_spend(tx).cloned() {
+            debug!("Replaced transaction tx_id={tx_id} by={}", tx.tx_id());
+            self.remove_from_mempool(&tx_id, RemovalReason::Replaced);
+        }
+    }
+
+    /// Evicts the mempool transactions that can no longer be paid for, walking
+    /// them in mempool order
+    pub(super) fn revalidate_mempool(&mut self) {
+        let (ordered, unpaid) = self.payable_order(self.mempool_order());
+        let order: Vec<_> = ordered.into_iter().map(|tx| tx.tx_id()).collect();
+        let evicted: Vec<_> = unpaid.into_iter().map(|tx| tx.tx_id()).collect();
+        // Transactions paid for by ones after them move past those
+        self.mempool_order.reset(order);
+        for tx_id in evicted {
+            debug!("Evicted transaction tx_id={tx_id}");
+            self.remove_from_mempool(&tx_id, RemovalReason::Evicted);
+        }
+    }
+
+    /// Replaces our replaceable mempool transaction by one paying `fee`,
+    /// twice its fee by default, and broadcasts it
+    pub fn bump_fee(&mut self, tx_id: &str, fee: Option<u32>) -> Result<SignedTransaction, String> {
+        let original = self
+            .mempool
+            .get(tx_id)
+            .ok_or("Bump failed: Transaction isn't in the mempool")?
+            .transaction
+            .clone();
+        if original.from != self.keypair.public_key || original.script.is_some() {
+            return Err("Bump failed: Not a transaction of our wallet".to_string());
+        }
+        if !original.replaceable {
+            return Err("Bump failed: Transaction isn't replaceable".to_string());
+        }
+
+        let fee = fee.unwrap_or_else(|| {
+            original
+                .fee
+                .saturating_add(original.fee.max(MIN_FEE_INCREMENT))
+        });
+        let tx = tx::create_replacement(&self.keypair, &original, fee);
+        self.add_tx_to_mempool(&tx)?;
+        self.transaction_tx.send(tx.clone()).unwrap();
+
+        Ok(tx)
+    }
+}
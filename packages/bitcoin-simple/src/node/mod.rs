@@ -1,26 +1,83 @@
-use std::{collections::HashMap, fs, path::Path, sync::mpsc};
+pub mod checkpoints;
+pub mod history;
+pub mod mempool;
+pub mod orphans;
+pub mod prune;
+pub mod snapshot;
+pub mod supply;
 
-use colored::Colorize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+    sync::{mpsc, Arc},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, info, warn};
 
 use crate::{
-    block::{Block, ProposedBlock},
-    crypto::{self, key::PublicKey, KeyPair},
-    storage::{self, Store},
-    tx::{self, create_signed, SignedTransaction},
+    block::{file as block_file, Block, ProposedBlock},
+    crypto::{self, Address, KeyPair, Network, SignatureScheme},
+    events::{Event, EventBus},
+    metrics::METRICS,
+    storage::{RocksStore, SharedStore},
+    tx::{self, create_signed, Output, SignedTransaction},
 };
 
+pub use checkpoints::Checkpoint;
+pub use history::{AddressTransaction, DEFAULT_LIST_COUNT};
+pub use mempool::{MempoolEntry, PendingBalances, MAX_BLOCK_TRANSACTIONS, MIN_FEE_INCREMENT};
+pub use orphans::{OrphanPool, MAX_ORPHANS};
+pub use prune::MIN_PRUNE_DEPTH;
+pub use snapshot::{ChainStateSnapshot, SnapshotInfo};
+pub use supply::{SupplyInfo, TxOutSetInfo, COINBASE_MATURITY, MAX_SUPPLY};
+
+use mempool::MempoolOrder;
+
 // TODO: Difficulty adjustment
 pub static DIFFICULTY: usize = 2;
 pub static GENESIS_PREV_BLOCK_HASH: &str =
     "000000000000000000000000000000000000000000000000000000000000000";
 
+/// Blocks whose median timestamp time locks are compared against, like BIP113
+pub const MEDIAN_TIME_SPAN: usize = 11;
+/// How far ahead of our clock a block's timestamp may be, in seconds
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+
 pub struct Node {
     pub mempool: HashMap<String, SignedTransaction>,
+    /// Balance changes of the mempool transactions, updated as they come and go
+    mempool_changes: HashMap<Address, i64>,
+    /// Mempool transaction of each sender's spend, conflicting ones replace it
+    mempool_spends: HashMap<(Address, String), String>,
+    mempool_order: MempoolOrder,
     pub keypair: KeyPair,
-    pub db_blocks: Store,
-    pub db_blocks_metadata: Store,
-    pub db_balances: Store,
+    pub network: Network,
+    pub store: SharedStore,
     pub data_dir: String,
+    /// Directory `export_blocks` and `dump_chain_state` write to, `exports`
+    /// in the data directory unless configured
+    pub export_dir: String,
+    /// Blocks before coinbase rewards can be spent, `COINBASE_MATURITY` unless
+    /// lowered for tests
+    pub coinbase_maturity: u32,
+    /// Chain and mempool changes, for subscribers of the events server
+    pub events: EventBus,
+    /// Blocks waiting for their parent to arrive
+    pub orphans: OrphanPool,
+    /// Blocks below the tip whose transactions are kept, all of them when
+    /// unset. `MIN_PRUNE_DEPTH` at least unless lowered for tests.
+    pub prune_depth: Option<u32>,
+    /// Hash of the chain state snapshot `load_chain_state` accepts
+    pub chainstate_hash: Option<String>,
+    /// Blocks the active chain must include, forks below the last one reached are refused
+    pub checkpoints: Vec<Checkpoint>,
+    /// Block whose ancestors are synced without checking their signatures
+    pub assume_valid: Option<String>,
+    /// Ancestors of `assume_valid` among the blocks being synced
+    assumed_valid: HashSet<String>,
 
     block_tx: mpsc::Sender<Block>,
     transaction_tx: mpsc::Sender<SignedTransaction>,
@@ -31,67 +88,319 @@ impl Node {
         block_tx: mpsc::Sender<Block>,
         transaction_tx: mpsc::Sender<SignedTransaction>,
         data_dir: &str,
+        network: Network,
+    ) -> Self {
+        fs::create_dir_all(data_dir).expect("Can't create data directory");
+        let store = Arc::new(RocksStore::open(data_dir));
+
+        Self::with_store(block_tx, transaction_tx, data_dir, network, store)
+    }
+
+    /// Creates a node on top of an already opened store, the data directory
+    /// still holds the wallet.
+    pub fn with_store(
+        block_tx: mpsc::Sender<Block>,
+        transaction_tx: mpsc::Sender<SignedTransaction>,
+        data_dir: &str,
+        network: Network,
+        store: SharedStore,
     ) -> Self {
         fs::create_dir_all(data_dir).expect("Can't create data directory");
 
         Self {
             keypair: get_keypair(data_dir).expect("Can't get keypair"),
             mempool: HashMap::new(),
-            db_blocks: storage::db::blocks(false, data_dir),
-            db_blocks_metadata: storage::db::blocks_metadata(false, data_dir),
-            db_balances: storage::db::balances(false, data_dir),
+            mempool_changes: HashMap::new(),
+            mempool_spends: HashMap::new(),
+            mempool_order: MempoolOrder::default(),
+            network,
+            store,
             data_dir: data_dir.to_string(),
+            export_dir: format!("{}/exports", data_dir),
+            coinbase_maturity: COINBASE_MATURITY,
+            events: EventBus::new(),
+            orphans: OrphanPool::new(),
+            prune_depth: None,
+            chainstate_hash: None,
+            checkpoints: Vec::new(),
+            assume_valid: None,
+            assumed_valid: HashSet::new(),
 
             block_tx,
             transaction_tx,
         }
     }
 
+    /// Address of the node's wallet, where mining rewards go
+    pub fn address(&self) -> Address {
+        Address::from_public_key(&self.keypair.public_key, self.network)
+    }
+
     pub fn process_block(&mut self, block: &Block) -> Result<(), String> {
-        self.verify_block(block)?;
+        self.connect_block(block)?;
+        self.tip_changed()
+    }
+
+    fn connect_block(&mut self, block: &Block) -> Result<(), String> {
+        self.verify_block(block).inspect_err(|e| {
+            METRICS.block_rejected();
+            debug!("Rejected block hash={} error={e}", block.hash);
+        })?;
+        self.assumed_valid.remove(&block.hash);
         self.process_block_transactions(block)?;
+        let supply = self.store.get_supply()? + supply::block_issuance(block);
+        self.store.set_supply(supply)?;
+
+        let prev_block_number = self.store.get_latest_block_number()?;
+        self.store.add_block(block)?;
+        self.store
+            .set_latest_block_hash(&block.hash, prev_block_number + 1)?;
 
-        let prev_block_number = storage::get_latest_block_number(&self.db_blocks_metadata)?;
-        storage::add_block(&self.db_blocks, block)?;
-        storage::set_latest_block_hash(
-            &self.db_blocks_metadata,
+        self.events.publish(Event::BlockConnected {
+            hash: block.hash.clone(),
+            height: prev_block_number + 1,
+            tx_ids: block.transactions.iter().map(|tx| tx.tx_id()).collect(),
+        });
+
+        Ok(())
+    }
+
+    /// Undoes the transactions of the chain tip and moves the tip back to its parent.
+    pub fn disconnect_tip(&mut self) -> Result<Block, String> {
+        let block = self.disconnect_block()?;
+        self.tip_changed()?;
+
+        Ok(block)
+    }
+
+    fn disconnect_block(&mut self) -> Result<Block, String> {
+        let height = self.store.get_latest_block_number()?;
+        if height > 0 && self.is_pruned(height)? {
+            return Err(format!(
+                "Disconnect failed: Block at height {height} is pruned"
+            ));
+        }
+        let block = self
+            .get_latest_block()?
+            .ok_or("Disconnect failed: Chain is empty")?;
+
+        for (i, tx) in block.transactions.iter().enumerate().rev() {
+            for (receiver, amount) in tx.transaction.payments() {
+                let receiver_balance = self.store.get_balance(receiver)?.unwrap_or_default();
+                let receiver_new_balance = receiver_balance
+                    .checked_sub(amount)
+                    .ok_or("Disconnect failed: Balance underflow")?;
+                self.store.set_balance(receiver, receiver_new_balance)?;
+            }
+
+            if i > 0 {
+                let sender_balance = self
+                    .store
+                    .get_balance(tx.transaction.sender())?
+                    .unwrap_or_default();
+                let sender_new_balance =
+                    u32::try_from(u64::from(sender_balance) + tx.transaction.debit())
+                        .map_err(|_| "Disconnect failed: Balance overflow")?;
+                self.store
+                    .set_balance(tx.transaction.sender(), sender_new_balance)?;
+            }
+        }
+
+        let supply = self
+            .store
+            .get_supply()?
+            .checked_sub(supply::block_issuance(&block))
+            .ok_or("Disconnect failed: Supply underflow")?;
+        self.store.set_supply(supply)?;
+        self.unindex_block(&block, height)?;
+
+        let prev_block_hash =
+            (block.prev_block != GENESIS_PREV_BLOCK_HASH).then_some(&block.prev_block);
+        self.store.unset_latest_block_hash(
             &block.hash,
-            prev_block_number + 1,
+            height,
+            prev_block_hash.map(|h| h.as_str()),
         )?;
 
+        self.events.publish(Event::BlockDisconnected {
+            hash: block.hash.clone(),
+            height,
+        });
+
+        Ok(block)
+    }
+
+    /// Publishes the new tip, evicts the mempool transactions its balances
+    /// can no longer pay for and prunes the blocks now deep enough
+    fn tip_changed(&mut self) -> Result<(), String> {
+        self.revalidate_mempool();
+        self.prune()?;
+
+        self.events.publish(Event::NewTip {
+            hash: self.store.get_latest_block_hash()?,
+            height: self.store.get_latest_block_number()?,
+        });
+
         Ok(())
     }
 
+    /// Switches to a longer chain forking off ours after `fork_height`, `blocks`
+    /// being the new chain's blocks above it. The current chain is restored if
+    /// any of them is invalid.
+    pub fn reorganize(&mut self, fork_height: u32, blocks: &[Block]) -> Result<(), String> {
+        let height = self.store.get_latest_block_number()?;
+        if fork_height < self.store.get_prune_height()? {
+            return Err("Reorganization failed: Fork is below the pruned blocks".to_string());
+        }
+        let checkpoint = self.last_checkpoint_height()?;
+        if fork_height < checkpoint {
+            return Err(format!(
+                "Reorganization failed: Fork is below the checkpoint at height {checkpoint}"
+            ));
+        }
+        if fork_height + blocks.len() as u32 <= height {
+            return Err("Reorganization failed: New chain isn't longer".to_string());
+        }
+
+        let mut disconnected = vec![];
+        while self.store.get_latest_block_number()? > fork_height {
+            disconnected.push(self.disconnect_block()?);
+        }
+
+        for (i, block) in blocks.iter().enumerate() {
+            if let Err(e) = self.connect_block(block) {
+                for _ in 0..i {
+                    self.disconnect_block()?;
+                }
+                for block in disconnected.iter().rev() {
+                    self.connect_block(block)?;
+                }
+
+                return Err(format!("Reorganization failed: {e}"));
+            }
+        }
+
+        warn!(
+            "Reorganized disconnected={} connected={} fork_height={}",
+            disconnected.len(),
+            blocks.len(),
+            fork_height
+        );
+
+        // Transactions that only made it into the old chain go back to the mempool
+        let connected: HashSet<_> = blocks
+            .iter()
+            .flat_map(|b| b.transactions.iter().map(|tx| tx.tx_id()))
+            .collect();
+        for block in disconnected.iter().rev() {
+            for tx in block.transactions.iter().skip(1) {
+                if !connected.contains(&tx.tx_id()) {
+                    let _ = self.add_tx_to_mempool(tx);
+                }
+            }
+        }
+
+        self.tip_changed()
+    }
+
     pub fn add_tx_to_mempool(&mut self, tx: &SignedTransaction) -> Result<(), String> {
-        println!(
-            "{}:{} - {}={} {}={} {}={} ",
-            "New Transaction:".green(),
+        info!(
+            "New transaction tx_id={} amount={} fee={} from={} to={}",
             tx.tx_id(),
-            "amount".yellow(),
-            tx.transaction.amount,
-            "from".yellow(),
-            tx.transaction.from,
-            "to".yellow(),
+            tx.transaction.total_amount(),
+            tx.transaction.fee,
+            tx.transaction.sender(),
             tx.transaction.to
         );
 
-        self.verify_reg_tx(tx)?;
-        self.mempool.insert(tx.tx_id(), tx.clone());
+        self.accept_to_mempool(tx).inspect_err(|e| {
+            METRICS.tx_rejected();
+            debug!("Rejected transaction tx_id={} error={e}", tx.tx_id());
+        })?;
+        self.events
+            .publish(Event::MempoolAccepted { tx_id: tx.tx_id() });
 
         Ok(())
     }
 
-    pub fn send_tx(&mut self, to: PublicKey, amount: u32) -> Result<SignedTransaction, String> {
-        let tx = tx::create_signed(&self.keypair, to, amount);
+    pub fn send_tx(
+        &mut self,
+        to: Address,
+        amount: u32,
+        scheme: SignatureScheme,
+    ) -> Result<SignedTransaction, String> {
+        self.send_tx_with_fee(to, amount, scheme, 0, false)
+    }
+
+    /// Sends paying `fee` to the miner, `replaceable` allowing `bump_fee` later
+    pub fn send_tx_with_fee(
+        &mut self,
+        to: Address,
+        amount: u32,
+        scheme: SignatureScheme,
+        fee: u32,
+        replaceable: bool,
+    ) -> Result<SignedTransaction, String> {
+        to.require_network(self.network)?;
+        let tx = tx::create_with_fee(&self.keypair, to, amount, scheme, fee, replaceable);
         self.add_tx_to_mempool(&tx)?;
         self.transaction_tx.send(tx.clone()).unwrap();
 
         Ok(tx)
     }
 
-    pub fn receive_tx(&mut self, block: &Block) -> Result<(), String> {
-        self.process_block(block)?;
-        self.block_tx.send(block.clone()).unwrap();
+    /// Sends to every recipient in one transaction paying `fee`, so either
+    /// all of them are paid or none. With `dry_run` it is only returned.
+    pub fn send_many(
+        &mut self,
+        recipients: &BTreeMap<Address, u32>,
+        scheme: SignatureScheme,
+        fee: u32,
+        dry_run: bool,
+    ) -> Result<SignedTransaction, String> {
+        let mut payments = recipients.iter();
+        let (to, amount) = payments.next().ok_or("Send failed: No recipients")?;
+        for to in recipients.keys() {
+            to.require_network(self.network)?;
+        }
+        let outputs = payments
+            .map(|(&to, &amount)| Output { to, amount })
+            .collect();
+        let tx = tx::create_with_outputs(&self.keypair, *to, *amount, outputs, scheme, fee);
+        if dry_run {
+            let mut balances = self.pending_balances(&HashSet::new());
+            self.verify_reg_tx(&tx, &mut balances, true)?;
+            return Ok(tx);
+        }
+
+        self.add_tx_to_mempool(&tx)?;
+        self.transaction_tx.send(tx.clone()).unwrap();
+
+        Ok(tx)
+    }
+
+    /// Signs a time locked transfer. It is only accepted once its locks expire,
+    /// until then it is returned to be broadcast later.
+    pub fn create_locked_tx(
+        &self,
+        to: Address,
+        amount: u32,
+        lock_time: u32,
+        relative_lock: Option<tx::RelativeLock>,
+    ) -> Result<SignedTransaction, String> {
+        to.require_network(self.network)?;
+        Ok(tx::create_locked(
+            &self.keypair,
+            to,
+            amount,
+            lock_time,
+            relative_lock,
+        ))
+    }
+
+    pub fn receive_tx(&mut self, tx: &SignedTransaction) -> Result<(), String> {
+        self.add_tx_to_mempool(tx)?;
+        self.transaction_tx.send(tx.clone()).unwrap();
 
         Ok(())
     }
@@ -103,22 +412,21 @@ impl Node {
         Ok(())
     }
 
-    pub fn create_coinbase_tx(&self) -> Result<SignedTransaction, String> {
-        let latest_block_number = storage::get_latest_block_number(&self.db_blocks_metadata)?;
-        let reward = self.get_block_reward(latest_block_number + 1);
+    /// Pays the block reward and the `fees` of the block's transactions to our wallet
+    pub fn create_coinbase_tx(&self, fees: u64) -> Result<SignedTransaction, String> {
+        let latest_block_number = self.store.get_latest_block_number()?;
+        let reward = u64::from(self.get_block_reward(latest_block_number + 1));
+        let amount = u32::try_from(reward + fees).map_err(|_| "Coinbase amount overflow")?;
 
-        Ok(create_signed(
-            &self.keypair,
-            self.keypair.public_key,
-            reward,
-        ))
+        Ok(create_signed(&self.keypair, self.address(), amount))
     }
 
     pub fn make_gensis_block(&self) -> Result<ProposedBlock, String> {
-        let coinbase_tx = self.create_coinbase_tx()?;
+        let coinbase_tx = self.create_coinbase_tx(0)?;
 
         Ok(ProposedBlock {
             prev_block: GENESIS_PREV_BLOCK_HASH.to_string(),
+            timestamp: now(),
             transactions: vec![coinbase_tx],
         })
     }
@@ -144,60 +452,104 @@ impl Node {
             return Err("Block verificatoin failed: Previous block hash mismatch".to_string());
         }
 
-        let prev_block_number = storage::get_latest_block_number(&self.db_blocks_metadata)?;
+        if prev_block_hash != GENESIS_PREV_BLOCK_HASH
+            && block.timestamp <= self.median_time_past()?
+        {
+            return Err("Block verificatoin failed: Timestamp too old".to_string());
+        }
+        if block.timestamp > now() + MAX_FUTURE_BLOCK_TIME {
+            return Err("Block verificatoin failed: Timestamp too far in the future".to_string());
+        }
+
+        let prev_block_number = self.store.get_latest_block_number()?;
+        self.verify_checkpoint(&block.hash, prev_block_number + 1)?;
 
-        for (i, tx) in block.transactions.iter().enumerate() {
-            if i == 0 {
-                self.verify_coinbase_tx(tx, prev_block_number + 1)?;
-            } else {
-                self.verify_reg_tx(tx)?;
+        let coinbase = block
+            .transactions
+            .first()
+            .ok_or("Block verificatoin failed: Missing coinbase")?;
+        self.verify_coinbase_tx(coinbase, prev_block_number + 1, supply::block_fees(block))?;
+
+        // Transactions can spend the amounts received earlier in the block
+        let mut balances = PendingBalances::new(self);
+        let mut spends = HashSet::new();
+        let check_signatures = !self.assumed_valid.contains(&block.hash);
+        for tx in block.transactions.iter().skip(1) {
+            self.verify_reg_tx(tx, &mut balances, check_signatures)?;
+            balances.apply(tx);
+
+            if !spends.insert((tx.transaction.sender(), tx.transaction.spend_id())) {
+                return Err("Block verificatoin failed: Conflicting transactions".to_string());
             }
         }
 
+        if self.store.get_supply()? + supply::block_issuance(block) > MAX_SUPPLY {
+            return Err("Block verificatoin failed: Supply cap exceeded".to_string());
+        }
+
         // TODO: verify more
 
         Ok(())
     }
 
     pub fn process_block_transactions(&mut self, block: &Block) -> Result<(), String> {
+        let height = self.store.get_latest_block_number()? + 1;
         for (i, tx) in block.transactions.iter().enumerate() {
             // Coinbase (first tx in block) is allowed to create new supply (by not deducting a balance)
             if i > 0 {
-                let sender_balance = storage::get_balance(&self.db_balances, tx.transaction.from)?
+                let sender_balance = self
+                    .store
+                    .get_balance(tx.transaction.sender())?
                     .unwrap_or_default();
-                let sender_new_balance = sender_balance - tx.transaction.amount;
-                storage::set_balance(&self.db_balances, tx.transaction.from, sender_new_balance)?;
+                let sender_new_balance = u32::try_from(tx.transaction.debit())
+                    .ok()
+                    .and_then(|debit| sender_balance.checked_sub(debit))
+                    .ok_or("Process failed: Balance underflow")?;
+                self.store
+                    .set_balance(tx.transaction.sender(), sender_new_balance)?;
             }
 
-            let receiver_balance =
-                storage::get_balance(&self.db_balances, tx.transaction.to)?.unwrap_or_default();
-            let receiver_new_balance = receiver_balance + tx.transaction.amount;
-            storage::set_balance(&self.db_balances, tx.transaction.to, receiver_new_balance)?;
+            for (receiver, amount) in tx.transaction.payments() {
+                let receiver_balance = self.store.get_balance(receiver)?.unwrap_or_default();
+                self.store
+                    .set_balance(receiver, receiver_balance + amount)?;
+            }
 
-            // Remove tx from mempool
-            self.mempool.remove(&tx.transaction.tx_id);
+            self.remove_confirmed(tx);
         }
+        self.index_block(block, height)?;
 
         Ok(())
     }
 
     pub fn get_latest_block(&self) -> Result<Option<Block>, String> {
-        let block_hash = match storage::get_latest_block_hash(&self.db_blocks_metadata)? {
-            Some(block_hash) => block_hash,
-            None => return Ok(None),
-        };
-
-        storage::get_block(&self.db_blocks, &block_hash)
+        self.store.get_latest_block()
     }
 
     pub fn get_proposed_block(&mut self) -> Result<ProposedBlock, String> {
         let prev_block = self.get_latest_block().expect("Must have genesis block");
 
         let block = prev_block.map(|b| {
-            let mut txs = vec![self.create_coinbase_tx()?];
-            txs.extend(self.mempool.values().cloned());
+            let height = self.store.get_latest_block_number()? + 1;
+            let median_time_past = self.median_time_past()?;
+
+            // Locks of mempool transactions may have become unmet after a reorganization
+            let selected = self.select_transactions(
+                self.mempool_order()
+                    .into_iter()
+                    .filter(|tx| self.verify_locks(tx, height, median_time_past).is_ok()),
+                MAX_BLOCK_TRANSACTIONS,
+            );
+            let fees = selected
+                .iter()
+                .map(|tx| u64::from(tx.transaction.fee))
+                .sum();
+            let mut txs = vec![self.create_coinbase_tx(fees)?];
+            txs.extend(selected);
             Ok(ProposedBlock {
                 prev_block: b.hash.clone(),
+                // Timestamps must keep increasing past the median even when our clock lags
+                timestamp: now().max(median_time_past + 1),
                 transactions: txs,
             })
         });
@@ -206,41 +558,233 @@ impl Node {
     }
 
     pub fn start(&mut self) -> Result<Option<Block>, String> {
+        self.init_supply()?;
+        self.init_address_index()?;
+        self.load_mempool()?;
         self.get_latest_block()
     }
 
+    /// Saves the mempool and flushes storage, so a restart picks up where we stopped
+    pub fn shutdown(&self) -> Result<(), String> {
+        self.save_mempool()?;
+        self.store.flush()
+    }
+
+    /// Creates the file `name` in the export directory. Paths leading out of
+    /// it and existing files are refused, so exports can't overwrite anything.
+    fn create_export_file(&self, name: &str) -> Result<(fs::File, PathBuf), String> {
+        let mut components = Path::new(name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(format!(
+                "Export failed: {name} isn't a file name in the export directory"
+            ));
+        }
+
+        fs::create_dir_all(&self.export_dir).map_err(|e| e.to_string())?;
+        let path = Path::new(&self.export_dir).join(name);
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|e| format!("Export failed: {e}"))?;
+
+        Ok((file, path))
+    }
+
+    /// Writes the active chain to the block file `name` of the export
+    /// directory, returns the number of blocks written
+    pub fn export_blocks(&self, name: &str) -> Result<u32, String> {
+        let pruned = self.store.get_prune_height()?;
+        if pruned > 0 {
+            return Err(format!(
+                "Export failed: Blocks up to height {pruned} are pruned"
+            ));
+        }
+
+        let (file, path) = self.create_export_file(name)?;
+        let mut writer = io::BufWriter::new(file);
+
+        let height = self.store.get_latest_block_number()?;
+        for block_number in 1..=height {
+            let block = self
+                .store
+                .get_block_hash(block_number)?
+                .and_then(|hash| self.store.get_block(&hash).transpose())
+                .ok_or_else(|| format!("Missing block at height {block_number}"))??;
+            block_file::write_block(&mut writer, self.network, &block)?;
+        }
+        writer.flush().map_err(|e| e.to_string())?;
+
+        info!("Exported blocks count={height} path={}", path.display());
+        Ok(height)
+    }
+
+    /// Validates and connects the blocks of a block file on top of our chain,
+    /// skipping those we already have. Returns the number of blocks connected.
+    pub fn import_blocks(&mut self, path: &str) -> Result<u32, String> {
+        let file = fs::File::open(path).map_err(|e| e.to_string())?;
+        let mut reader = io::BufReader::new(file);
+
+        let mut imported = 0;
+        while let Some(block) = block_file::read_block(&mut reader, self.network)? {
+            if self.store.get_block_height(&block.hash)?.is_some() {
+                continue;
+            }
+
+            self.process_block(&block)
+                .map_err(|e| format!("Import failed at block {}: {e}", block.hash))?;
+            imported += 1;
+        }
+
+        info!("Imported blocks count={imported} path={path}");
+        Ok(imported)
+    }
+
+    pub fn save_mempool(&self) -> Result<(), String> {
+        // Transactions spending received amounts load after the ones paying them
+        let txs = self.mempool_order();
+
+        let data = serde_json::to_string(&txs).map_err(|e| e.to_string())?;
+        fs::write(mempool_path(&self.data_dir), data).map_err(|e| e.to_string())
+    }
+
+    /// Adds back the transactions saved on shutdown, dropping those that are no longer valid
+    pub fn load_mempool(&mut self) -> Result<usize, String> {
+        let path = mempool_path(&self.data_dir);
+        if !Path::new(&path).exists() {
+            return Ok(0);
+        }
+
+        let data = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+        let txs: Vec<SignedTransaction> = serde_json::from_str(&data).map_err(|e| e.to_string())?;
+        let loaded = txs
+            .iter()
+            .filter(|tx| self.add_tx_to_mempool(tx).is_ok())
+            .count();
+        info!("Loaded mempool txs={loaded} dropped={}", txs.len() - loaded);
+
+        Ok(loaded)
+    }
+
+    /// Checks the coinbase pays the block reward plus the `fees` of the block
     pub fn verify_coinbase_tx(
         &self,
         tx: &SignedTransaction,
         block_number: u32,
+        fees: u64,
     ) -> Result<(), String> {
         self.verify_tx(tx)?;
-        if tx.transaction.amount != self.get_block_reward(block_number) {
+        if tx.transaction.script.is_some() {
+            return Err(
+                "Transaction verification failed: Coinbase can't spend a script".to_string(),
+            );
+        }
+        if tx.transaction.fee != 0 {
+            return Err("Transaction verification failed: Coinbase can't pay a fee".to_string());
+        }
+        if !tx.transaction.outputs.is_empty() {
+            return Err("Transaction verification failed: Coinbase can't have outputs".to_string());
+        }
+        if u64::from(tx.transaction.amount) != u64::from(self.get_block_reward(block_number)) + fees
+        {
             return Err("Transaction verification failed: Coinbase Amount mismatch".to_string());
         }
 
         Ok(())
     }
 
-    pub fn verify_reg_tx(&self, tx: &SignedTransaction) -> Result<(), String> {
-        self.verify_tx(tx)?;
-        let from_balance =
-            storage::get_balance(&self.db_balances, tx.transaction.from)?.unwrap_or_default();
-        if from_balance < tx.transaction.amount {
-            return Err("Transaction verification failed: Insufficient balance".to_string());
+    /// Checks the transaction can go in the next block, `balances` being
+    /// those after the transactions before it. Signatures are only skipped
+    /// in blocks the assume-valid block descends from.
+    pub fn verify_reg_tx(
+        &self,
+        tx: &SignedTransaction,
+        balances: &mut PendingBalances,
+        check_signatures: bool,
+    ) -> Result<(), String> {
+        self.check_tx(tx, check_signatures)?;
+        balances.check(tx)?;
+
+        // Transactions can't be replayed, relative locks rely on ids being unique
+        let spend_id = tx.transaction.spend_id();
+        match self.store.get_spend(tx.transaction.sender(), spend_id)? {
+            Some(tx_id) if tx_id == tx.tx_id() => {
+                return Err("Transaction verification failed: Already in the chain".to_string());
+            }
+            Some(tx_id) => {
+                return Err(format!(
+                    "Transaction verification failed: Conflicts with confirmed {tx_id}"
+                ));
+            }
+            None => {}
         }
 
         Ok(())
     }
 
     pub fn verify_tx(&self, tx: &SignedTransaction) -> Result<(), String> {
-        if !tx.is_sig_valid() {
+        self.check_tx(tx, true)
+    }
+
+    /// `verify_tx`, skipping signatures and scripts unless `check_signatures`
+    fn check_tx(&self, tx: &SignedTransaction, check_signatures: bool) -> Result<(), String> {
+        if tx.tx_id() != tx.transaction.serialize() {
+            return Err("Transaction verification failed: Id mismatch".to_string());
+        }
+
+        if check_signatures && !tx.is_sig_valid() {
             return Err("Transaction verification failed: Invalid signature".to_string());
         }
 
+        for (to, _) in tx.transaction.payments() {
+            to.require_network(self.network)
+                .map_err(|e| format!("Transaction verification failed: {e}"))?;
+        }
+
+        // Scripts and locks are checked as of the block the transaction goes in
+        let height = self.store.get_latest_block_number()? + 1;
+        if check_signatures {
+            tx.verify_script(height)
+                .map_err(|e| format!("Transaction verification failed: {e}"))?;
+        }
+        self.verify_locks(tx, height, self.median_time_past()?)
+            .map_err(|e| format!("Transaction verification failed: {e}"))?;
+
         Ok(())
     }
 
+    /// Checks the transaction's time locks allow it in a block at `height`
+    pub fn verify_locks(
+        &self,
+        tx: &SignedTransaction,
+        height: u32,
+        median_time_past: u64,
+    ) -> Result<(), String> {
+        tx.transaction
+            .verify_locks(height, median_time_past, |tx_id| {
+                self.store.get_tx_height(tx_id)
+            })
+    }
+
+    /// Median timestamp of the last blocks of the chain, 0 when it is empty
+    pub fn median_time_past(&self) -> Result<u64, String> {
+        let mut timestamps = vec![];
+        let mut block = self.get_latest_block()?;
+        while let Some(b) = block.filter(|_| timestamps.len() < MEDIAN_TIME_SPAN) {
+            timestamps.push(b.timestamp);
+            block = self.store.get_block(&b.prev_block)?;
+        }
+
+        timestamps.sort_unstable();
+        Ok(timestamps
+            .get(timestamps.len() / 2)
+            .copied()
+            .unwrap_or_default())
+    }
+
     pub fn get_block_reward(&self, block_number: u32) -> u32 {
         let halving = block_number / 1024;
         if halving > 10 {
@@ -251,6 +795,17 @@ impl Node {
     }
 }
 
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn mempool_path(data_dir: &str) -> String {
+    format!("{}/mempool.json", data_dir)
+}
+
 pub fn get_keypair(data_dir: &str) -> Result<KeyPair, Box<dyn std::error::Error>> {
     let wallet_path = format!("{}/wallet", data_dir);
     if Path::new(&wallet_path).exists() {
@@ -267,3 +822,895 @@ pub fn get_keypair(data_dir: &str) -> Result<KeyPair, Box<dyn std::error::Error>
 
     Ok(keypair)
 }
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        crypto::musig::{self, KeyAggContext, SigningSession},
+        events::RemovalReason,
+        miner,
+        script::Script,
+        storage::{MemoryStore, TxLocation},
+    };
+
+    fn test_node() -> (Node, TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let (block_tx, _) = mpsc::channel();
+        let (transaction_tx, _) = mpsc::channel();
+        let data_dir = dir.path().to_str().unwrap();
+        let mut node = Node::with_store(
+            block_tx,
+            transaction_tx,
+            data_dir,
+            Network::Regtest,
+            Arc::new(MemoryStore::new()),
+        );
+        // Rewards are spent right away, see `test_coinbase_maturity`
+        node.coinbase_maturity = 0;
+
+        (node, dir)
+    }
+
+    fn mine(node: &mut Node) -> Block {
+        let block = miner::mine(node.get_proposed_block().unwrap());
+        node.process_block(&block).unwrap();
+        block
+    }
+
+    #[test]
+    fn test_process_block() {
+        let (mut node, _dir) = test_node();
+        let genesis = mine(&mut node);
+        let address = node.address();
+
+        assert_eq!(genesis.prev_block, GENESIS_PREV_BLOCK_HASH);
+        assert_eq!(node.store.get_balance(address), Ok(Some(512)));
+
+        let other = Address::from_public_key(&KeyPair::new().public_key, Network::Regtest);
+        node.add_tx_to_mempool(&create_signed(&node.keypair, other, 12))
+            .unwrap();
+        mine(&mut node);
+
+        assert!(node.mempool.is_empty());
+        assert_eq!(node.store.get_latest_block_number(), Ok(2));
+        assert_eq!(node.store.get_balance(address), Ok(Some(1012)));
+        assert_eq!(node.store.get_balance(other), Ok(Some(12)));
+
+        // The same block can't be connected twice
+        assert!(node.process_block(&genesis).is_err());
+    }
+
+    #[test]
+    fn test_reorganize_restores_balances_and_mempool() {
+        let (mut node, _dir) = test_node();
+        let genesis = mine(&mut node);
+        let other = Address::from_public_key(&KeyPair::new().public_key, Network::Regtest);
+        let tx = create_signed(&node.keypair, other, 12);
+        node.add_tx_to_mempool(&tx).unwrap();
+        mine(&mut node);
+
+        // A competing chain of two empty blocks on top of genesis
+        let (mut fork, _fork_dir) = test_node();
+        fork.process_block(&genesis).unwrap();
+        let blocks = vec![mine(&mut fork), mine(&mut fork)];
+
+        let events = node.events.subscribe().unwrap();
+        node.reorganize(1, &blocks).unwrap();
+
+        assert_eq!(
+            node.get_latest_block().unwrap().unwrap().hash,
+            blocks[1].hash
+        );
+        assert_eq!(node.store.get_balance(other), Ok(Some(0)));
+        assert_eq!(node.store.get_balance(node.address()), Ok(Some(512)));
+        assert!(node.mempool.contains_key(&tx.tx_id()));
+        assert!(node.list_transactions(other, 10, 0).unwrap().is_empty());
+        let tips: Vec<_> = events
+            .try_iter()
+            .filter(|e| matches!(e, Event::NewTip { .. }))
+            .collect();
+        assert_eq!(
+            tips,
+            vec![Event::NewTip {
+                hash: Some(blocks[1].hash.clone()),
+                height: 3
+            }]
+        );
+
+        // A shorter chain is refused
+        assert!(node.reorganize(1, &blocks[..1]).is_err());
+    }
+
+    #[test]
+    fn test_events() {
+        let (mut node, _dir) = test_node();
+        let events = node.events.subscribe().unwrap();
+        let genesis = mine(&mut node);
+        let block = mine(&mut node);
+        let other = Address::from_public_key(&KeyPair::new().public_key, Network::Regtest);
+        let tx = create_signed(&node.keypair, other, 1000);
+        node.add_tx_to_mempool(&tx).unwrap();
+        node.disconnect_tip().unwrap();
+
+        let connected = |block: &Block, height| Event::BlockConnected {
+            hash: block.hash.clone(),
+            height,
+            tx_ids: vec![block.transactions[0].tx_id()],
+        };
+        let tip = |block: &Block, height| Event::NewTip {
+            hash: Some(block.hash.clone()),
+            height,
+        };
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                connected(&genesis, 1),
+                tip(&genesis, 1),
+                connected(&block, 2),
+                tip(&block, 2),
+                Event::MempoolAccepted { tx_id: tx.tx_id() },
+                Event::BlockDisconnected {
+                    hash: block.hash.clone(),
+                    height: 2
+                },
+                // The disconnected reward paid for it
+                Event::MempoolRemoved {
+                    tx_id: tx.tx_id(),
+                    reason: RemovalReason::Evicted
+                },
+                tip(&genesis, 1),
+            ]
+        );
+        assert!(node.mempool.is_empty());
+
+        let tx = create_signed(&node.keypair, other, 12);
+        node.add_tx_to_mempool(&tx).unwrap();
+        mine(&mut node);
+        assert!(events.try_iter().any(|e| e
+            == Event::MempoolRemoved {
+                tx_id: tx.tx_id(),
+                reason: RemovalReason::Confirmed
+            }));
+    }
+
+    #[test]
+    fn test_replace_by_fee() {
+        let (mut node, _dir) = test_node();
+        // Bumped transactions are broadcast
+        let (transaction_tx, _transactions) = mpsc::channel();
+        node.transaction_tx = transaction_tx;
+        mine(&mut node);
+        let to = Address::from_public_key(&KeyPair::new().public_key, Network::Regtest);
+        let original = node
+            .send_tx_with_fee(to, 12, SignatureScheme::Ecdsa, 1, true)
+            .unwrap();
+        let final_tx = node.send_tx(to, 5, SignatureScheme::Ecdsa).unwrap();
+
+        // Only opted in transactions are replaced, by a higher fee
+        let replacement = tx::create_replacement(&node.keypair, &final_tx.transaction, 10);
+        assert!(node.add_tx_to_mempool(&replacement).is_err());
+        let replacement = tx::create_replacement(&node.keypair, &original.transaction, 1);
+        assert!(node.add_tx_to_mempool(&replacement).is_err());
+        assert!(node.bump_fee(&final_tx.tx_id(), None).is_err());
+
+        let events = node.events.subscribe().unwrap();
+        let bumped = node.bump_fee(&original.tx_id(), None).unwrap();
+        assert_eq!(bumped.transaction.fee, 2);
+        assert_eq!(bumped.transaction.spend_id(), original.tx_id());
+        assert!(!node.mempool.contains_key(&original.tx_id()));
+        assert_eq!(
+            events.try_iter().next(),
+            Some(Event::MempoolRemoved {
+                tx_id: original.tx_id(),
+                reason: RemovalReason::Replaced
+            })
+        );
+        assert!(node.add_tx_to_mempool(&original).is_err());
+        // Balance changes, spends and order follow the replacement
+        assert_eq!(node.mempool_changes.get(&to), Some(&17));
+        let spend = (node.address(), original.tx_id());
+        assert_eq!(node.mempool_spends.get(&spend), Some(&bumped.tx_id()));
+        let order: Vec<_> = node.mempool_order().iter().map(|tx| tx.tx_id()).collect();
+        assert_eq!(order, vec![final_tx.tx_id(), bumped.tx_id()]);
+
+        // The miner collects the fees, which aren't new supply
+        let block = mine(&mut node);
+        assert!(node.mempool_changes.is_empty());
+        assert!(node.mempool_spends.is_empty());
+        assert!(node.mempool_order().is_empty());
+        assert_eq!(block.transactions[0].transaction.amount, 514);
+        assert_eq!(node.store.get_balance(to), Ok(Some(17)));
+        assert_eq!(node.store.get_balance(node.address()), Ok(Some(1007)));
+        assert_eq!(node.supply_info().unwrap().issued, 1024);
+
+        // Conflicting spends can't confirm once one did
+        let error = node.add_tx_to_mempool(&original).unwrap_err();
+        assert!(error.contains("Conflicts with confirmed"));
+        node.disconnect_tip().unwrap();
+        assert_eq!(node.store.get_balance(node.address()), Ok(Some(512)));
+    }
+
+    #[test]
+    fn test_child_pays_for_parent() {
+        let (mut node, _dir) = test_node();
+        mine(&mut node);
+        let alice = KeyPair::new();
+        let alice_address = Address::from_public_key(&alice.public_key, Network::Regtest);
+        let other = Address::from_public_key(&KeyPair::new().public_key, Network::Regtest);
+
+        let unrelated =
+            tx::create_with_fee(&node.keypair, other, 1, SignatureScheme::Ecdsa, 4, false);
+        node.add_tx_to_mempool(&unrelated).unwrap();
+        let parent = create_signed(&node.keypair, alice_address, 100);
+        node.add_tx_to_mempool(&parent).unwrap();
+        // Spends the unconfirmed amount of its parent
+        let child = tx::create_with_fee(&alice, other, 50, SignatureScheme::Ecdsa, 10, false);
+        node.add_tx_to_mempool(&child).unwrap();
+        let overspend = tx::create_signed(&alice, other, 41);
+        assert!(node.add_tx_to_mempool(&overspend).is_err());
+
+        let entry = node.mempool_entry(&child.tx_id()).unwrap();
+        assert_eq!(entry.ancestors, vec![parent.tx_id()]);
+        assert_eq!((entry.package_fee, entry.package_size), (10, 2));
+
+        // The package pays 5 per transaction, more than the unrelated one
+        let select = |limit| {
+            node.select_transactions(node.mempool_order(), limit)
+                .iter()
+                .map(|tx| tx.tx_id())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(select(2), vec![parent.tx_id(), child.tx_id()]);
+        assert_eq!(select(1), vec![unrelated.tx_id()]);
+
+        // Blocks can't spend amounts before receiving them
+        let mut proposed = node.get_proposed_block().unwrap();
+        assert_eq!(proposed.transactions[0].transaction.amount, 526);
+        let valid = proposed.clone();
+        let position = |tx: &SignedTransaction| {
+            proposed
+                .transactions
+                .iter()
+                .position(|t| t.tx_id() == tx.tx_id())
+                .unwrap()
+        };
+        let (parent_position, child_position) = (position(&parent), position(&child));
+        proposed.transactions.swap(parent_position, child_position);
+        assert!(node.process_block(&miner::mine(proposed)).is_err());
+
+        node.process_block(&miner::mine(valid)).unwrap();
+        assert!(node.mempool.is_empty());
+        assert_eq!(node.store.get_balance(alice_address), Ok(Some(40)));
+        assert_eq!(node.store.get_balance(other), Ok(Some(51)));
+    }
+
+    #[test]
+    fn test_send_many() {
+        let (mut node, _dir) = test_node();
+        let (transaction_tx, transactions) = mpsc::channel();
+        node.transaction_tx = transaction_tx;
+        mine(&mut node);
+        let address = || Address::from_public_key(&KeyPair::new().public_key, Network::Regtest);
+        let (a, b) = (address(), address());
+        let recipients = BTreeMap::from([(a, 100), (b, 200)]);
+
+        let planned = node
+            .send_many(&recipients, SignatureScheme::Ecdsa, 5, true)
+            .unwrap();
+        assert!(node.mempool.is_empty());
+
+        let sent = node
+            .send_many(&recipients, SignatureScheme::Ecdsa, 5, false)
+            .unwrap();
+        assert_eq!(
+            sent.transaction.payments().collect::<Vec<_>>(),
+            planned.transaction.payments().collect::<Vec<_>>()
+        );
+        assert_eq!(sent.transaction.debit(), 305);
+        assert!(node.mempool.contains_key(&sent.tx_id()));
+        assert_eq!(transactions.try_iter().count(), 1);
+
+        // The balance left covers each of them but not both
+        let too_much = BTreeMap::from([(a, 400), (b, 400)]);
+        assert!(node
+            .send_many(&too_much, SignatureScheme::Ecdsa, 0, false)
+            .is_err());
+        assert_eq!(node.mempool.len(), 1);
+        assert!(node
+            .send_many(&BTreeMap::new(), SignatureScheme::Ecdsa, 0, true)
+            .is_err());
+
+        mine(&mut node);
+        assert_eq!(node.store.get_balance(a), Ok(Some(100)));
+        assert_eq!(node.store.get_balance(b), Ok(Some(200)));
+    }
+
+    #[test]
+    fn test_list_transactions() {
+        let (mut node, _dir) = test_node();
+        mine(&mut node);
+        let other = Address::from_public_key(&KeyPair::new().public_key, Network::Regtest);
+        let mut sent = vec![];
+        for amount in [12, 5] {
+            let tx = create_signed(&node.keypair, other, amount);
+            node.add_tx_to_mempool(&tx).unwrap();
+            mine(&mut node);
+            sent.push(tx);
+        }
+
+        let history = node.list_transactions(other, 10, 0).unwrap();
+        let tx_ids: Vec<_> = history.iter().map(|t| t.tx.tx_id()).collect();
+        assert_eq!(tx_ids, vec![sent[1].tx_id(), sent[0].tx_id()]);
+        assert_eq!((history[0].height, history[0].confirmations), (3, 1));
+        assert_eq!((history[1].height, history[1].confirmations), (2, 2));
+
+        // Three coinbases and the two transfers, paginated
+        let address = node.address();
+        assert_eq!(node.list_transactions(address, 10, 0).unwrap().len(), 5);
+        let page = node.list_transactions(address, 2, 1).unwrap();
+        assert_eq!(page.len(), 2);
+        assert_eq!(page[0].height, 3);
+        assert_eq!(page[1].tx.tx_id(), sent[0].tx_id());
+        assert!(node.list_transactions(address, 10, 5).unwrap().is_empty());
+
+        let spend =
+            |node: &Node, tx: &SignedTransaction| node.store.get_spend(address, &tx.tx_id());
+        assert_eq!(spend(&node, &sent[1]), Ok(Some(sent[1].tx_id())));
+        node.disconnect_tip().unwrap();
+        assert_eq!(spend(&node, &sent[1]), Ok(None));
+        assert_eq!(spend(&node, &sent[0]), Ok(Some(sent[0].tx_id())));
+        let history = node.list_transactions(other, 10, 0).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].tx.tx_id(), sent[0].tx_id());
+        assert_eq!(node.list_transactions(address, 10, 0).unwrap().len(), 3);
+
+        // Spend keys of version 1 indexes are taken from their sender entries
+        node.store.remove_spend(address, &sent[0].tx_id()).unwrap();
+        node.store.set_index_version(1).unwrap();
+        node.init_address_index().unwrap();
+        assert_eq!(spend(&node, &sent[0]), Ok(Some(sent[0].tx_id())));
+        assert_eq!(node.store.get_index_version(), Ok(history::INDEX_VERSION));
+
+        // Chains connected before the index existed are indexed on start
+        let (mut unindexed, _unindexed_dir) = test_node();
+        for block_number in 1..=2 {
+            let hash = node.store.get_block_hash(block_number).unwrap().unwrap();
+            let block = node.store.get_block(&hash).unwrap().unwrap();
+            unindexed.store.add_block(&block).unwrap();
+            unindexed
+                .store
+                .set_latest_block_hash(&hash, block_number)
+                .unwrap();
+        }
+        assert!(unindexed
+            .list_transactions(other, 10, 0)
+            .unwrap()
+            .is_empty());
+        // Sender entries indexed before spends were recorded are rewritten
+        let legacy = TxLocation {
+            height: 2,
+            index: 1,
+            tx_id: sent[0].tx_id(),
+            spends: None,
+        };
+        unindexed.store.add_address_tx(address, &legacy).unwrap();
+        assert_eq!(unindexed.store.get_spend(address, &legacy.tx_id), Ok(None));
+        unindexed.start().unwrap();
+        assert_eq!(
+            unindexed.store.get_spend(address, &legacy.tx_id),
+            Ok(Some(legacy.tx_id))
+        );
+        assert_eq!(unindexed.list_transactions(other, 10, 0).unwrap().len(), 1);
+        assert_eq!(
+            unindexed.list_transactions(address, 10, 0).unwrap().len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_spend_from_multisig_address() {
+        let (mut node, _dir) = test_node();
+        mine(&mut node);
+
+        let cosigner = KeyPair::new();
+        let script = Script::multisig(2, &[node.keypair.public_key, cosigner.public_key]);
+        let multisig = Address::from_script(&script, Network::Regtest);
+        node.add_tx_to_mempool(&create_signed(&node.keypair, multisig, 100))
+            .unwrap();
+        mine(&mut node);
+        assert_eq!(node.store.get_balance(multisig), Ok(Some(100)));
+
+        let to = Address::from_public_key(&cosigner.public_key, Network::Regtest);
+        let keypair = KeyPair::from(node.keypair.private_key.display_secret().to_string()).unwrap();
+        let spend = |signers: Vec<&KeyPair>| {
+            tx::create_script_spend(&keypair, script.clone(), to, 60, |tx| {
+                Script::new(signers.iter().map(|k| tx::script_sig(k, tx)).collect())
+            })
+        };
+
+        assert!(node.add_tx_to_mempool(&spend(vec![&keypair])).is_err());
+        assert!(node
+            .add_tx_to_mempool(&spend(vec![&cosigner, &keypair]))
+            .is_err());
+
+        node.add_tx_to_mempool(&spend(vec![&keypair, &cosigner]))
+            .unwrap();
+        mine(&mut node);
+        assert_eq!(node.store.get_balance(multisig), Ok(Some(40)));
+        assert_eq!(node.store.get_balance(to), Ok(Some(60)));
+    }
+
+    #[test]
+    fn test_time_locks() {
+        let (mut node, _dir) = test_node();
+        mine(&mut node);
+        let to = Address::from_public_key(&KeyPair::new().public_key, Network::Regtest);
+
+        // Height lock, the next block is at height 2
+        let height_locked = node.create_locked_tx(to, 1, 3, None).unwrap();
+        assert!(node.add_tx_to_mempool(&height_locked).is_err());
+        mine(&mut node);
+        node.add_tx_to_mempool(&height_locked).unwrap();
+
+        // Time lock against the median time of the previous blocks
+        let median_time_past = node.median_time_past().unwrap() as u32;
+        let time_locked = node
+            .create_locked_tx(to, 1, median_time_past + 3600, None)
+            .unwrap();
+        assert!(node.add_tx_to_mempool(&time_locked).is_err());
+        let unlocked = node
+            .create_locked_tx(to, 1, median_time_past, None)
+            .unwrap();
+        node.add_tx_to_mempool(&unlocked).unwrap();
+
+        // Relative lock, two blocks after the unvaulting transaction confirms
+        let unvault = create_signed(&node.keypair, to, 1);
+        let relative_lock = tx::RelativeLock {
+            tx_id: unvault.tx_id(),
+            blocks: 2,
+        };
+        let vault_spend = node
+            .create_locked_tx(to, 1, 0, Some(relative_lock))
+            .unwrap();
+        node.add_tx_to_mempool(&unvault).unwrap();
+        assert!(node.add_tx_to_mempool(&vault_spend).is_err());
+
+        let block = mine(&mut node);
+        assert_eq!(block.transactions.len(), 4);
+        assert!(node.add_tx_to_mempool(&vault_spend).is_err());
+        mine(&mut node);
+        node.add_tx_to_mempool(&vault_spend).unwrap();
+
+        // Confirmed transactions can't be replayed
+        assert!(node.add_tx_to_mempool(&unvault).is_err());
+    }
+
+    #[test]
+    fn test_reject_other_network_address() {
+        let (mut node, _dir) = test_node();
+        mine(&mut node);
+        let mainnet = Address::from_public_key(&KeyPair::new().public_key, Network::Mainnet);
+
+        assert!(node.send_tx(mainnet, 12, SignatureScheme::Ecdsa).is_err());
+
+        let tx = create_signed(&node.keypair, mainnet, 12);
+        assert!(node.add_tx_to_mempool(&tx).is_err());
+    }
+
+    #[test]
+    fn test_signature_schemes() {
+        let (mut node, _dir) = test_node();
+        mine(&mut node);
+        let to = Address::from_public_key(&KeyPair::new().public_key, Network::Regtest);
+
+        let ecdsa = create_signed(&node.keypair, to, 1);
+        let schnorr = tx::create_signed_with(&node.keypair, to, 2, SignatureScheme::Schnorr);
+        assert!(ecdsa.is_sig_valid());
+        assert!(schnorr.is_sig_valid());
+
+        // A signature only verifies under the scheme it was made with
+        let retag = |tx: &SignedTransaction, scheme| {
+            let mut tx = tx.clone();
+            tx.transaction.scheme = scheme;
+            tx.transaction.tx_id = tx.transaction.serialize();
+            tx
+        };
+        assert!(!retag(&ecdsa, SignatureScheme::Schnorr).is_sig_valid());
+        assert!(!retag(&schnorr, SignatureScheme::Ecdsa).is_sig_valid());
+
+        let mut forged = schnorr.clone();
+        forged.sig = node.keypair.sign(&forged.transaction.hash()).to_string();
+        assert!(!forged.is_sig_valid());
+
+        node.add_tx_to_mempool(&ecdsa).unwrap();
+        node.add_tx_to_mempool(&schnorr).unwrap();
+        mine(&mut node);
+        assert_eq!(node.store.get_balance(to), Ok(Some(3)));
+    }
+
+    #[test]
+    fn test_spend_from_aggregated_key() {
+        let (mut node, _dir) = test_node();
+        mine(&mut node);
+
+        let signers: Vec<_> = (0..2).map(|_| KeyPair::new()).collect();
+        let key_agg = KeyAggContext::new(signers.iter().map(|k| k.public_key).collect()).unwrap();
+        let account = key_agg.address(Network::Regtest);
+        node.add_tx_to_mempool(&create_signed(&node.keypair, account, 100))
+            .unwrap();
+        mine(&mut node);
+
+        let to = node.address();
+        let transaction = tx::create_aggregate_spend(&key_agg, to, 60);
+        let message: [u8; 32] = transaction.hash().try_into().unwrap();
+        let (secret_nonces, public_nonces): (Vec<_>, Vec<_>) =
+            signers.iter().map(|_| musig::nonce_gen()).unzip();
+        let session = SigningSession::new(key_agg, &public_nonces, message).unwrap();
+        let partials: Vec<_> = signers
+            .iter()
+            .zip(secret_nonces)
+            .map(|(keypair, nonce)| session.partial_sign(keypair, nonce).unwrap())
+            .collect();
+
+        // One signer alone can't spend
+        let unsigned = SignedTransaction {
+            transaction,
+            sig: String::new(),
+            unlocking_script: Script::default(),
+        };
+        let mut partial_spend = unsigned.clone();
+        partial_spend.sig = node.keypair.sign_schnorr(&message).to_string();
+        assert!(node.add_tx_to_mempool(&partial_spend).is_err());
+
+        let spend = SignedTransaction {
+            sig: session.aggregate(&partials).unwrap().to_string(),
+            ..unsigned
+        };
+        node.add_tx_to_mempool(&spend).unwrap();
+        mine(&mut node);
+        assert_eq!(node.store.get_balance(account), Ok(Some(40)));
+    }
+
+    #[test]
+    fn test_mempool_survives_restart() {
+        let (mut node, dir) = test_node();
+        mine(&mut node);
+        let to = Address::from_public_key(&KeyPair::new().public_key, Network::Regtest);
+        let tx = create_signed(&node.keypair, to, 12);
+        node.add_tx_to_mempool(&tx).unwrap();
+        node.shutdown().unwrap();
+
+        let (block_tx, _) = mpsc::channel();
+        let (transaction_tx, _) = mpsc::channel();
+        let data_dir = dir.path().to_str().unwrap();
+        let mut restarted = Node::with_store(
+            block_tx,
+            transaction_tx,
+            data_dir,
+            Network::Regtest,
+            node.store.clone(),
+        );
+        restarted.coinbase_maturity = 0;
+        restarted.start().unwrap();
+        assert!(restarted.mempool.contains_key(&tx.tx_id()));
+
+        // Transactions confirmed meanwhile aren't loaded again
+        mine(&mut restarted);
+        node.mempool.clear();
+        node.mempool_changes.clear();
+        node.mempool_spends.clear();
+        node.mempool_order = MempoolOrder::default();
+        assert_eq!(node.load_mempool(), Ok(0));
+    }
+
+    #[test]
+    fn test_export_import_blocks() {
+        let (mut node, dir) = test_node();
+        mine(&mut node);
+        let to = Address::from_public_key(&KeyPair::new().public_key, Network::Regtest);
+        node.add_tx_to_mempool(&create_signed(&node.keypair, to, 12))
+            .unwrap();
+        mine(&mut node);
+        mine(&mut node);
+
+        node.export_dir = dir.path().to_str().unwrap().to_string();
+        assert_eq!(node.export_blocks("blocks.dat"), Ok(3));
+        let path = dir.path().join("blocks.dat");
+        let path = path.to_str().unwrap();
+
+        // Exports don't overwrite files or leave the export directory
+        assert!(node.export_blocks("blocks.dat").is_err());
+        assert!(node.export_blocks("../blocks.dat").is_err());
+        assert!(node.export_blocks(path).is_err());
+
+        let (mut imported, _imported_dir) = test_node();
+        assert_eq!(imported.import_blocks(path), Ok(3));
+        assert_eq!(
+            imported.get_latest_block().unwrap().unwrap().hash,
+            node.get_latest_block().unwrap().unwrap().hash
+        );
+        assert_eq!(imported.store.get_balance(to), Ok(Some(12)));
+
+        // Blocks we already have are skipped
+        assert_eq!(imported.import_blocks(path), Ok(0));
+
+        // Every block is validated again
+        let genesis_hash = node.store.get_block_hash(1).unwrap().unwrap();
+        let mut block = node.store.get_block(&genesis_hash).unwrap().unwrap();
+        block.transactions[0].transaction.amount += 1;
+        let mut file = fs::File::create(path).unwrap();
+        block_file::write_block(&mut file, Network::Regtest, &block).unwrap();
+        let (mut tampered, _tampered_dir) = test_node();
+        assert!(tampered.import_blocks(path).is_err());
+    }
+
+    #[test]
+    fn test_prune() {
+        let (mut node, dir) = test_node();
+        node.prune_depth = Some(1);
+        let payee = KeyPair::new();
+        let to = Address::from_public_key(&payee.public_key, Network::Regtest);
+        mine(&mut node);
+        let tx = create_signed(&node.keypair, to, 12);
+        node.add_tx_to_mempool(&tx).unwrap();
+        let coinbase = mine(&mut node).transactions[0].tx_id();
+        // The blocks median time past reads are kept whatever the depth
+        for _ in 0..12 {
+            mine(&mut node);
+        }
+
+        assert_eq!(node.store.get_prune_height(), Ok(3));
+        assert!(node.is_pruned(2).unwrap());
+        assert!(!node.is_pruned(4).unwrap());
+        let hash = node.store.get_block_hash(2).unwrap().unwrap();
+        assert!(node.store.get_block(&hash).unwrap().is_none());
+        assert!(node.store.get_header(&hash).unwrap().is_some());
+        assert_eq!(node.store.get_headers(0, 20).unwrap().len(), 14);
+
+        // Balances and replay protection don't need the pruned blocks
+        assert_eq!(node.store.get_balance(to), Ok(Some(12)));
+        let error = node.add_tx_to_mempool(&tx).unwrap_err();
+        assert!(error.contains("Already in the chain"));
+        assert!(node.list_transactions(to, 10, 0).is_err());
+        assert_eq!(node.list_transactions(to, 10, 1).unwrap().len(), 0);
+
+        // Relative locks resolve against transactions of pruned blocks
+        let relative_lock = tx::RelativeLock {
+            tx_id: coinbase,
+            blocks: 2,
+        };
+        let address = Address::from_public_key(&node.keypair.public_key, Network::Regtest);
+        let vault_spend = tx::create_locked(&payee, address, 1, 0, Some(relative_lock));
+        node.add_tx_to_mempool(&vault_spend).unwrap();
+        node.export_dir = dir.path().to_str().unwrap().to_string();
+        let error = node.export_blocks("blocks.dat").unwrap_err();
+        assert!(error.contains("pruned"));
+
+        // Reorganizations can't reach the pruned blocks
+        let error = node.reorganize(2, &[]).unwrap_err();
+        assert!(error.contains("pruned"));
+        node.disconnect_tip().unwrap();
+        assert_eq!(node.store.get_prune_height(), Ok(3));
+
+        // An index predating spend tracking can't be rebuilt once pruned
+        node.store.set_index_version(0).unwrap();
+        assert!(node.start().unwrap_err().contains("resync"));
+    }
+
+    #[test]
+    fn test_chain_state_snapshot() {
+        let (mut node, dir) = test_node();
+        let to = Address::from_public_key(&KeyPair::new().public_key, Network::Regtest);
+        mine(&mut node);
+        let tx = create_signed(&node.keypair, to, 12);
+        node.add_tx_to_mempool(&tx).unwrap();
+        for _ in 0..13 {
+            mine(&mut node);
+        }
+        node.export_dir = dir.path().to_str().unwrap().to_string();
+        let info = node.dump_chain_state("chainstate.json", None).unwrap();
+        assert_eq!(info.height, 14);
+        assert!(node.dump_chain_state("chainstate.json", None).is_err());
+        let path = dir.path().join("chainstate.json");
+        let path = path.to_str().unwrap();
+
+        let (mut other, _other_dir) = test_node();
+        assert!(other
+            .load_chain_state(path)
+            .unwrap_err()
+            .contains("configured"));
+        other.chainstate_hash = Some("00".repeat(32));
+        assert!(other.load_chain_state(path).unwrap_err().contains("hash"));
+        other.chainstate_hash = Some(info.hash.clone());
+        assert_eq!(other.load_chain_state(path), Ok(info.clone()));
+
+        // A load failing midway is rolled back to an empty chain
+        let snapshot: ChainStateSnapshot =
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        let (mut rolled_back, _rolled_back_dir) = test_node();
+        rolled_back.chainstate_hash = Some(info.hash.clone());
+        rolled_back.load_chain_state(path).unwrap();
+        rolled_back.clear_chain_state(&snapshot).unwrap();
+        assert_eq!(rolled_back.store.get_latest_block_number(), Ok(0));
+        assert_eq!(rolled_back.store.get_supply(), Ok(0));
+        assert_eq!(rolled_back.store.get_tx_height(&tx.tx_id()), Ok(None));
+        assert!(rolled_back.store.get_address_txs(to).unwrap().is_empty());
+        assert_eq!(rolled_back.load_chain_state(path), Ok(info.clone()));
+
+        assert!(other
+            .load_chain_state(path)
+            .unwrap_err()
+            .contains("isn't empty"));
+
+        // Only the blocks following ones are validated against are kept
+        assert_eq!(other.store.get_prune_height(), Ok(3));
+        assert_eq!(
+            other.get_latest_block().unwrap().unwrap().hash,
+            info.block_hash
+        );
+        assert_eq!(other.store.get_balances(), node.store.get_balances());
+        assert_eq!(other.store.get_supply(), node.store.get_supply());
+        assert_eq!(other.store.get_tx_height(&tx.tx_id()), Ok(Some(2)));
+        assert!(other
+            .add_tx_to_mempool(&tx)
+            .unwrap_err()
+            .contains("Already in the chain"));
+
+        other.process_block(&mine(&mut node)).unwrap();
+        assert_eq!(other.store.get_latest_block_number(), Ok(15));
+
+        // A changed balance changes the hash
+        let mut snapshot: ChainStateSnapshot =
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        *snapshot.balances.get_mut(&to).unwrap() += 1;
+        assert_ne!(snapshot.hash(), Ok(info.hash));
+        assert!(snapshot.verify(0).is_err());
+        *snapshot.balances.get_mut(&to).unwrap() -= 1;
+
+        // Blocks can't outnumber the headers
+        let mut truncated = snapshot.clone();
+        truncated.height = 2;
+        truncated.headers.truncate(2);
+        truncated.block_hash = truncated.headers[1].hash.clone();
+        let error = truncated.verify(0).unwrap_err();
+        assert!(error.contains("More blocks than the height"));
+
+        // Dumped below the tip, the state is the one the chain had there
+        let (mut lagging, _lagging_dir) = test_node();
+        for block_number in 1..=2 {
+            let hash = node.store.get_block_hash(block_number).unwrap().unwrap();
+            lagging
+                .process_block(&node.store.get_block(&hash).unwrap().unwrap())
+                .unwrap();
+        }
+        lagging.export_dir = node.export_dir.clone();
+        let at_tip = lagging.dump_chain_state("lagging.json", None).unwrap();
+        let below_tip = node.dump_chain_state("below.json", Some(2)).unwrap();
+        assert_eq!(below_tip, at_tip);
+        assert!(node.dump_chain_state("above.json", Some(16)).is_err());
+        assert!(node.dump_chain_state("genesis.json", Some(0)).is_err());
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let (mut node, _dir) = test_node();
+        let blocks: Vec<_> = (0..3).map(|_| mine(&mut node)).collect();
+
+        let (mut other, _other_dir) = test_node();
+        other.checkpoints = vec![Checkpoint {
+            height: 2,
+            hash: "00".repeat(32),
+        }];
+        other.process_block(&blocks[0]).unwrap();
+        let error = other.process_block(&blocks[1]).unwrap_err();
+        assert!(error.contains("Checkpoint mismatch"));
+
+        other.checkpoints[0].hash = blocks[1].hash.clone();
+        other.process_block(&blocks[1]).unwrap();
+        assert_eq!(other.last_checkpoint_height(), Ok(2));
+
+        // A longer fork below the checkpoint is refused
+        let fork: Vec<_> = (0..3).map(|_| mine(&mut other)).collect();
+        let error = node.reorganize(1, &fork).unwrap_err();
+        assert!(error.contains("Reorganization failed"));
+        node.checkpoints = other.checkpoints.clone();
+        let error = node.reorganize(1, &fork).unwrap_err();
+        assert!(error.contains("checkpoint at height 2"));
+        assert_eq!(
+            node.get_latest_block().unwrap().unwrap().hash,
+            blocks[2].hash
+        );
+    }
+
+    #[test]
+    fn test_assume_valid() {
+        let (mut node, _dir) = test_node();
+        let to = Address::from_public_key(&KeyPair::new().public_key, Network::Regtest);
+        let genesis = mine(&mut node);
+
+        // Only verifying signatures would reject the block
+        let mut forged = create_signed(&node.keypair, to, 12);
+        forged.sig = create_signed(&node.keypair, to, 13).sig;
+        let mut proposed = node.get_proposed_block().unwrap();
+        proposed.transactions.push(forged);
+        let block = miner::mine(proposed);
+        assert!(node
+            .process_block(&block)
+            .unwrap_err()
+            .contains("Invalid signature"));
+
+        let (mut other, _other_dir) = test_node();
+        other.assume_valid = Some(block.hash.clone());
+        let (mut unrelated, _unrelated_dir) = test_node();
+        other.mark_assumed_valid(&[mine(&mut unrelated), block.clone()]);
+        assert!(other.assumed_valid.is_empty());
+
+        let blocks = [genesis, block];
+
+        other.mark_assumed_valid(&blocks);
+        for block in &blocks {
+            other.process_block(block).unwrap();
+        }
+        assert_eq!(other.store.get_balance(to), Ok(Some(12)));
+        assert!(other.assumed_valid.is_empty());
+    }
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let (mut node, _dir) = test_node();
+        node.coinbase_maturity = 3;
+        let to = Address::from_public_key(&KeyPair::new().public_key, Network::Regtest);
+        let spend = |node: &Node| create_signed(&node.keypair, to, 600);
+
+        // Rewards of 512 at heights 1 and 2, none is mature for a block at 3
+        mine(&mut node);
+        mine(&mut node);
+        assert_eq!(node.immature_balance(node.address()), Ok(1024));
+        assert!(node.add_tx_to_mempool(&spend(&node)).is_err());
+
+        // Only the first reward is mature for a block at 4
+        mine(&mut node);
+        assert_eq!(node.immature_balance(node.address()), Ok(1024));
+        assert!(node.add_tx_to_mempool(&spend(&node)).is_err());
+
+        mine(&mut node);
+        node.add_tx_to_mempool(&spend(&node)).unwrap();
+    }
+
+    #[test]
+    fn test_supply() {
+        let (mut node, _dir) = test_node();
+        let to = Address::from_public_key(&KeyPair::new().public_key, Network::Regtest);
+        mine(&mut node);
+        node.add_tx_to_mempool(&create_signed(&node.keypair, to, 12))
+            .unwrap();
+        mine(&mut node);
+
+        let info = node.txoutset_info().unwrap();
+        assert_eq!(info.height, 2);
+        assert_eq!(info.addresses, 2);
+        assert_eq!(info.total_amount, 1024);
+        assert_eq!(node.supply_info().unwrap().issued, 1024);
+
+        node.disconnect_tip().unwrap();
+        assert_eq!(node.supply_info().unwrap().issued, 512);
+        assert_eq!(node.txoutset_info().unwrap().total_amount, 512);
+
+        node.store.set_supply(0).unwrap();
+        assert_eq!(node.init_supply(), Ok(512));
+
+        // Rewards stop once the cap is issued
+        let rewards: u64 = (1..=12 * 1024)
+            .map(|height| u64::from(node.get_block_reward(height)))
+            .sum();
+        assert_eq!(rewards, MAX_SUPPLY);
+        assert_eq!(node.get_block_reward(10 * 1024), 0);
+
+        node.store.set_supply(MAX_SUPPLY).unwrap();
+        let block = miner::mine(node.get_proposed_block().unwrap());
+        assert!(node.process_block(&block).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::{HashMap, VecDeque};
+
+use log::{debug, warn};
+
+use crate::block::Block;
+
+use super::{Node, GENESIS_PREV_BLOCK_HASH};
+
+/// Orphans kept at most, the oldest ones are dropped first
+pub const MAX_ORPHANS: usize = 100;
+
+/// Blocks received before their parent, keyed by the missing parent's hash
+#[derive(Debug, Default)]
+pub struct OrphanPool {
+    by_parent: HashMap<String, Vec<Block>>,
+    /// Hash and parent hash of the orphans, oldest first
+    arrival: VecDeque<(String, String)>,
+}
+
+impl OrphanPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.arrival.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.arrival.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= MAX_ORPHANS
+    }
+
+    pub fn contains(&self, block_hash: &str) -> bool {
+        self.arrival.iter().any(|(hash, _)| hash == block_hash)
+    }
+
+    /// Pools the block, false when it already was
+    pub fn insert(&mut self, block: Block) -> bool {
+        if self.contains(&block.hash) {
+            return false;
+        }
+
+        if self.is_full() {
+            if let Some((hash, parent)) = self.arrival.pop_front() {
+                debug!("Dropped orphan block hash={hash}");
+                self.remove(&hash, &parent);
+            }
+        }
+
+        self.arrival
+            .push_back((block.hash.clone(), block.prev_block.clone()));
+        self.by_parent
+            .entry(block.prev_block.clone())
+            .or_default()
+            .push(block);
+
+        true
+    }
+
+    /// Removes and returns the orphans waiting for `parent`
+    pub fn take_children(&mut self, parent: &str) -> Vec<Block> {
+        let children = self.by_parent.remove(parent).unwrap_or_default();
+        self.arrival.retain(|(_, p)| p != parent);
+        children
+    }
+
+    fn remove(&mut self, block_hash: &str, parent: &str) {
+        if let Some(siblings) = self.by_parent.get_mut(parent) {
+            siblings.retain(|b| b.hash != block_hash);
+            if siblings.is_empty() {
+                self.by_parent.remove(parent);
+            }
+        }
+    }
+}
+
+impl Node {
+    /// Whether the block's parent is unknown, so it can't be connected yet
+    pub fn is_orphan(&self, block: &Block) -> Result<bool, String> {
+        if block.prev_block == GENESIS_PREV_BLOCK_HASH {
+            return Ok(false);
+        }
+
+        // Pruned parents only have their header left
+        Ok(self.store.get_header(&block.prev_block)?.is_none())
+    }
+
+    /// Connects and relays the pooled descendants of `parent` now that it is
+    /// connected, returns how many were
+    pub fn connect_orphans(&mut self, parent: &str) -> usize {
+        let mut connected = 0;
+        let mut parents = vec![parent.to_string()];
+
+        while let Some(parent) = parents.pop() {
+            for block in self.orphans.take_children(&parent) {
+                match self.receive_block(&block) {
+                    Ok(()) => {
+                        connected += 1;
+                        parents.push(block.hash);
+                    }
+                    Err(e) => warn!("Dropped orphan block hash={} error={e}", block.hash),
+                }
+            }
+        }
+
+        connected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(hash: &str, prev_block: &str) -> Block {
+        Block {
+            hash: hash.to_string(),
+            prev_block: prev_block.to_string(),
+            timestamp: 0,
+            nonce: 0,
+            transactions: vec![],
+        }
+    }
+
+    #[test]
+    fn test_pool() {
+        let mut pool = OrphanPool::new();
+        assert!(pool.insert(block("b", "a")));
+        assert!(pool.insert(block("b2", "a")));
+        assert!(pool.insert(block("c", "b")));
+        assert!(!pool.insert(block("c", "b")));
+        assert_eq!(pool.len(), 3);
+
+        let children: Vec<_> = pool
+            .take_children("a")
+            .into_iter()
+            .map(|b| b.hash)
+            .collect();
+        assert_eq!(children, vec!["b", "b2"]);
+        assert!(!pool.contains("b"));
+        assert!(pool.contains("c"));
+        assert!(pool.take_children("a").is_empty());
+    }
+
+    #[test]
+    fn test_pool_is_bounded() {
+        let mut pool = OrphanPool::new();
+        for i in 0..=MAX_ORPHANS {
+            pool.insert(block(&format!("b{i}"), &format!("a{i}")));
+        }
+
+        assert_eq!(pool.len(), MAX_ORPHANS);
+        assert!(!pool.contains("b0"));
+        assert!(pool.take_children("a0").is_empty());
+        assert!(pool.contains(&format!("b{MAX_ORPHANS}")));
+    }
+}
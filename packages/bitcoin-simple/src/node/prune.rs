@@ -0,0 +1,45 @@
+use log::info;
+
+use super::{Node, MEDIAN_TIME_SPAN};
+
+/// Blocks whose bodies are kept below the tip at least, like Bitcoin's 288.
+/// Reorganizations can't go deeper.
+pub const MIN_PRUNE_DEPTH: u32 = 288;
+
+impl Node {
+    /// Deletes the transactions of the blocks more than `prune_depth` below
+    /// the tip, their headers and address index entries stay. Returns how
+    /// many blocks were pruned.
+    pub fn prune(&self) -> Result<u32, String> {
+        let Some(depth) = self.prune_depth else {
+            return Ok(0);
+        };
+        // Median time past and coinbase maturity read the blocks this deep
+        let depth = depth
+            .max(MEDIAN_TIME_SPAN as u32)
+            .max(self.coinbase_maturity);
+
+        let pruned = self.store.get_prune_height()?;
+        let target = self.store.get_latest_block_number()?.saturating_sub(depth);
+        if target <= pruned {
+            return Ok(0);
+        }
+
+        for block_number in pruned + 1..=target {
+            let block_hash = self
+                .store
+                .get_block_hash(block_number)?
+                .ok_or_else(|| format!("Missing block at height {block_number}"))?;
+            self.store.prune_block(&block_hash)?;
+        }
+        self.store.set_prune_height(target)?;
+        info!("Pruned blocks count={} height={target}", target - pruned);
+
+        Ok(target - pruned)
+    }
+
+    /// Whether the transactions of the active chain's block at `height` were deleted
+    pub fn is_pruned(&self, height: u32) -> Result<bool, String> {
+        Ok(height <= self.store.get_prune_height()?)
+    }
+}
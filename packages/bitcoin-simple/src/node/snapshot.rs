@@ -0,0 +1,314 @@
+use std::{collections::BTreeMap, fs, io, io::Write};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::{Block, BlockHeader},
+    crypto::{self, Address},
+    storage::TxLocation,
+};
+
+use super::{
+    history::INDEX_VERSION, supply, Node, GENESIS_PREV_BLOCK_HASH, MAX_SUPPLY, MEDIAN_TIME_SPAN,
+};
+
+/// Chain state at a height, what a node starts from instead of replaying the
+/// blocks below it, like Bitcoin's assumeutxo snapshots. Only trusted when
+/// its hash matches the configured one.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ChainStateSnapshot {
+    pub height: u32,
+    pub block_hash: String,
+    pub supply: u64,
+    pub balances: BTreeMap<Address, u32>,
+    /// Headers of the blocks up to `height`, so the following ones link
+    pub headers: Vec<BlockHeader>,
+    /// Last blocks, those median time past and coinbase maturity read
+    pub blocks: Vec<Block>,
+    /// Address index, replay protection relies on it
+    pub address_txs: BTreeMap<Address, Vec<TxLocation>>,
+}
+
+/// What `dumpchainstate` and `loadchainstate` report
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SnapshotInfo {
+    pub height: u32,
+    pub block_hash: String,
+    /// Commitment to the whole snapshot
+    pub hash: String,
+}
+
+impl ChainStateSnapshot {
+    /// Hex encoded SHA256 of the snapshot's JSON, deterministic as maps are sorted
+    pub fn hash(&self) -> Result<String, String> {
+        let json = serde_json::to_string(self).map_err(|e| e.to_string())?;
+        Ok(hex::encode(crypto::sha256(json)))
+    }
+
+    pub fn info(&self) -> Result<SnapshotInfo, String> {
+        Ok(SnapshotInfo {
+            height: self.height,
+            block_hash: self.block_hash.clone(),
+            hash: self.hash()?,
+        })
+    }
+
+    /// Checks the headers form a chain ending at `block_hash`, the blocks are
+    /// its last ones and the balances add up to the supply. The hash is what
+    /// vouches for the balances themselves.
+    pub fn verify(&self, min_blocks: u32) -> Result<(), String> {
+        if self.height == 0 || self.headers.len() != self.height as usize {
+            return Err("Snapshot verification failed: Header count mismatch".to_string());
+        }
+
+        let mut prev_block = GENESIS_PREV_BLOCK_HASH;
+        for header in &self.headers {
+            header.verify()?;
+            if header.prev_block != prev_block {
+                return Err(format!(
+                    "Snapshot verification failed: Header {} doesn't link",
+                    header.hash
+                ));
+            }
+            prev_block = &header.hash;
+        }
+        if prev_block != self.block_hash {
+            return Err("Snapshot verification failed: Tip hash mismatch".to_string());
+        }
+
+        if (self.blocks.len() as u32) < min_blocks.min(self.height) {
+            return Err("Snapshot verification failed: Too few blocks".to_string());
+        }
+        if self.blocks.len() > self.headers.len() {
+            return Err("Snapshot verification failed: More blocks than the height".to_string());
+        }
+        let first = self.headers.len().saturating_sub(self.blocks.len());
+        for (block, header) in self.blocks.iter().zip(&self.headers[first..]) {
+            if block.header() != *header {
+                return Err(format!(
+                    "Snapshot verification failed: Block {} isn't in the chain",
+                    block.hash
+                ));
+            }
+        }
+
+        let total: u64 = self.balances.values().map(|&b| b as u64).sum();
+        if total != self.supply || self.supply > MAX_SUPPLY {
+            return Err(
+                "Snapshot verification failed: Balances don't match the supply".to_string(),
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Takes the balance changes of the block's transactions off `balances`
+fn undo_balances(balances: &mut BTreeMap<Address, u32>, block: &Block) -> Result<(), String> {
+    for (i, tx) in block.transactions.iter().enumerate().rev() {
+        for (receiver, amount) in tx.transaction.payments() {
+            let balance = balances.entry(receiver).or_default();
+            *balance = balance
+                .checked_sub(amount)
+                .ok_or("Dump failed: Balance underflow")?;
+        }
+
+        // Coinbases have no sender
+        if i > 0 {
+            let balance = balances.entry(tx.transaction.sender()).or_default();
+            *balance = u32::try_from(u64::from(*balance) + tx.transaction.debit())
+                .map_err(|_| "Dump failed: Balance overflow")?;
+        }
+    }
+
+    Ok(())
+}
+
+impl Node {
+    /// Bodies a snapshot carries, those the following blocks are validated against
+    fn snapshot_blocks(&self) -> u32 {
+        (MEDIAN_TIME_SPAN as u32).max(self.coinbase_maturity)
+    }
+
+    /// Block of the active chain at `block_number`, which must not be pruned
+    fn snapshot_block(&self, block_number: u32) -> Result<Block, String> {
+        if self.is_pruned(block_number)? {
+            return Err(format!(
+                "Dump failed: Block at height {block_number} is pruned"
+            ));
+        }
+
+        self.store
+            .get_block_hash(block_number)?
+            .and_then(|hash| self.store.get_block(&hash).transpose())
+            .ok_or_else(|| format!("Missing block at height {block_number}"))?
+    }
+
+    /// Writes the chain state at `height`, the tip by default, to the new file
+    /// `name` of the export directory. Below the tip the state is rebuilt by
+    /// undoing the blocks above it, which must not be pruned.
+    pub fn dump_chain_state(
+        &self,
+        name: &str,
+        height: Option<u32>,
+    ) -> Result<SnapshotInfo, String> {
+        let tip = self.store.get_latest_block_number()?;
+        if tip == 0 {
+            return Err("Dump failed: Chain is empty".to_string());
+        }
+        let height = height.unwrap_or(tip);
+        let block_hash = self
+            .store
+            .get_block_hash(height)?
+            .filter(|_| height > 0)
+            .ok_or_else(|| format!("Dump failed: No block at height {height}"))?;
+
+        let mut balances: BTreeMap<_, _> = self.store.get_balances()?.into_iter().collect();
+        let mut supply = self.store.get_supply()?;
+        for block_number in (height + 1..=tip).rev() {
+            let block = self.snapshot_block(block_number)?;
+            undo_balances(&mut balances, &block)?;
+            supply = supply
+                .checked_sub(supply::block_issuance(&block))
+                .ok_or("Dump failed: Supply underflow")?;
+        }
+
+        let mut blocks = vec![];
+        for block_number in height.saturating_sub(self.snapshot_blocks()) + 1..=height {
+            blocks.push(self.snapshot_block(block_number)?);
+        }
+
+        let mut address_txs = BTreeMap::new();
+        for &address in balances.keys() {
+            let mut locations = self.store.get_address_txs(address)?;
+            locations.retain(|location| location.height <= height);
+            // Addresses are only in the chain state once a transaction touched them
+            if !locations.is_empty() {
+                address_txs.insert(address, locations);
+            }
+        }
+        balances.retain(|address, _| address_txs.contains_key(address));
+
+        let snapshot = ChainStateSnapshot {
+            height,
+            block_hash,
+            supply,
+            balances,
+            headers: self.store.get_headers(0, height)?,
+            blocks,
+            address_txs,
+        };
+
+        let (file, path) = self.create_export_file(name)?;
+        let mut writer = io::BufWriter::new(file);
+        serde_json::to_writer(&mut writer, &snapshot).map_err(|e| e.to_string())?;
+        writer.flush().map_err(|e| e.to_string())?;
+
+        let info = snapshot.info()?;
+        info!(
+            "Dumped chain state height={height} hash={} path={}",
+            info.hash,
+            path.display()
+        );
+        Ok(info)
+    }
+
+    /// Starts an empty chain from the snapshot at `path`, which must hash to
+    /// `chainstate_hash`. Blocks below it stay pruned, the following ones are
+    /// validated as usual.
+    pub fn load_chain_state(&mut self, path: &str) -> Result<SnapshotInfo, String> {
+        if self.store.get_latest_block_number()? > 0 {
+            return Err("Load failed: Chain isn't empty".to_string());
+        }
+        let expected = self
+            .chainstate_hash
+            .clone()
+            .ok_or("Load failed: No chain state hash configured")?;
+
+        let data = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let snapshot: ChainStateSnapshot =
+            serde_json::from_str(&data).map_err(|e| e.to_string())?;
+        let info = snapshot.info()?;
+        if info.hash != expected {
+            return Err(format!(
+                "Load failed: Snapshot hash {} isn't the configured one",
+                info.hash
+            ));
+        }
+        snapshot.verify(self.snapshot_blocks())?;
+
+        if let Err(e) = self.write_chain_state(&snapshot) {
+            self.clear_chain_state(&snapshot)
+                .map_err(|clear| format!("Load failed: {e}, rollback failed: {clear}"))?;
+            return Err(format!("Load failed: {e}"));
+        }
+
+        info!(
+            "Loaded chain state height={} hash={} path={path}",
+            info.height, info.hash
+        );
+        self.tip_changed()?;
+
+        Ok(info)
+    }
+
+    /// Writes the snapshot to the store, the chain tip last so the chain stays
+    /// empty until everything else is written
+    fn write_chain_state(&self, snapshot: &ChainStateSnapshot) -> Result<(), String> {
+        let pruned = snapshot.height - snapshot.blocks.len() as u32;
+        for header in &snapshot.headers[..pruned as usize] {
+            self.store.add_header(header)?;
+        }
+        for block in &snapshot.blocks {
+            self.store.add_block(block)?;
+        }
+        self.store.set_prune_height(pruned)?;
+
+        for (&address, &balance) in &snapshot.balances {
+            self.store.set_balance(address, balance)?;
+        }
+        for (&address, locations) in &snapshot.address_txs {
+            for location in locations {
+                self.store.add_address_tx(address, location)?;
+                self.store.add_tx_height(&location.tx_id, location.height)?;
+                if let Some(spend_id) = &location.spends {
+                    self.store.add_spend(address, spend_id, &location.tx_id)?;
+                }
+            }
+        }
+        self.store.set_supply(snapshot.supply)?;
+        self.store.set_index_version(INDEX_VERSION)?;
+
+        for (height, header) in (1..).zip(&snapshot.headers) {
+            self.store.set_latest_block_hash(&header.hash, height)?;
+        }
+
+        Ok(())
+    }
+
+    /// Undoes what `write_chain_state` wrote of the snapshot, leaving an empty
+    /// chain a snapshot can be loaded into again. Stored blocks and headers
+    /// are kept, nothing refers to them.
+    pub(super) fn clear_chain_state(&self, snapshot: &ChainStateSnapshot) -> Result<(), String> {
+        for (height, header) in (1..).zip(&snapshot.headers) {
+            self.store
+                .unset_latest_block_hash(&header.hash, height, None)?;
+        }
+        self.store.set_supply(0)?;
+        for (&address, locations) in &snapshot.address_txs {
+            for location in locations {
+                self.store.remove_address_tx(address, location)?;
+                self.store.remove_tx_height(&location.tx_id)?;
+                if let Some(spend_id) = &location.spends {
+                    self.store.remove_spend(address, spend_id)?;
+                }
+            }
+        }
+        for &address in snapshot.balances.keys() {
+            self.store.set_balance(address, 0)?;
+        }
+
+        self.store.set_prune_height(0)
+    }
+}
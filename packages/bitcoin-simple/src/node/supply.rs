@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{block::Block, crypto::Address};
+
+use super::Node;
+
+/// Blocks after which a coinbase reward can be spent, like Bitcoin's
+pub const COINBASE_MATURITY: u32 = 100;
+
+/// Sum of every block reward, the genesis block being at height 1
+pub const MAX_SUPPLY: u64 = 1_047_040;
+
+/// Summary of the balances, what Bitcoin's `gettxoutsetinfo` reports for its UTXO set
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TxOutSetInfo {
+    pub height: u32,
+    pub best_block: Option<String>,
+    /// Addresses with a non zero balance
+    pub addresses: usize,
+    pub total_amount: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SupplyInfo {
+    pub height: u32,
+    pub issued: u64,
+    pub max_supply: u64,
+    pub next_block_reward: u32,
+}
+
+/// Fees the block's transactions pay to its coinbase
+pub fn block_fees(block: &Block) -> u64 {
+    block
+        .transactions
+        .iter()
+        .skip(1)
+        .map(|tx| u64::from(tx.transaction.fee))
+        .sum()
+}
+
+/// Amount issued by the block's coinbase, the fees it collects aren't new
+pub fn block_issuance(block: &Block) -> u64 {
+    block
+        .transactions
+        .first()
+        .map_or(0, |tx| u64::from(tx.transaction.amount))
+        .saturating_sub(block_fees(block))
+}
+
+impl Node {
+    /// Coinbase rewards of `address` that can't be spent in the next block yet
+    pub fn immature_balance(&self, address: Address) -> Result<u32, String> {
+        let height = self.store.get_latest_block_number()?;
+        // Rewards of the block at `h` can be spent from height `h + coinbase_maturity` on
+        let first_immature = (height + 2).saturating_sub(self.coinbase_maturity).max(1);
+
+        let mut immature = 0;
+        for block_number in first_immature..=height {
+            let block = self
+                .store
+                .get_block_hash(block_number)?
+                .and_then(|hash| self.store.get_block(&hash).transpose())
+                .ok_or_else(|| format!("Missing block at height {block_number}"))??;
+            immature += block
+                .transactions
+                .first()
+                .filter(|coinbase| coinbase.transaction.to == address)
+                .map_or(0, |coinbase| coinbase.transaction.amount);
+        }
+
+        Ok(immature)
+    }
+
+    /// Stores created before the supply was tracked have none, it is summed
+    /// from the chain once.
+    pub fn init_supply(&self) -> Result<u64, String> {
+        let supply = self.store.get_supply()?;
+        let height = self.store.get_latest_block_number()?;
+        if supply > 0 || height == 0 {
+            return Ok(supply);
+        }
+
+        let mut supply = 0;
+        for block_number in 1..=height {
+            let block = self
+                .store
+                .get_block_hash(block_number)?
+                .and_then(|hash| self.store.get_block(&hash).transpose())
+                .ok_or_else(|| format!("Missing block at height {block_number}"))??;
+            supply += block_issuance(&block);
+        }
+        self.store.set_supply(supply)?;
+
+        Ok(supply)
+    }
+
+    pub fn txoutset_info(&self) -> Result<TxOutSetInfo, String> {
+        let balances = self.store.get_balances()?;
+
+        Ok(TxOutSetInfo {
+            height: self.store.get_latest_block_number()?,
+            best_block: self.store.get_latest_block_hash()?,
+            addresses: balances.values().filter(|b| **b > 0).count(),
+            total_amount: balances.values().map(|b| u64::from(*b)).sum(),
+        })
+    }
+
+    pub fn supply_info(&self) -> Result<SupplyInfo, String> {
+        let height = self.store.get_latest_block_number()?;
+
+        Ok(SupplyInfo {
+            height,
+            issued: self.store.get_supply()?,
+            max_supply: MAX_SUPPLY,
+            next_block_reward: self.get_block_reward(height + 1),
+        })
+    }
+}
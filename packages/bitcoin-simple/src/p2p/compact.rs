@@ -0,0 +1,200 @@
+//! Compact block relay in the spirit of BIP152: new blocks are announced with
+//! short ids of their transactions, peers look them up in their mempool and
+//! only fetch the ones they miss.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::block::{Block, BlockHeader};
+use crate::crypto;
+use crate::tx::SignedTransaction;
+
+/// A block with its transactions replaced by short ids, but the coinbase no
+/// peer can have yet.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    /// Short ids of the transactions not prefilled, in block order
+    pub short_ids: Vec<u64>,
+    pub prefilled: Vec<PrefilledTransaction>,
+}
+
+/// A transaction sent in full at its position in the block
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PrefilledTransaction {
+    pub index: u32,
+    pub tx: SignedTransaction,
+}
+
+/// Transactions of a block asked for with GET_BLOCK_TXN, by position
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BlockTxnRequest {
+    pub block_hash: String,
+    pub indexes: Vec<u32>,
+}
+
+/// 6 bytes of the transaction id hashed with the block's, so ids colliding in
+/// one block don't in the others.
+pub fn short_id(block_hash: &str, tx_id: &str) -> u64 {
+    let hash = crypto::sha256(format!("{block_hash}{tx_id}"));
+    let mut bytes = [0; 8];
+    bytes[2..].copy_from_slice(&hash[..6]);
+    u64::from_be_bytes(bytes)
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block) -> Self {
+        let mut transactions = block.transactions.iter();
+        let prefilled = transactions
+            .next()
+            .map(|coinbase| PrefilledTransaction {
+                index: 0,
+                tx: coinbase.clone(),
+            })
+            .into_iter()
+            .collect();
+
+        Self {
+            header: block.header(),
+            short_ids: transactions
+                .map(|tx| short_id(&block.hash, &tx.tx_id()))
+                .collect(),
+            prefilled,
+        }
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.header.hash
+    }
+
+    /// Fills in the prefilled transactions and those of `mempool` matching a
+    /// short id, the others are left missing.
+    pub fn reconstruct<'a>(
+        &self,
+        mempool: impl IntoIterator<Item = &'a SignedTransaction>,
+    ) -> Result<PartialBlock, String> {
+        let count = self.short_ids.len() + self.prefilled.len();
+        let mut transactions = vec![None; count];
+        for prefilled in &self.prefilled {
+            let slot = transactions
+                .get_mut(prefilled.index as usize)
+                .ok_or("Compact block reconstruction failed: Invalid prefilled index")?;
+            *slot = Some(prefilled.tx.clone());
+        }
+
+        let by_short_id: HashMap<_, _> = mempool
+            .into_iter()
+            .map(|tx| (short_id(self.hash(), &tx.tx_id()), tx))
+            .collect();
+        let free = transactions.iter().filter(|tx| tx.is_none()).count();
+        if free != self.short_ids.len() {
+            return Err(
+                "Compact block reconstruction failed: Duplicate prefilled index".to_string(),
+            );
+        }
+        let slots = transactions.iter_mut().filter(|tx| tx.is_none());
+        for (slot, id) in slots.zip(&self.short_ids) {
+            *slot = by_short_id.get(id).map(|&tx| tx.clone());
+        }
+
+        Ok(PartialBlock {
+            header: self.header.clone(),
+            transactions,
+        })
+    }
+}
+
+/// A compact block's transactions, `None` where the mempool didn't have them
+#[derive(Debug, Clone)]
+pub struct PartialBlock {
+    header: BlockHeader,
+    transactions: Vec<Option<SignedTransaction>>,
+}
+
+impl PartialBlock {
+    /// Positions of the transactions to request from the sender
+    pub fn missing(&self) -> Vec<u32> {
+        (0..)
+            .zip(&self.transactions)
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Completes the block with the `missing` transactions, in order. Fails if
+    /// a short id matched the wrong transaction, the full block is needed then.
+    pub fn fill(self, missing: Vec<SignedTransaction>) -> Result<Block, String> {
+        let mut missing = missing.into_iter();
+        let transactions = self
+            .transactions
+            .into_iter()
+            .map(|tx| tx.or_else(|| missing.next()))
+            .collect::<Option<Vec<_>>>()
+            .ok_or("Compact block reconstruction failed: Missing transactions")?;
+
+        let block = Block {
+            hash: self.header.hash.clone(),
+            prev_block: self.header.prev_block.clone(),
+            timestamp: self.header.timestamp,
+            nonce: self.header.nonce,
+            transactions,
+        };
+        if missing.next().is_some() || block.header() != self.header {
+            return Err("Compact block reconstruction failed: Merkle root mismatch".to_string());
+        }
+
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{Address, KeyPair, Network};
+    use crate::tx;
+
+    fn test_block() -> Block {
+        let keypair = KeyPair::new();
+        let to = Address::from_public_key(&keypair.public_key, Network::Regtest);
+        let transactions: Vec<_> = (1..=4)
+            .map(|amount| tx::create_signed(&keypair, to, amount))
+            .collect();
+
+        let mut block = Block {
+            hash: String::new(),
+            prev_block: "00".to_string(),
+            timestamp: 1,
+            nonce: 0,
+            transactions,
+        };
+        block.hash = hex::encode(crypto::sha256(block.serialize()));
+        block
+    }
+
+    #[test]
+    fn test_reconstruct_from_mempool() {
+        let block = test_block();
+        let compact = CompactBlock::new(&block);
+        assert_eq!(compact.prefilled.len(), 1);
+        assert_eq!(compact.short_ids.len(), 3);
+
+        let partial = compact.reconstruct(&block.transactions[1..]).unwrap();
+        assert!(partial.missing().is_empty());
+        assert_eq!(partial.fill(vec![]).unwrap().tx_ids(), block.tx_ids());
+
+        // Transactions missing from the mempool are filled in from the sender
+        let partial = compact.reconstruct(&block.transactions[2..3]).unwrap();
+        assert_eq!(partial.missing(), vec![1, 3]);
+        let missing = vec![block.transactions[1].clone(), block.transactions[3].clone()];
+        assert_eq!(partial.clone().fill(missing).unwrap().hash, block.hash);
+
+        let wrong = vec![block.transactions[3].clone(), block.transactions[1].clone()];
+        assert!(partial.clone().fill(wrong).is_err());
+        assert!(partial.fill(vec![]).is_err());
+
+        let mut duplicate = compact.clone();
+        duplicate.prefilled.push(duplicate.prefilled[0].clone());
+        assert!(duplicate.reconstruct(&block.transactions).is_err());
+    }
+}
@@ -0,0 +1,266 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use secp256k1::{PublicKey, SecretKey};
+
+use super::transport::{Handshake, Session, MAX_FRAME_SIZE};
+use super::{MESSAGE_ENCRYPT, MESSAGE_ERROR};
+
+/// Messages and responses are framed as single lines terminated by `\r\n`.
+pub const MESSAGE_DELIMITER: &str = "\r\n";
+/// Longest plaintext message accepted, the same as encrypted ones
+pub const MAX_MESSAGE_SIZE: usize = MAX_FRAME_SIZE;
+/// Inbound connections served at once, more are turned away
+pub const MAX_INBOUND_CONNECTIONS: usize = 125;
+
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long an outbound request waits for the peer to answer.
+pub const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long an inbound connection may stay silent before the server drops it.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// A long-lived outbound connection to a peer, carrying one request and one
+/// response at a time.
+#[derive(Debug)]
+pub struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    /// Set once the peer agreed to encrypt the connection
+    session: Option<Session>,
+}
+
+impl Connection {
+    pub fn open(addr: &str) -> std::io::Result<Self> {
+        let socket_addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid peer address")
+        })?;
+
+        let stream = TcpStream::connect_timeout(&socket_addr, CONNECT_TIMEOUT)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            session: None,
+        })
+    }
+
+    /// Switches to the encrypted transport, must come before any other message.
+    /// Returns the peer's identity key, or `None` when the peer doesn't support
+    /// it and the connection stays plaintext. Fails if the peer announces
+    /// another key than the `pinned` one, or stays plaintext despite one.
+    pub fn encrypt(
+        &mut self,
+        identity: &SecretKey,
+        pinned: Option<&PublicKey>,
+    ) -> std::io::Result<Option<PublicKey>> {
+        let handshake = Handshake::new(identity);
+        let resp = self.request(&format!("{}({})", MESSAGE_ENCRYPT, handshake.keys()))?;
+        let remote = match parse_response(resp) {
+            Ok(remote) => remote,
+            Err(_) if pinned.is_none() => return Ok(None),
+            Err(e) => return Err(invalid_data(format!("Peer with a pinned key: {e}"))),
+        };
+
+        let (remote_identity, session) = handshake.finish(&remote, true).map_err(invalid_data)?;
+        if pinned.is_some_and(|key| *key != remote_identity) {
+            return Err(invalid_data(format!(
+                "Peer identity {remote_identity} isn't the pinned one"
+            )));
+        }
+        self.session = Some(session);
+
+        Ok(Some(remote_identity))
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Sends `msg` and waits for the raw response line.
+    pub fn request(&mut self, msg: &str) -> std::io::Result<String> {
+        send(&mut self.writer, &mut self.session, msg)?;
+
+        receive(&mut self.reader, &mut self.session)?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Connection closed by peer",
+            )
+        })
+    }
+}
+
+fn invalid_data(error: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+}
+
+/// Counts the connections being served, up to `max` of them
+#[derive(Debug, Clone)]
+pub struct ConnectionLimit {
+    count: Arc<AtomicUsize>,
+    max: usize,
+}
+
+/// A connection counted by a `ConnectionLimit` until dropped
+#[derive(Debug)]
+pub struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            count: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Counts one more connection, `None` when `max` already are
+    pub fn acquire(&self) -> Option<ConnectionSlot> {
+        self.count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < self.max).then_some(count + 1)
+            })
+            .ok()?;
+        Some(ConnectionSlot(self.count.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Splits an `ERROR(reason)` response sent back by the peer from a regular one.
+pub fn parse_response(resp: String) -> Result<String, String> {
+    match resp
+        .strip_prefix(MESSAGE_ERROR)
+        .and_then(|e| e.strip_prefix('('))
+        .and_then(|e| e.strip_suffix(')'))
+    {
+        Some(err) => Err(err.to_string()),
+        None => Ok(resp),
+    }
+}
+
+pub fn error_response(err: &str) -> String {
+    format!("{}({})", MESSAGE_ERROR, err.replace(['\r', '\n'], " "))
+}
+
+pub fn write_message(stream: &mut TcpStream, msg: &str) -> std::io::Result<()> {
+    stream.write_all(format!("{}{}", msg, MESSAGE_DELIMITER).as_bytes())?;
+    stream.flush()
+}
+
+/// Reads the next message, returning `None` once the peer closed the connection.
+/// Fails on lines longer than `MAX_MESSAGE_SIZE`.
+pub fn read_message(reader: &mut impl BufRead) -> std::io::Result<Option<String>> {
+    let mut line = vec![];
+    let limit = MAX_MESSAGE_SIZE + MESSAGE_DELIMITER.len();
+    if reader.take(limit as u64).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") && line.len() == limit {
+        return Err(invalid_data("Message too large".to_string()));
+    }
+
+    let line = String::from_utf8(line).map_err(|_| invalid_data("Invalid UTF-8".to_string()))?;
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+/// Writes `msg`, encrypted if the connection has a session
+pub fn send(
+    stream: &mut TcpStream,
+    session: &mut Option<Session>,
+    msg: &str,
+) -> std::io::Result<()> {
+    match session {
+        Some(session) => session.write_message(stream, msg),
+        None => write_message(stream, msg),
+    }
+}
+
+/// Reads the next message, decrypting it if the connection has a session
+pub fn receive(
+    reader: &mut BufReader<TcpStream>,
+    session: &mut Option<Session>,
+) -> std::io::Result<Option<String>> {
+    match session {
+        Some(session) => session.read_message(reader),
+        None => read_message(reader),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_connection_is_reused_for_several_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            while let Some(msg) = read_message(&mut reader).unwrap() {
+                let resp = match msg.as_str() {
+                    "PING" => "OK".to_string(),
+                    _ => error_response("Invalid\r\nMESSAGE"),
+                };
+                write_message(&mut writer, &resp).unwrap();
+            }
+        });
+
+        // The server doesn't know ENCRYPT, so the connection stays plaintext
+        let mut conn = Connection::open(&addr).unwrap();
+        let identity = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        assert_eq!(conn.encrypt(&identity, None).unwrap(), None);
+        assert!(!conn.is_encrypted());
+        assert_eq!(
+            parse_response(conn.request("PING").unwrap()),
+            Ok("OK".into())
+        );
+        assert_eq!(
+            parse_response(conn.request("PING").unwrap()),
+            Ok("OK".into())
+        );
+        assert_eq!(
+            parse_response(conn.request("FOO").unwrap()),
+            Err("Invalid  MESSAGE".into())
+        );
+    }
+
+    #[test]
+    fn test_message_size_is_limited() {
+        let line = format!("PING{}", "x".repeat(MAX_MESSAGE_SIZE - 4));
+        let wire = format!("{line}{MESSAGE_DELIMITER}PING{MESSAGE_DELIMITER}");
+        let mut reader = wire.as_bytes();
+        assert_eq!(read_message(&mut reader).unwrap(), Some(line.clone()));
+        assert_eq!(read_message(&mut reader).unwrap(), Some("PING".to_string()));
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+
+        // Lines without an end are cut off at the limit
+        let wire = format!("{line}x{MESSAGE_DELIMITER}");
+        assert!(read_message(&mut wire.as_bytes()).is_err());
+        assert!(read_message(&mut "x".repeat(2 * MAX_MESSAGE_SIZE).as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let limit = ConnectionLimit::new(2);
+        let first = limit.acquire().unwrap();
+        let _second = limit.acquire().unwrap();
+        assert!(limit.acquire().is_none());
+
+        drop(first);
+        assert!(limit.acquire().is_some());
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::{HashSet, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::block::Block;
+use crate::tx::SignedTransaction;
+
+/// How many inventory hashes are remembered per peer before the oldest are forgotten
+pub const MAX_KNOWN_INVENTORY: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InventoryKind {
+    Block,
+    Transaction,
+}
+
+/// Announces that the sender has a block or transaction, without its content.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct Inventory {
+    pub kind: InventoryKind,
+    pub hash: String,
+}
+
+impl Inventory {
+    pub fn block(hash: impl Into<String>) -> Self {
+        Self {
+            kind: InventoryKind::Block,
+            hash: hash.into(),
+        }
+    }
+
+    pub fn transaction(tx_id: impl Into<String>) -> Self {
+        Self {
+            kind: InventoryKind::Transaction,
+            hash: tx_id.into(),
+        }
+    }
+}
+
+/// Content sent back in response to a GETDATA request
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InventoryItem {
+    Block(Block),
+    Transaction(Box<SignedTransaction>),
+}
+
+impl InventoryItem {
+    pub fn inventory(&self) -> Inventory {
+        match self {
+            Self::Block(block) => Inventory::block(&block.hash),
+            Self::Transaction(tx) => Inventory::transaction(tx.tx_id()),
+        }
+    }
+}
+
+/// Inventory a peer is known to have, either because it announced it to us or
+/// because we already sent it. Bounded so long-lived peers don't grow it forever.
+#[derive(Debug, Clone, Default)]
+pub struct KnownInventory {
+    hashes: HashSet<Inventory>,
+    order: VecDeque<Inventory>,
+}
+
+impl KnownInventory {
+    pub fn contains(&self, inventory: &Inventory) -> bool {
+        self.hashes.contains(inventory)
+    }
+
+    pub fn insert(&mut self, inventory: Inventory) {
+        if !self.hashes.insert(inventory.clone()) {
+            return;
+        }
+
+        self.order.push_back(inventory);
+        if self.order.len() > MAX_KNOWN_INVENTORY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_inventory_forgets_oldest() {
+        let mut known = KnownInventory::default();
+        for i in 0..=MAX_KNOWN_INVENTORY {
+            known.insert(Inventory::transaction(i.to_string()));
+        }
+
+        assert!(!known.contains(&Inventory::transaction("0")));
+        assert!(known.contains(&Inventory::transaction("1")));
+        assert!(known.contains(&Inventory::transaction(MAX_KNOWN_INVENTORY.to_string())));
+        assert!(!known.contains(&Inventory::block("1")));
+    }
+}
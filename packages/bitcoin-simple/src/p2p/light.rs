@@ -0,0 +1,203 @@
+//! SPV client that keeps only block headers and checks transactions with
+//! merkle proofs fetched from a full node.
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use secp256k1::{rand, SecretKey};
+
+use crate::block::{merkle::TxOutProof, BlockHeader};
+use crate::node::GENESIS_PREV_BLOCK_HASH;
+
+use super::connection::{self, Connection};
+use super::version::{VersionMessage, USER_AGENT};
+use super::{handshake, MAX_HEADERS, MESSAGE_GET_HEADERS, MESSAGE_GET_TX_PROOF};
+
+pub struct LightClient {
+    pub peer: String,
+    /// Header chain of the peer, checked for proof of work and linking
+    pub headers: Vec<BlockHeader>,
+    heights: HashMap<String, u32>,
+    conn: Connection,
+}
+
+impl LightClient {
+    /// Connects to a full node, announcing no services since we serve no blocks.
+    pub fn connect(peer: &str) -> Result<Self, Box<dyn Error>> {
+        let local_version = VersionMessage {
+            version: crate::rpc::protocol_version(),
+            services: 0,
+            best_height: 0,
+            genesis_hash: None,
+            user_agent: USER_AGENT.to_string(),
+            addr_from: String::new(),
+        };
+
+        let mut conn = Connection::open(peer)?;
+        // We have no identity of our own to announce, any key will do
+        conn.encrypt(&SecretKey::new(&mut rand::thread_rng()), None)?;
+        handshake(&mut conn, &local_version)?.check_encryption(conn.is_encrypted())?;
+
+        Ok(Self {
+            peer: peer.to_owned(),
+            headers: vec![],
+            heights: HashMap::new(),
+            conn,
+        })
+    }
+
+    pub fn height(&self) -> u32 {
+        self.headers.len() as u32
+    }
+
+    /// Downloads the headers we miss and returns the new height. If the peer
+    /// switched to another chain, its whole header chain is synced again.
+    pub fn sync_headers(&mut self) -> Result<u32, Box<dyn Error>> {
+        let headers = self.get_headers(self.height())?;
+        let tip = self.headers.last().map(|h| h.hash.as_str());
+
+        match headers.first() {
+            None => return Ok(self.height()),
+            Some(first) if Some(first.prev_block.as_str()) == tip || tip.is_none() => {
+                let mut chain = std::mem::take(&mut self.headers);
+                chain.extend(headers);
+                self.set_headers(chain)?;
+            }
+            Some(_) => {
+                let chain = self.get_headers(0)?;
+                if chain.len() <= self.headers.len() {
+                    return Err("Header sync failed: Peer's chain isn't longer".into());
+                }
+                self.set_headers(chain)?;
+            }
+        }
+
+        Ok(self.height())
+    }
+
+    fn get_headers(&mut self, start_height: u32) -> Result<Vec<BlockHeader>, Box<dyn Error>> {
+        let mut headers: Vec<BlockHeader> = vec![];
+        loop {
+            let msg = format!(
+                "{}({})",
+                MESSAGE_GET_HEADERS,
+                start_height + headers.len() as u32
+            );
+            let batch: Vec<BlockHeader> =
+                serde_json::from_str(&connection::parse_response(self.conn.request(&msg)?)?)?;
+            let done = batch.len() < MAX_HEADERS as usize;
+            headers.extend(batch);
+
+            if done {
+                return Ok(headers);
+            }
+        }
+    }
+
+    /// Replaces our header chain after checking every header, keeping the old
+    /// one if any is invalid.
+    fn set_headers(&mut self, headers: Vec<BlockHeader>) -> Result<(), String> {
+        let mut prev_block = GENESIS_PREV_BLOCK_HASH;
+        for header in &headers {
+            header.verify()?;
+            if header.prev_block != prev_block {
+                return Err("Header verification failed: Previous block hash mismatch".to_string());
+            }
+            prev_block = &header.hash;
+        }
+
+        self.heights = headers
+            .iter()
+            .enumerate()
+            .map(|(i, h)| (h.hash.clone(), i as u32 + 1))
+            .collect();
+        self.headers = headers;
+
+        Ok(())
+    }
+
+    /// Asks the peer for a proof that the transaction is in its chain.
+    pub fn get_tx_proof(&mut self, tx_id: &str) -> Result<Option<TxOutProof>, Box<dyn Error>> {
+        let msg = format!("{}({})", MESSAGE_GET_TX_PROOF, tx_id);
+        let proof = connection::parse_response(self.conn.request(&msg)?)?;
+
+        Ok(serde_json::from_str(&proof)?)
+    }
+
+    /// Checks the proof against our headers, returning the height of the block
+    /// including the transaction.
+    pub fn verify_tx_proof(&self, proof: &TxOutProof) -> Result<u32, String> {
+        proof.verify()?;
+
+        let height = *self
+            .heights
+            .get(&proof.header.hash)
+            .ok_or("Proof verification failed: Block isn't in the header chain")?;
+        if self.headers[height as usize - 1] != proof.header {
+            return Err("Proof verification failed: Header mismatch".to_string());
+        }
+
+        Ok(height)
+    }
+
+    /// Number of confirmations of a transaction, `None` if the peer can't prove
+    /// it is in the chain.
+    pub fn confirmations(&mut self, tx_id: &str) -> Result<Option<u32>, Box<dyn Error>> {
+        let proof = match self.get_tx_proof(tx_id)? {
+            Some(proof) => proof,
+            None => return Ok(None),
+        };
+        if proof.proof.tx_id != tx_id {
+            return Err("Proof verification failed: Transaction mismatch".into());
+        }
+
+        let height = self.verify_tx_proof(&proof)?;
+        Ok(Some(self.height() - height + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::simulator::Network;
+
+    #[test]
+    fn test_light_client_verifies_inclusion() {
+        let network = Network::new(2);
+        network.mine(0).unwrap();
+        network.connect_all().unwrap();
+        network
+            .wait_for_convergence(Duration::from_secs(10))
+            .unwrap();
+
+        let tx = network.send(0, 1, 100).unwrap();
+        network.mine(0).unwrap();
+        network
+            .wait_for_convergence(Duration::from_secs(10))
+            .unwrap();
+
+        let mut client = LightClient::connect(&network.node(1).addr).unwrap();
+        assert_eq!(client.sync_headers().unwrap(), 2);
+        assert_eq!(client.confirmations(&tx.tx_id()).unwrap(), Some(1));
+        assert_eq!(client.confirmations("unknown").unwrap(), None);
+
+        network.mine(1).unwrap();
+        assert_eq!(client.sync_headers().unwrap(), 3);
+        assert_eq!(client.confirmations(&tx.tx_id()).unwrap(), Some(2));
+
+        // A proof for a transaction the block doesn't include fails
+        let mut proof = client.get_tx_proof(&tx.tx_id()).unwrap().unwrap();
+        proof.proof.tx_id = "forged".to_string();
+        assert!(client.verify_tx_proof(&proof).is_err());
+
+        // So does a valid proof of a block that isn't in our header chain
+        let other = Network::new(1);
+        let block = other.mine(0).unwrap();
+        let coinbase = block.transactions[0].tx_id();
+        let proof = TxOutProof::new(&block, &coinbase).unwrap();
+        assert_eq!(proof.verify(), Ok(coinbase.as_str()));
+        assert!(client.verify_tx_proof(&proof).is_err());
+    }
+}
@@ -1,53 +1,80 @@
+pub mod compact;
+pub mod connection;
+pub mod inventory;
+pub mod light;
 pub mod server;
+pub mod transport;
+pub mod version;
 
-use std::io::{Read, Write};
-use std::net::TcpStream;
 use std::sync::{mpsc, Arc, Mutex};
-use std::thread;
 use std::time::{Duration, Instant};
 
-use colored::Colorize;
+use log::{info, warn};
 
 use crate::block::Block;
+use crate::metrics::METRICS;
 use crate::node::Node;
-use crate::storage;
+use crate::shutdown::Shutdown;
 use crate::tx::SignedTransaction;
 
+use self::compact::CompactBlock;
+use self::connection::Connection;
+use self::inventory::Inventory;
 use self::server::{P2pData, P2pServer};
+use self::version::{VersionMessage, NODE_COMPACT_BLOCKS};
 
 pub type ResultUnit = core::result::Result<(), Box<dyn std::error::Error>>;
 
+/// Opens the encrypted transport, see `transport`
+const MESSAGE_ENCRYPT: &str = "ENCRYPT";
+const MESSAGE_VERSION: &str = "VERSION";
+const MESSAGE_VERACK: &str = "VERACK";
+
 const MESSAGE_NEW_PEER: &str = "NEW_PEER";
 const MESSAGE_PING: &str = "PING";
 
 const MESSAGE_GET_BLOCK: &str = "GET_BLOCK";
 const MESSAGE_GET_BLOCKS: &str = "GET_BLOCKS";
+const MESSAGE_GET_HEADERS: &str = "GET_HEADERS";
+const MESSAGE_GET_TX_PROOF: &str = "GET_TX_PROOF";
+
+/// Most headers sent in response to a single GET_HEADERS
+pub const MAX_HEADERS: u32 = 2_000;
+
+const MESSAGE_INV: &str = "INV";
+const MESSAGE_GETDATA: &str = "GETDATA";
 
 const MESSAGE_NEW_BLOCK: &str = "NEW_BLOCK";
+const MESSAGE_CMPCT_BLOCK: &str = "CMPCT_BLOCK";
+const MESSAGE_GET_BLOCK_TXN: &str = "GET_BLOCK_TXN";
 const MESSAGE_NEW_TRANSACTION: &str = "NEW_TRANSACTION";
 
+const MESSAGE_ERROR: &str = "ERROR";
+
 pub fn run(
     node: Arc<Mutex<Node>>,
     data: Arc<Mutex<P2pData>>,
     host_addr: impl Into<String>,
     miner_interrupt_tx: mpsc::Sender<()>,
-    data_dir: &str,
+    shutdown: Shutdown,
 ) -> ResultUnit {
     let mut server = P2pServer::new(node, data, host_addr, miner_interrupt_tx);
 
-    server.serve(data_dir)?;
+    server.serve(&shutdown)?;
 
     Ok(())
 }
 
+/// Relays the node's new blocks and transactions until `shutdown` is requested
 pub fn run_receiver(
     p2p_data: Arc<Mutex<P2pData>>,
     block_rx: mpsc::Receiver<Block>,
     transaction_rx: mpsc::Receiver<SignedTransaction>,
+    shutdown: Shutdown,
 ) -> ResultUnit {
     let mut now = Instant::now();
 
-    loop {
+    while !shutdown.wait_timeout(Duration::from_millis(100)) {
         // TODO: keep trying to reconnect to bootstrap nodes if they go offline
         if now.elapsed().as_secs() > 60 {
             check_and_update_peers(p2p_data.clone())?;
@@ -61,140 +88,289 @@ pub fn run_receiver(
         if let Ok(tx) = transaction_rx.try_recv() {
             publish_transaction(p2p_data.clone(), tx)?;
         }
-
-        thread::sleep(Duration::from_millis(100));
     }
+
+    Ok(())
 }
 
 fn publish_transaction(p2p_data: Arc<Mutex<P2pData>>, tx: SignedTransaction) -> ResultUnit {
-    publish(
-        p2p_data,
-        MESSAGE_NEW_TRANSACTION,
-        serde_json::to_string(&tx)?,
-    )
+    publish(p2p_data, Inventory::transaction(tx.tx_id()))
 }
 
-fn publish_block(p2p_data: Arc<Mutex<P2pData>>, block: Block) -> ResultUnit {
-    publish(p2p_data, MESSAGE_NEW_BLOCK, serde_json::to_string(&block)?)
+/// Sends new blocks as compact blocks to the peers supporting them and
+/// announces them to the others.
+pub fn publish_block(p2p_data: Arc<Mutex<P2pData>>, block: Block) -> ResultUnit {
+    let inventory = Inventory::block(&block.hash);
+    let peers = unaware_peers(&p2p_data, &inventory);
+
+    let compact = serde_json::to_string(&CompactBlock::new(&block))?;
+    let inv = serde_json::to_string(&[&inventory])?;
+    for (peer, services) in &peers {
+        let (message, data) = if services & NODE_COMPACT_BLOCKS != 0 {
+            (MESSAGE_CMPCT_BLOCK, &compact)
+        } else {
+            (MESSAGE_INV, &inv)
+        };
+        if let Err(e) = send_message(&p2p_data, peer, message.to_owned(), Some(data.clone())) {
+            warn!(
+                "Failed to publish block={} peer={peer} error={e:?}",
+                block.hash
+            );
+        }
+    }
+
+    Ok(())
 }
 
-pub fn publish(p2p_data: Arc<Mutex<P2pData>>, req: &str, data: String) -> ResultUnit {
-    let p2p_data = p2p_data.lock().unwrap();
-    for peer in &p2p_data.peers {
-        if let Err(e) = send_message(peer, req.to_owned(), Some(data.clone())) {
-            println!(
-                "{}",
-                format!(
-                    "Failed to publish {} to {}, happened error: {e:?}",
-                    data, peer
-                )
-                .red()
-            );
+/// Announces inventory to every peer that completed the version handshake and
+/// doesn't have it yet, peers then fetch what they miss with GETDATA.
+pub fn publish(p2p_data: Arc<Mutex<P2pData>>, inventory: Inventory) -> ResultUnit {
+    let peers = unaware_peers(&p2p_data, &inventory);
+
+    let data = serde_json::to_string(&[&inventory])?;
+    for (peer, _) in &peers {
+        if let Err(e) = send_message(&p2p_data, peer, MESSAGE_INV.to_owned(), Some(data.clone())) {
+            warn!("Failed to publish inventory={data} peer={peer} error={e:?}");
         }
     }
 
     Ok(())
 }
 
+/// Peers that completed the version handshake and don't have `inventory` yet,
+/// with the services they announced. They are marked as having it.
+fn unaware_peers(p2p_data: &Arc<Mutex<P2pData>>, inventory: &Inventory) -> Vec<(String, u64)> {
+    let mut p2p_data = p2p_data.lock().unwrap();
+    let peers: Vec<_> = p2p_data
+        .peers
+        .iter()
+        .filter(|peer| !p2p_data.is_known(peer, inventory))
+        .filter_map(|peer| {
+            let version = p2p_data.versions.get(peer)?;
+            Some((peer.clone(), version.services))
+        })
+        .collect();
+
+    peers
+        .iter()
+        .for_each(|(peer, _)| p2p_data.mark_known(peer, inventory.clone()));
+    peers
+}
+
 pub fn add_peer(
     node: Arc<Mutex<Node>>,
     data: Arc<Mutex<P2pData>>,
     miner_interrupt_tx: mpsc::Sender<()>,
     remote_peer: &str,
-    data_dir: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut data = data.lock().unwrap();
-    if !data.peers.iter().any(|x| x == remote_peer) {
-        println!("{} {} added", "New Peer:".green(), remote_peer);
+    if data.lock().unwrap().peers.iter().any(|x| x == remote_peer) {
+        return Ok(());
+    }
+
+    // Only peers that complete the handshake are added
+    connect(&data, remote_peer)?;
+
+    {
+        // Release the lock before syncing, sending messages needs the connection pool
+        let mut data = data.lock().unwrap();
+        if data.peers.iter().any(|x| x == remote_peer) {
+            return Ok(());
+        }
+
+        info!("New peer addr={remote_peer}");
         data.peers.push(remote_peer.to_owned());
-        check_peer_blocks(node, miner_interrupt_tx, remote_peer, data_dir)?;
     }
 
-    Ok(())
+    check_peer_blocks(node, &data, miner_interrupt_tx, remote_peer)
 }
 
 pub fn check_and_update_peers(data: Arc<Mutex<P2pData>>) -> ResultUnit {
+    let peers = data.lock().unwrap().peers.clone();
+    let disconnected: Vec<_> = peers
+        .into_iter()
+        .filter(|peer| {
+            if let Err(e) = send_message(&data, peer, MESSAGE_PING.to_string(), None) {
+                warn!("Disconnected from peer addr={peer} error={e:?}");
+                true
+            } else {
+                false
+            }
+        })
+        .collect();
+
     let mut data = data.lock().unwrap();
-    data.peers.retain(|peer| {
-        if let Err(e) = send_message(peer, MESSAGE_PING.to_string(), None) {
-            println!("Disconnected from peer: {} - {:?}", peer, e);
-            false
-        } else {
-            true
-        }
-    });
+    disconnected.iter().for_each(|peer| data.remove_peer(peer));
 
     Ok(())
 }
 
+/// Disconnects from the peer and refuses any further connection from or to it.
+pub fn ban_peer(data: &Arc<Mutex<P2pData>>, peer: &str) {
+    let mut data = data.lock().unwrap();
+    data.remove_peer(peer);
+    data.banned.insert(peer.to_owned());
+}
+
+pub fn unban_peer(data: &Arc<Mutex<P2pData>>, peer: &str) {
+    data.lock().unwrap().banned.remove(peer);
+}
+
+/// Returns the pooled connection to `addr`, opening one and running the version
+/// handshake if there is none yet.
+pub fn connect(
+    p2p_data: &Arc<Mutex<P2pData>>,
+    addr: &str,
+) -> Result<Arc<Mutex<Connection>>, Box<dyn std::error::Error>> {
+    {
+        let p2p_data = p2p_data.lock().unwrap();
+        if p2p_data.is_banned(addr) {
+            return Err(format!("Peer {addr} is banned").into());
+        }
+        if let Some(conn) = p2p_data.connections.get(addr) {
+            return Ok(conn.clone());
+        }
+    }
+
+    let (local_version, encryption, identity, pinned) = {
+        let p2p_data = p2p_data.lock().unwrap();
+        (
+            VersionMessage::local(&p2p_data.host_addr, p2p_data.store.as_ref())?,
+            p2p_data.encryption,
+            p2p_data.identity,
+            p2p_data.peer_keys.get(addr).copied(),
+        )
+    };
+
+    let mut conn = Connection::open(addr)?;
+    let peer_key = if encryption || pinned.is_some() {
+        conn.encrypt(&identity, pinned.as_ref())?
+    } else {
+        None
+    };
+    if encryption && peer_key.is_none() {
+        info!("Peer doesn't support encryption addr={addr}");
+    }
+    let remote_version = handshake(&mut conn, &local_version)?;
+    if encryption {
+        remote_version.check_encryption(conn.is_encrypted())?;
+    }
+
+    let conn = Arc::new(Mutex::new(conn));
+    let mut p2p_data = p2p_data.lock().unwrap();
+    p2p_data.connections.insert(addr.to_owned(), conn.clone());
+    p2p_data.versions.insert(addr.to_owned(), remote_version);
+    if let Some(key) = peer_key {
+        p2p_data.peer_keys.entry(addr.to_owned()).or_insert(key);
+    }
+
+    Ok(conn)
+}
+
+/// Exchanges VERSION messages and acknowledges the peer's one with VERACK.
+pub(crate) fn handshake(
+    conn: &mut Connection,
+    local_version: &VersionMessage,
+) -> Result<VersionMessage, Box<dyn std::error::Error>> {
+    let resp = conn.request(&format!(
+        "{}({})",
+        MESSAGE_VERSION,
+        serde_json::to_string(local_version)?
+    ))?;
+    let remote_version: VersionMessage = serde_json::from_str(&connection::parse_response(resp)?)?;
+    local_version.check_compatible(&remote_version)?;
+
+    connection::parse_response(conn.request(MESSAGE_VERACK)?)?;
+
+    Ok(remote_version)
+}
+
+/// Sends a message over the pooled connection to `addr`, opening one if needed.
+///
+/// A pooled connection may have been dropped by the peer while idle, so a failed
+/// request on a reused connection is retried once on a fresh one.
 pub fn send_message(
+    p2p_data: &Arc<Mutex<P2pData>>,
     addr: &str,
     message: String,
     data: Option<String>,
 ) -> Result<String, Box<dyn std::error::Error>> {
-    let mut stream = TcpStream::connect(addr)?;
-
     let msg = data
         .map(|d| format!("{}({})", message, d))
         .unwrap_or(message);
 
-    stream.write_all(msg.as_bytes())?;
-
-    let mut buffer = [0; 100000];
-    stream.read_exact(&mut buffer)?;
-
-    let resp = String::from_utf8_lossy(&buffer[..]);
-    let resp_parts: Vec<_> = resp.split("\r\n").collect();
-    let resp = resp_parts[0].to_string();
+    let mut reused = p2p_data.lock().unwrap().connections.contains_key(addr);
+    let resp = loop {
+        let result = connect(p2p_data, addr)?.lock().unwrap().request(&msg);
+        match result {
+            Ok(resp) => break resp,
+            Err(e) => {
+                p2p_data.lock().unwrap().connections.remove(addr);
+                if !reused {
+                    return Err(e.into());
+                }
+                reused = false;
+            }
+        }
+    };
 
-    Ok(resp)
+    Ok(connection::parse_response(resp)?)
 }
 
+/// Syncs with the peer's chain when it is longer than ours, reorganizing onto
+/// it if the chains forked.
 pub fn check_peer_blocks(
     node: Arc<Mutex<Node>>,
+    p2p_data: &Arc<Mutex<P2pData>>,
     miner_interrupt_tx: mpsc::Sender<()>,
     peer: &str,
-    data_dir: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Get blocks from remote peer
-    let blocks_resp = send_message(peer, MESSAGE_GET_BLOCKS.to_string(), None)?;
-    let block_hashes: Vec<String> = serde_json::from_str(&blocks_resp)?;
-
-    // Get latest block hash from storage
-    let missing_hashes =
-        match storage::get_latest_block_hash(&storage::db::blocks_metadata(true, data_dir))? {
-            Some(latest_block_hash) => {
-                let position = block_hashes.iter().position(|b| *b == latest_block_hash);
-                match position {
-                    Some(position) => block_hashes[position + 1..].to_vec(),
-                    None => vec![],
-                }
-            }
-            None => block_hashes,
-        };
-
-    for block_hash in missing_hashes {
-        let block = send_message(peer, MESSAGE_GET_BLOCK.to_owned(), Some(block_hash))?;
-        let block: Block = serde_json::from_str(&block)?;
-        let mut node = node.lock().unwrap();
-        node.process_block(&block)?;
-        miner_interrupt_tx.send(())?;
+    let block_hashes: Vec<String> = serde_json::from_str(&send_message(
+        p2p_data,
+        peer,
+        MESSAGE_GET_BLOCKS.to_string(),
+        None,
+    )?)?;
+    let local_hashes = p2p_data.lock().unwrap().store.get_block_hashes()?;
+
+    // Longest chain wins, ties keep our own
+    if block_hashes.len() <= local_hashes.len() {
+        return Ok(());
     }
 
-    Ok(())
-}
+    let fork_point = local_hashes
+        .iter()
+        .zip(&block_hashes)
+        .take_while(|(local, remote)| local == remote)
+        .count();
 
-pub fn check_peers(data: Arc<Mutex<P2pData>>) -> ResultUnit {
-    let mut data = data.lock().unwrap();
+    let mut blocks = vec![];
+    for block_hash in &block_hashes[fork_point..] {
+        let block = send_message(
+            p2p_data,
+            peer,
+            MESSAGE_GET_BLOCK.to_owned(),
+            Some(block_hash.clone()),
+        )?;
+        let block: Option<Block> = serde_json::from_str(&block)?;
+        blocks.push(block.ok_or_else(|| format!("Peer is missing block {block_hash}"))?);
+    }
 
-    data.peers.retain(|peer| {
-        if let Err(e) = send_message(peer, MESSAGE_PING.to_string(), None) {
-            println!("Disconnected from peer: {} - {:?}", peer, e);
-            false
+    METRICS.blocks_received(blocks.len() as u64);
+    {
+        let mut node = node.lock().unwrap();
+        node.mark_assumed_valid(&blocks);
+        if fork_point < local_hashes.len() {
+            node.reorganize(fork_point as u32, &blocks)?;
         } else {
-            true
+            for block in &blocks {
+                node.process_block(block)?;
+            }
         }
-    });
+    }
+
+    // The miner isn't running when it is disabled
+    let _ = miner_interrupt_tx.send(());
 
     Ok(())
 }
@@ -205,7 +381,6 @@ pub fn init(
     miner_interrupt_tx: mpsc::Sender<()>,
     host_addr: &str,
     bootstrap_nodes: Vec<String>,
-    data_dir: &str,
 ) -> ResultUnit {
     bootstrap_nodes.iter().for_each(|peer| {
         if let Err(e) = init_node(
@@ -214,9 +389,8 @@ pub fn init(
             miner_interrupt_tx.clone(),
             peer,
             host_addr,
-            data_dir,
         ) {
-            println!("Failed to add peer: {e}");
+            warn!("Failed to add peer addr={peer} error={e}");
         }
     });
 
@@ -229,17 +403,16 @@ pub fn init_node(
     miner_interrupt_tx: mpsc::Sender<()>,
     remote_peer: &str,
     host_addr: &str,
-    data_dir: &str,
 ) -> ResultUnit {
     add_peer(
         node.clone(),
         data.clone(),
         miner_interrupt_tx.clone(),
         remote_peer,
-        data_dir,
     )?;
 
     let resp = send_message(
+        &data,
         remote_peer,
         MESSAGE_NEW_PEER.to_string(),
         Some(host_addr.to_string()),
@@ -252,9 +425,9 @@ pub fn init_node(
             data.clone(),
             miner_interrupt_tx.clone(),
             &peer,
-            data_dir,
         )?;
         send_message(
+            &data,
             remote_peer,
             MESSAGE_NEW_PEER.to_string(),
             Some(host_addr.to_string()),
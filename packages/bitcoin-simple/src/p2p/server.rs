@@ -1,33 +1,157 @@
-use std::io::{Read, Write};
+use std::collections::{HashMap, HashSet};
+use std::io::BufReader;
 use std::{
-    net::{TcpListener, TcpStream},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
     sync::{mpsc, Arc, Mutex},
+    thread,
 };
 
-use colored::Colorize;
+use log::{info, warn};
 use regex::Regex;
+use secp256k1::{rand, PublicKey, SecretKey};
 
+use crate::block::merkle::TxOutProof;
 use crate::block::Block;
-use crate::node::Node;
-use crate::storage;
+use crate::metrics::METRICS;
+use crate::node::{Node, MAX_ORPHANS};
+use crate::shutdown::Shutdown;
+use crate::storage::SharedStore;
 use crate::tx::SignedTransaction;
 
+use super::compact::{BlockTxnRequest, CompactBlock};
+use super::connection::{self, Connection, ConnectionLimit, IDLE_TIMEOUT, MAX_INBOUND_CONNECTIONS};
+use super::inventory::{Inventory, InventoryItem, InventoryKind, KnownInventory};
+use super::transport;
+use super::version::VersionMessage;
 use super::{
-    add_peer, ResultUnit, MESSAGE_GET_BLOCK, MESSAGE_GET_BLOCKS, MESSAGE_NEW_BLOCK,
-    MESSAGE_NEW_PEER, MESSAGE_NEW_TRANSACTION, MESSAGE_PING,
+    add_peer, send_message, ResultUnit, MAX_HEADERS, MESSAGE_CMPCT_BLOCK, MESSAGE_ENCRYPT,
+    MESSAGE_GETDATA, MESSAGE_GET_BLOCK, MESSAGE_GET_BLOCKS, MESSAGE_GET_BLOCK_TXN,
+    MESSAGE_GET_HEADERS, MESSAGE_GET_TX_PROOF, MESSAGE_INV, MESSAGE_NEW_BLOCK, MESSAGE_NEW_PEER,
+    MESSAGE_NEW_TRANSACTION, MESSAGE_PING, MESSAGE_VERACK, MESSAGE_VERSION,
 };
 
-#[derive(Debug, Clone, Default)]
+#[derive(Clone)]
 pub struct P2pData {
+    pub host_addr: String,
+    pub store: SharedStore,
     pub peers: Vec<String>,
+    /// Outbound connections kept open per peer and reused across messages
+    pub connections: HashMap<String, Arc<Mutex<Connection>>>,
+    /// Version announced by each peer we completed the handshake with
+    pub versions: HashMap<String, VersionMessage>,
+    /// Blocks and transactions each peer already has, so they aren't announced twice
+    pub known_inventory: HashMap<String, KnownInventory>,
+    /// Peers we refuse to talk to in either direction
+    pub banned: HashSet<String>,
+    /// Whether outbound connections try the encrypted transport first
+    pub encryption: bool,
+    /// Secret of the identity key we announce in the encrypted transport
+    pub identity: SecretKey,
+    /// Identity keys the peers we connect to must announce, configured or
+    /// learned on the first encrypted connection
+    pub peer_keys: HashMap<String, PublicKey>,
+}
+
+impl P2pData {
+    pub fn new(host_addr: impl Into<String>, store: SharedStore) -> Self {
+        Self {
+            host_addr: host_addr.into(),
+            store,
+            peers: Vec::new(),
+            connections: HashMap::new(),
+            versions: HashMap::new(),
+            known_inventory: HashMap::new(),
+            banned: HashSet::new(),
+            encryption: true,
+            identity: SecretKey::new(&mut rand::thread_rng()),
+            peer_keys: HashMap::new(),
+        }
+    }
+
+    /// Forgets everything about the peer and closes our connection to it.
+    pub fn remove_peer(&mut self, peer: &str) {
+        self.peers.retain(|p| p != peer);
+        self.connections.remove(peer);
+        self.versions.remove(peer);
+        self.known_inventory.remove(peer);
+    }
+
+    pub fn is_known(&self, peer: &str, inventory: &Inventory) -> bool {
+        self.known_inventory
+            .get(peer)
+            .is_some_and(|known| known.contains(inventory))
+    }
+
+    pub fn mark_known(&mut self, peer: &str, inventory: Inventory) {
+        self.known_inventory
+            .entry(peer.to_owned())
+            .or_default()
+            .insert(inventory);
+    }
+
+    /// Whether `addr` or its host is banned
+    pub fn is_banned(&self, addr: &str) -> bool {
+        self.banned.contains(addr) || self.banned.contains(host(addr))
+    }
+}
+
+/// Host part of a `host:port` address
+fn host(addr: &str) -> &str {
+    addr.rsplit_once(':')
+        .map_or(addr, |(host, _)| host)
+        .trim_matches(['[', ']'])
+}
+
+/// Whether the `announced` listening address resolves to the host of the
+/// socket address `addr`
+fn is_on_host(announced: &str, addr: &str) -> bool {
+    let Ok(addr) = addr.parse::<SocketAddr>() else {
+        return false;
+    };
+    announced
+        .to_socket_addrs()
+        .is_ok_and(|mut resolved| resolved.any(|a| a.ip() == addr.ip()))
+}
+
+/// Handshake progress of an inbound connection
+#[derive(Debug, Default)]
+pub struct ConnectionState {
+    /// Address the connection comes from
+    pub addr: String,
+    pub version: Option<VersionMessage>,
+    pub verack: bool,
+}
+
+impl ConnectionState {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Listening address of the peer, as announced in its VERSION message and
+    /// checked to be on the connecting host. Blocks and transactions are only
+    /// requested from it, and tracked as known to it, over an outbound
+    /// connection that completed its own handshake.
+    pub fn peer(&self) -> Result<&str, String> {
+        match self.version.as_ref().map(|v| v.addr_from.as_str()) {
+            None => Err("Handshake required".to_string()),
+            Some("") => Err("Peer announced no listening address".to_string()),
+            Some(peer) => Ok(peer),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct P2pServer {
     pub node: Arc<Mutex<Node>>,
     pub data: Arc<Mutex<P2pData>>,
+    pub store: SharedStore,
     pub host_addr: String,
     pub miner_interrupt_tx: mpsc::Sender<()>,
+    /// Inbound connections being served, see `MAX_INBOUND_CONNECTIONS`
+    pub inbound: ConnectionLimit,
 }
 
 impl P2pServer {
@@ -37,134 +161,514 @@ impl P2pServer {
         host_addr: impl Into<String>,
         miner_interrupt_tx: mpsc::Sender<()>,
     ) -> Self {
+        let store = node.lock().unwrap().store.clone();
         Self {
             node,
             data,
+            store,
             host_addr: host_addr.into(),
             miner_interrupt_tx,
+            inbound: ConnectionLimit::new(MAX_INBOUND_CONNECTIONS),
         }
     }
 
-    pub fn serve(&mut self, data_dir: &str) -> ResultUnit {
+    /// Accepts connections until `shutdown` is requested
+    pub fn serve(&mut self, shutdown: &Shutdown) -> ResultUnit {
         let listener = TcpListener::bind(self.host_addr.as_str())?;
 
-        println!(
-            "{:?} Listening on {:?}",
-            "P2p".green(),
-            listener.local_addr()?.to_string()
-        );
+        info!("P2P listening addr={}", listener.local_addr()?);
+        shutdown.wake_listener(listener.local_addr()?);
 
         for stream in listener.incoming() {
-            self.handle_connection(stream?, data_dir)?;
+            if shutdown.is_requested() {
+                info!("P2P listener closed");
+                break;
+            }
+
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to accept connection error={e}");
+                    continue;
+                }
+            };
+
+            let peer_addr = stream
+                .peer_addr()
+                .map(|a| a.to_string())
+                .unwrap_or_default();
+            let Some(slot) = self.inbound.acquire() else {
+                warn!("Too many inbound connections, refused addr={peer_addr}");
+                continue;
+            };
+
+            // Each connection gets its own thread so a slow or failing peer
+            // can't block or bring down the listener
+            let mut server = self.clone();
+            thread::spawn(move || {
+                let _slot = slot;
+                if let Err(e) = server.handle_connection(stream) {
+                    warn!("Connection closed addr={peer_addr} error={e}");
+                }
+            });
         }
 
         Ok(())
     }
 
-    pub fn handle_connection(&mut self, mut stream: TcpStream, data_dir: &str) -> ResultUnit {
-        let mut buffer = [0; 100000];
-
-        stream.read_exact(&mut buffer)?;
+    pub fn handle_connection(&mut self, stream: TcpStream) -> ResultUnit {
+        stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
 
-        let msg = String::from_utf8_lossy(&buffer[..]);
-        let msg = msg.as_ref();
+        let mut state = ConnectionState::new(stream.peer_addr()?.to_string());
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        let mut session = None;
 
-        let response = self.response(msg, data_dir)?;
+        while let Some(msg) = connection::receive(&mut reader, &mut session)? {
+            // Encryption can only be requested first, plaintext peers never do
+            if msg.starts_with(MESSAGE_ENCRYPT) && session.is_none() && state.version.is_none() {
+                let identity = self.data.lock().unwrap().identity;
+                match payload(&msg, MESSAGE_ENCRYPT)
+                    .and_then(|keys| transport::accept(keys, &identity))
+                {
+                    Ok((keys, _, new_session)) => {
+                        connection::write_message(&mut writer, &keys)?;
+                        session = Some(new_session);
+                    }
+                    Err(e) => {
+                        connection::write_message(&mut writer, &connection::error_response(&e))?
+                    }
+                }
+                continue;
+            }
 
-        let final_resp = format!("{}\r\n", response);
+            let response = self
+                .response(&msg, &mut state)
+                .unwrap_or_else(|e| connection::error_response(&e));
 
-        stream.write_all(final_resp.as_bytes())?;
-        stream.flush()?;
+            connection::send(&mut writer, &mut session, &response)?;
+        }
 
         Ok(())
     }
 
-    fn response(&mut self, msg: &str, data_dir: &str) -> Result<String, String> {
-        if msg.starts_with(MESSAGE_PING) {
+    fn response(&mut self, msg: &str, state: &mut ConnectionState) -> Result<String, String> {
+        if msg.starts_with(MESSAGE_VERSION) {
+            let version = payload(msg, MESSAGE_VERSION)?;
+            self.handle_version(version, state)
+        } else if msg.starts_with(MESSAGE_VERACK) {
+            if state.version.is_none() {
+                return Err("VERACK before VERSION".to_string());
+            }
+            state.verack = true;
+            Ok(String::from("OK"))
+        } else if !state.verack {
+            Err("Handshake required".to_string())
+        } else if self.is_banned(state) {
+            Err("Peer is banned".to_string())
+        } else if msg.starts_with(MESSAGE_PING) {
             Ok(String::from("OK"))
         } else if msg.starts_with(MESSAGE_GET_BLOCKS) {
-            self.handle_get_blocks(data_dir)
+            self.handle_get_blocks()
+        } else if msg.starts_with(MESSAGE_GET_BLOCK_TXN) {
+            let request = payload(msg, MESSAGE_GET_BLOCK_TXN)?;
+            self.handle_get_block_txn(request)
         } else if msg.starts_with(MESSAGE_GET_BLOCK) {
-            let regex = Regex::new(&format!(r"{}\((?P<hash>.*?)\)", MESSAGE_GET_BLOCK))
-                .map_err(|e| e.to_string())?;
-            let caps = regex.captures(msg).unwrap();
-            let block = &caps["hash"];
-            self.handle_get_block(block, data_dir)
+            let block = payload(msg, MESSAGE_GET_BLOCK)?;
+            self.handle_get_block(block)
+        } else if msg.starts_with(MESSAGE_GET_HEADERS) {
+            let start_height = payload(msg, MESSAGE_GET_HEADERS)?;
+            self.handle_get_headers(start_height)
+        } else if msg.starts_with(MESSAGE_GET_TX_PROOF) {
+            let tx_id = payload(msg, MESSAGE_GET_TX_PROOF)?;
+            self.handle_get_tx_proof(tx_id)
+        } else if msg.starts_with(MESSAGE_INV) {
+            let inventory = payload(msg, MESSAGE_INV)?;
+            self.handle_inv(inventory, state)
+        } else if msg.starts_with(MESSAGE_GETDATA) {
+            let inventory = payload(msg, MESSAGE_GETDATA)?;
+            self.handle_getdata(inventory, state)
+        } else if msg.starts_with(MESSAGE_CMPCT_BLOCK) {
+            let compact = payload(msg, MESSAGE_CMPCT_BLOCK)?;
+            self.handle_compact_block(compact, state)
         } else if msg.starts_with(MESSAGE_NEW_BLOCK) {
-            let regex = Regex::new(&format!(r"{}\((?P<block>.*?)\)", MESSAGE_GET_BLOCK))
-                .map_err(|e| e.to_string())?;
-            let caps = regex.captures(msg).unwrap();
-            let block = &caps["block"];
-            self.handle_get_block(block, data_dir)
+            let block = payload(msg, MESSAGE_NEW_BLOCK)?;
+            self.handle_new_block(block, state)
         } else if msg.starts_with(MESSAGE_NEW_PEER) {
-            let re = Regex::new(&format!(r"{}\((?P<host>.*?)\)", MESSAGE_NEW_PEER))
-                .map_err(|e| e.to_string())?;
-            let caps = re.captures(msg).unwrap();
-            let host = &caps["host"];
-            self.handle_new_peer(host, data_dir)
-                .map_err(|e| e.to_string())
+            let host = payload(msg, MESSAGE_NEW_PEER)?;
+            self.handle_new_peer(host).map_err(|e| e.to_string())
         } else if msg.starts_with(MESSAGE_NEW_TRANSACTION) {
-            let re = Regex::new(&format!(r"{}\((?P<tx>.*?)\)", MESSAGE_NEW_TRANSACTION))
-                .map_err(|e| e.to_string())?;
-            let caps = re.captures(msg).unwrap();
-            let tx = &caps["tx"];
+            let tx = payload(msg, MESSAGE_NEW_TRANSACTION)?;
             self.handle_new_transaction(tx)
         } else {
             Err(String::from("Invalid MESSAGE"))
         }
     }
 
-    pub fn handle_get_blocks(&mut self, data_dir: &str) -> Result<String, String> {
-        let block_hashes =
-            storage::get_block_hashes(&storage::db::blocks_metadata(true, data_dir))?;
+    pub fn handle_version(
+        &mut self,
+        version: &str,
+        state: &mut ConnectionState,
+    ) -> Result<String, String> {
+        let remote_version: VersionMessage =
+            serde_json::from_str(version).map_err(|e| e.to_string())?;
+        let announced = &remote_version.addr_from;
+        // Light clients don't listen
+        if !announced.is_empty() && !is_on_host(announced, &state.addr) {
+            return Err(format!(
+                "Announced address {announced} isn't on the connecting host"
+            ));
+        }
+        let banned = {
+            let data = self.data.lock().unwrap();
+            data.is_banned(&state.addr) || data.is_banned(announced)
+        };
+        if banned {
+            return Err("Peer is banned".to_string());
+        }
+
+        let local_version = VersionMessage::local(&self.host_addr, self.store.as_ref())?;
+        local_version.check_protocol(&remote_version)?;
+
+        info!(
+            "Handshake addr={} user_agent={} height={}",
+            remote_version.addr_from, remote_version.user_agent, remote_version.best_height
+        );
+        state.version = Some(remote_version);
+
+        serde_json::to_string(&local_version).map_err(|e| e.to_string())
+    }
+
+    pub fn handle_get_blocks(&mut self) -> Result<String, String> {
+        let block_hashes = self.store.get_block_hashes()?;
         serde_json::to_string(&block_hashes).map_err(|e| e.to_string())
     }
 
-    pub fn handle_get_block(&mut self, block_hash: &str, data_dir: &str) -> Result<String, String> {
-        let block = storage::get_block(&storage::db::blocks(true, data_dir), block_hash)?;
-        serde_json::to_string_pretty(&block).map_err(|e| e.to_string())
+    pub fn handle_get_block(&mut self, block_hash: &str) -> Result<String, String> {
+        let block = self.store.get_block(block_hash)?;
+        serde_json::to_string(&block).map_err(|e| e.to_string())
+    }
+
+    pub fn handle_get_headers(&mut self, start_height: &str) -> Result<String, String> {
+        let start_height: u32 = start_height.parse().map_err(|_| "Invalid start height")?;
+        let headers = self.store.get_headers(start_height, MAX_HEADERS)?;
+        serde_json::to_string(&headers).map_err(|e| e.to_string())
+    }
+
+    pub fn handle_get_tx_proof(&mut self, tx_id: &str) -> Result<String, String> {
+        let proof = self
+            .store
+            .find_transaction_block(tx_id)?
+            .and_then(|block| TxOutProof::new(&block, tx_id));
+        serde_json::to_string(&proof).map_err(|e| e.to_string())
+    }
+
+    fn is_banned(&self, state: &ConnectionState) -> bool {
+        let data = self.data.lock().unwrap();
+        data.is_banned(&state.addr) || state.peer().is_ok_and(|peer| data.is_banned(peer))
+    }
+
+    /// The peer's listening address once an outbound connection to it
+    /// completed the handshake, connecting first if there is none
+    fn dial_back<'a>(&self, state: &'a ConnectionState) -> Result<&'a str, String> {
+        let peer = state.peer()?;
+        super::connect(&self.data, peer).map_err(|e| e.to_string())?;
+        Ok(peer)
+    }
+
+    /// Records what the announcing peer has and fetches the blocks and
+    /// transactions we are missing from it with GETDATA.
+    pub fn handle_inv(
+        &mut self,
+        inventory: &str,
+        state: &ConnectionState,
+    ) -> Result<String, String> {
+        let inventory: Vec<Inventory> =
+            serde_json::from_str(inventory).map_err(|e| e.to_string())?;
+        let peer = self.dial_back(state)?;
+
+        {
+            let mut data = self.data.lock().unwrap();
+            inventory
+                .iter()
+                .for_each(|inv| data.mark_known(peer, inv.clone()));
+        }
+
+        let mut missing = vec![];
+        for inv in inventory {
+            if !self.has_inventory(&inv)? {
+                missing.push(inv);
+            }
+        }
+
+        if missing.is_empty() {
+            return Ok("OK".to_string());
+        }
+
+        let missing = serde_json::to_string(&missing).map_err(|e| e.to_string())?;
+        let items = send_message(&self.data, peer, MESSAGE_GETDATA.to_string(), Some(missing))
+            .map_err(|e| e.to_string())?;
+        let items: Vec<InventoryItem> = serde_json::from_str(&items).map_err(|e| e.to_string())?;
+
+        for item in items {
+            match item {
+                InventoryItem::Block(block) => self.process_new_block(block, peer)?,
+                InventoryItem::Transaction(tx) => self.process_new_transaction(*tx)?,
+            }
+        }
+
+        Ok("OK".to_string())
+    }
+
+    /// Sends back the requested blocks and transactions we still have.
+    pub fn handle_getdata(
+        &mut self,
+        inventory: &str,
+        state: &ConnectionState,
+    ) -> Result<String, String> {
+        let inventory: Vec<Inventory> =
+            serde_json::from_str(inventory).map_err(|e| e.to_string())?;
+        // Only tracked for peers we connected to ourselves
+        let peer = state
+            .peer()
+            .ok()
+            .filter(|peer| self.data.lock().unwrap().versions.contains_key(*peer));
+
+        let mut items = vec![];
+        for inv in &inventory {
+            let item = match inv.kind {
+                InventoryKind::Block => self.store.get_block(&inv.hash)?.map(InventoryItem::Block),
+                InventoryKind::Transaction => {
+                    let node = self.node.lock().unwrap();
+                    node.mempool
+                        .get(&inv.hash)
+                        .cloned()
+                        .map(|tx| InventoryItem::Transaction(Box::new(tx)))
+                }
+            };
+
+            if let Some(item) = item {
+                if let Some(peer) = peer {
+                    self.data.lock().unwrap().mark_known(peer, item.inventory());
+                }
+                items.push(item);
+            }
+        }
+
+        serde_json::to_string(&items).map_err(|e| e.to_string())
+    }
+
+    fn has_inventory(&self, inv: &Inventory) -> Result<bool, String> {
+        match inv.kind {
+            // Pruned blocks were had too
+            InventoryKind::Block => self.store.get_header(&inv.hash).map(|h| h.is_some()),
+            InventoryKind::Transaction => {
+                Ok(self.node.lock().unwrap().mempool.contains_key(&inv.hash))
+            }
+        }
+    }
+
+    pub fn handle_new_block(
+        &mut self,
+        block: &str,
+        state: &ConnectionState,
+    ) -> Result<String, String> {
+        let block: Block = serde_json::from_str(block).map_err(|e| e.to_string())?;
+        self.process_new_block(block, state.peer()?)?;
+
+        Ok("OK".to_string())
+    }
+
+    /// Rebuilds an announced block from our mempool, fetching the transactions
+    /// we miss from the peer, or the whole block if the short ids collided.
+    pub fn handle_compact_block(
+        &mut self,
+        compact: &str,
+        state: &ConnectionState,
+    ) -> Result<String, String> {
+        let compact: CompactBlock = serde_json::from_str(compact).map_err(|e| e.to_string())?;
+        let peer = self.dial_back(state)?;
+        let inventory = Inventory::block(compact.hash());
+        self.data
+            .lock()
+            .unwrap()
+            .mark_known(peer, inventory.clone());
+        if self.has_inventory(&inventory)? {
+            return Ok("OK".to_string());
+        }
+        compact.header.verify()?;
+
+        let partial = compact.reconstruct(self.node.lock().unwrap().mempool.values())?;
+        let missing = partial.missing();
+        info!(
+            "Compact block hash={} txs={} missing={}",
+            compact.hash(),
+            compact.short_ids.len() + compact.prefilled.len(),
+            missing.len()
+        );
+
+        let txs = if missing.is_empty() {
+            vec![]
+        } else {
+            let request = BlockTxnRequest {
+                block_hash: compact.hash().to_string(),
+                indexes: missing,
+            };
+            let request = serde_json::to_string(&request).map_err(|e| e.to_string())?;
+            let txs = send_message(
+                &self.data,
+                peer,
+                MESSAGE_GET_BLOCK_TXN.to_string(),
+                Some(request),
+            )
+            .map_err(|e| e.to_string())?;
+            serde_json::from_str(&txs).map_err(|e| e.to_string())?
+        };
+
+        match partial.fill(txs) {
+            Ok(block) => self.process_new_block(block, peer)?,
+            Err(e) => {
+                warn!("Requesting full block hash={} error={e}", compact.hash());
+                self.request_block(compact.hash(), peer)?;
+            }
+        }
+
+        Ok("OK".to_string())
+    }
+
+    /// Sends back the transactions of a block a peer missed rebuilding it
+    pub fn handle_get_block_txn(&mut self, request: &str) -> Result<String, String> {
+        let request: BlockTxnRequest = serde_json::from_str(request).map_err(|e| e.to_string())?;
+        let block = self
+            .store
+            .get_block(&request.block_hash)?
+            .ok_or("Unknown block")?;
+
+        let txs = request
+            .indexes
+            .iter()
+            .map(|&index| {
+                block
+                    .transactions
+                    .get(index as usize)
+                    .ok_or("Invalid transaction index")
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        serde_json::to_string(&txs).map_err(|e| e.to_string())
+    }
+
+    pub fn handle_new_transaction(&mut self, tx: &str) -> Result<String, String> {
+        let tx: SignedTransaction = serde_json::from_str(tx).map_err(|e| e.to_string())?;
+        self.process_new_transaction(tx)?;
+
+        Ok("OK".to_string())
     }
 
     // TODO: how to handle fork
-    pub fn handle_new_block(&mut self, block: String, data_dir: &str) -> Result<String, String> {
-        let block: Block = serde_json::from_str(&block).map_err(|e| e.to_string())?;
+    /// Connects a block sent by `peer`. Blocks arriving before their parent
+    /// are pooled as orphans while their ancestors are requested from the
+    /// peer one at a time, as many as the pool holds, the rest are synced.
+    fn process_new_block(&mut self, block: Block, peer: &str) -> Result<(), String> {
+        let mut block = block;
+        for _ in 0..MAX_ORPHANS {
+            let missing_parent = {
+                let mut node = self.node.lock().unwrap();
+                if self.store.get_header(&block.hash)?.is_some()
+                    || node.orphans.contains(&block.hash)
+                {
+                    return Ok(());
+                }
+
+                METRICS.blocks_received(1);
+                info!(
+                    "New block hash={} txs={}",
+                    block.hash,
+                    block.transactions.len()
+                );
+
+                if node.is_orphan(&block)? {
+                    // Only the proof of work can be checked without the parent
+                    block.header().verify()?;
+                    info!(
+                        "Orphan block hash={} parent={}",
+                        block.hash, block.prev_block
+                    );
+                    let parent = block.prev_block.clone();
+                    let full = node.orphans.is_full();
+                    node.orphans.insert(block);
+                    Some((parent, full))
+                } else {
+                    // Received blocks are relayed on to the peers that don't have them yet
+                    node.receive_block(&block)?;
+                    node.connect_orphans(&block.hash);
+                    None
+                }
+            };
+
+            match missing_parent {
+                Some((_, true)) => break,
+                Some((parent, false)) => match self.request_block(&parent, peer)? {
+                    Some(parent) => block = parent,
+                    None => return Ok(()),
+                },
+                // The miner isn't running when it is disabled
+                None => {
+                    let _ = self.miner_interrupt_tx.send(());
+                    return Ok(());
+                }
+            }
+        }
+
+        super::check_peer_blocks(
+            self.node.clone(),
+            &self.data,
+            self.miner_interrupt_tx.clone(),
+            peer,
+        )
+        .map_err(|e| e.to_string())?;
         let mut node = self.node.lock().unwrap();
-        let existing_block = storage::get_block(&storage::db::blocks(true, data_dir), &block.hash)?;
-
-        if existing_block.is_none() {
-            println!(
-                "{} {} - Txs: {}",
-                "New Block".green(),
-                block.hash,
-                block.transactions.len()
-            );
-            node.process_block(&block)?;
-            self.miner_interrupt_tx.send(()).unwrap()
+        if let Some(tip) = node.get_latest_block()? {
+            node.connect_orphans(&tip.hash);
         }
 
-        Ok("Ok".to_string())
+        Ok(())
     }
 
-    pub fn handle_new_transaction(&mut self, tx: &str) -> Result<String, String> {
-        let tx: SignedTransaction = serde_json::from_str(tx).unwrap();
+    /// Fetches a block from the peer with GETDATA, `None` if it doesn't have it
+    fn request_block(&mut self, block_hash: &str, peer: &str) -> Result<Option<Block>, String> {
+        let inventory =
+            serde_json::to_string(&[Inventory::block(block_hash)]).map_err(|e| e.to_string())?;
+        let items = send_message(
+            &self.data,
+            peer,
+            MESSAGE_GETDATA.to_string(),
+            Some(inventory),
+        )
+        .map_err(|e| e.to_string())?;
+        let items: Vec<InventoryItem> = serde_json::from_str(&items).map_err(|e| e.to_string())?;
+
+        Ok(items.into_iter().find_map(|item| match item {
+            InventoryItem::Block(block) if block.hash == block_hash => Some(block),
+            _ => None,
+        }))
+    }
+
+    fn process_new_transaction(&mut self, tx: SignedTransaction) -> Result<(), String> {
         let mut node = self.node.lock().unwrap();
-        // TODO: check tx is duplicate or not
-        node.add_tx_to_mempool(&tx)?;
+        if !node.mempool.contains_key(&tx.tx_id()) {
+            METRICS.tx_received();
+            node.receive_tx(&tx)?;
+        }
 
-        Ok("Ok".to_string())
+        Ok(())
     }
 
-    pub fn handle_new_peer(
-        &mut self,
-        peer: &str,
-        data_dir: &str,
-    ) -> Result<String, Box<dyn std::error::Error>> {
+    pub fn handle_new_peer(&mut self, peer: &str) -> Result<String, Box<dyn std::error::Error>> {
         add_peer(
             self.node.clone(),
             self.data.clone(),
             self.miner_interrupt_tx.clone(),
             peer,
-            data_dir,
         )?;
 
         let p2p_data = self.data.lock().unwrap();
@@ -178,3 +682,425 @@ impl P2pServer {
         Ok(serde_json::to_string(&resp_peers)?)
     }
 }
+
+/// Extracts the payload of a `MESSAGE(payload)` message
+fn payload<'a>(msg: &'a str, message: &str) -> Result<&'a str, String> {
+    let re =
+        Regex::new(&format!(r"^{}\((?P<payload>.*)\)$", message)).map_err(|e| e.to_string())?;
+    let caps = re.captures(msg).ok_or("Invalid MESSAGE")?;
+
+    Ok(caps.name("payload").map_or("", |m| m.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    use secp256k1::Secp256k1;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::crypto::{Network, SignatureScheme};
+    use crate::miner;
+    use crate::p2p::version::NODE_COMPACT_BLOCKS;
+    use crate::p2p::{add_peer, connect, publish, publish_block};
+    use crate::storage::MemoryStore;
+    use crate::tx;
+
+    struct TestPeer {
+        server: P2pServer,
+        node: Arc<Mutex<Node>>,
+        data: Arc<Mutex<P2pData>>,
+        block_rx: mpsc::Receiver<Block>,
+        _transaction_rx: mpsc::Receiver<SignedTransaction>,
+        _dir: TempDir,
+    }
+
+    impl TestPeer {
+        fn start() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let data_dir = format!("{}/", dir.path().display());
+            let host_addr = TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .to_string();
+
+            let (block_tx, block_rx) = mpsc::channel();
+            let (transaction_tx, transaction_rx) = mpsc::channel();
+            let (miner_interrupt_tx, _) = mpsc::channel();
+            let store: SharedStore = Arc::new(MemoryStore::new());
+            let mut node = Node::with_store(
+                block_tx,
+                transaction_tx,
+                &data_dir,
+                Network::Regtest,
+                store.clone(),
+            );
+            node.coinbase_maturity = 0;
+            let node = Arc::new(Mutex::new(node));
+            let data = Arc::new(Mutex::new(P2pData::new(&host_addr, store)));
+            let server = P2pServer::new(node.clone(), data.clone(), &host_addr, miner_interrupt_tx);
+
+            let mut running_server = server.clone();
+            thread::spawn(move || running_server.serve(&Shutdown::new()).unwrap());
+            while TcpStream::connect(&host_addr).is_err() {
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            Self {
+                server,
+                node,
+                data,
+                block_rx,
+                _transaction_rx: transaction_rx,
+                _dir: dir,
+            }
+        }
+
+        fn addr(&self) -> String {
+            self.server.host_addr.clone()
+        }
+
+        fn mine(&self) -> Block {
+            let mut node = self.node.lock().unwrap();
+            let block = miner::mine(node.get_proposed_block().unwrap());
+            node.process_block(&block).unwrap();
+            block
+        }
+
+        fn latest_block_hash(&self) -> Option<String> {
+            let node = self.node.lock().unwrap();
+            node.get_latest_block().unwrap().map(|b| b.hash)
+        }
+
+        fn handshaken_state(&self) -> ConnectionState {
+            ConnectionState {
+                addr: self.addr(),
+                version: Some(
+                    VersionMessage::local(&self.addr(), self.server.store.as_ref()).unwrap(),
+                ),
+                verack: true,
+            }
+        }
+    }
+
+    #[test]
+    fn test_new_block_message_is_processed() {
+        let peer = TestPeer::start();
+        let block = miner::mine(peer.node.lock().unwrap().get_proposed_block().unwrap());
+
+        let msg = format!(
+            "{}({})",
+            MESSAGE_NEW_BLOCK,
+            serde_json::to_string(&block).unwrap()
+        );
+        let mut server = peer.server.clone();
+        let resp = server.response(&msg, &mut peer.handshaken_state());
+
+        assert_eq!(resp, Ok("OK".to_string()));
+        assert_eq!(peer.latest_block_hash(), Some(block.hash));
+    }
+
+    #[test]
+    fn test_orphan_block_waits_for_parent() {
+        let a = TestPeer::start();
+        let b = TestPeer::start();
+        let genesis = a.mine();
+        let block = a.mine();
+
+        // B gets A's second block first and fetches its parent from A
+        let msg = format!(
+            "{}({})",
+            MESSAGE_NEW_BLOCK,
+            serde_json::to_string(&block).unwrap()
+        );
+        let mut server = b.server.clone();
+        let resp = server.response(&msg, &mut a.handshaken_state());
+
+        assert_eq!(resp, Ok("OK".to_string()));
+        assert_eq!(b.latest_block_hash(), Some(block.hash.clone()));
+        assert!(b.node.lock().unwrap().orphans.is_empty());
+        assert_eq!(b.block_rx.try_recv().unwrap().hash, genesis.hash);
+        assert_eq!(b.block_rx.try_recv().unwrap().hash, block.hash);
+    }
+
+    #[test]
+    fn test_orphan_without_proof_of_work_is_refused() {
+        let a = TestPeer::start();
+        let b = TestPeer::start();
+        a.mine();
+        let mut block = a.mine();
+        block.nonce += 1;
+
+        // The parent isn't requested for a block whose header doesn't verify
+        let msg = format!(
+            "{}({})",
+            MESSAGE_NEW_BLOCK,
+            serde_json::to_string(&block).unwrap()
+        );
+        let mut server = b.server.clone();
+        let resp = server.response(&msg, &mut a.handshaken_state());
+
+        assert!(resp.is_err());
+        assert!(b.node.lock().unwrap().orphans.is_empty());
+        assert_eq!(b.latest_block_hash(), None);
+    }
+
+    #[test]
+    fn test_announced_address_is_checked() {
+        let a = TestPeer::start();
+        let b = TestPeer::start();
+        let mut server = b.server.clone();
+        let version = serde_json::to_string(&a.handshaken_state().version).unwrap();
+        let msg = format!("{MESSAGE_VERSION}({version})");
+
+        // Another host can't claim A's address
+        let mut state = ConnectionState::new("10.0.0.1:50000");
+        assert!(server
+            .response(&msg, &mut state)
+            .unwrap_err()
+            .contains("isn't on the connecting host"));
+        let mut state = ConnectionState::new("127.0.0.1:50000");
+        assert!(server.response(&msg, &mut state).is_ok());
+
+        // What a peer fetches is only tracked once we connected to it
+        let block = a.mine();
+        b.node.lock().unwrap().process_block(&block).unwrap();
+        let getdata = format!(
+            "{MESSAGE_GETDATA}({})",
+            serde_json::to_string(&[Inventory::block(&block.hash)]).unwrap()
+        );
+        let mut state = a.handshaken_state();
+        server.response(&getdata, &mut state).unwrap();
+        assert!(!b
+            .data
+            .lock()
+            .unwrap()
+            .is_known(&a.addr(), &Inventory::block(&block.hash)));
+        connect(&b.data, &a.addr()).unwrap();
+        server.response(&getdata, &mut state).unwrap();
+        assert!(b
+            .data
+            .lock()
+            .unwrap()
+            .is_known(&a.addr(), &Inventory::block(&block.hash)));
+
+        // Banning the host refuses any address on it
+        b.data
+            .lock()
+            .unwrap()
+            .banned
+            .insert("127.0.0.1".to_string());
+        let mut state = ConnectionState::new("127.0.0.1:50000");
+        assert_eq!(
+            server.response(&msg, &mut state),
+            Err("Peer is banned".to_string())
+        );
+        assert!(server
+            .response(MESSAGE_PING, &mut a.handshaken_state())
+            .is_err());
+    }
+
+    #[test]
+    fn test_messages_require_handshake() {
+        let peer = TestPeer::start();
+        let mut server = peer.server.clone();
+
+        let resp = server.response(MESSAGE_GET_BLOCKS, &mut ConnectionState::default());
+
+        assert_eq!(resp, Err("Handshake required".to_string()));
+    }
+
+    #[test]
+    fn test_compact_block_relay() {
+        let a = TestPeer::start();
+        let b = TestPeer::start();
+        a.mine();
+        let (miner_interrupt_tx, _) = mpsc::channel();
+        add_peer(
+            a.node.clone(),
+            a.data.clone(),
+            miner_interrupt_tx,
+            &b.addr(),
+        )
+        .unwrap();
+
+        // B has the relayed transaction, the other one is fetched with the block
+        let to = b.node.lock().unwrap().address();
+        let relayed = a
+            .node
+            .lock()
+            .unwrap()
+            .send_tx(to, 10, SignatureScheme::Ecdsa)
+            .unwrap();
+        publish(a.data.clone(), Inventory::transaction(relayed.tx_id())).unwrap();
+        let unrelayed = tx::create_signed(&a.node.lock().unwrap().keypair, to, 20);
+        a.node
+            .lock()
+            .unwrap()
+            .add_tx_to_mempool(&unrelayed)
+            .unwrap();
+
+        let block = a.mine();
+        assert_eq!(block.transactions.len(), 3);
+        publish_block(a.data.clone(), block.clone()).unwrap();
+        assert_eq!(b.latest_block_hash(), Some(block.hash.clone()));
+        assert!(b.node.lock().unwrap().mempool.is_empty());
+
+        let request = BlockTxnRequest {
+            block_hash: block.hash.clone(),
+            indexes: vec![9],
+        };
+        let mut server = a.server.clone();
+        let resp = server.handle_get_block_txn(&serde_json::to_string(&request).unwrap());
+        assert_eq!(resp, Err("Invalid transaction index".to_string()));
+
+        // Peers without compact blocks get the block announced
+        a.data
+            .lock()
+            .unwrap()
+            .versions
+            .get_mut(&b.addr())
+            .unwrap()
+            .services &= !NODE_COMPACT_BLOCKS;
+        let block = a.mine();
+        publish_block(a.data.clone(), block.clone()).unwrap();
+        assert_eq!(b.latest_block_hash(), Some(block.hash));
+    }
+
+    #[test]
+    fn test_encrypted_transport() {
+        let a = TestPeer::start();
+        let b = TestPeer::start();
+        let block = a.mine();
+        let (miner_interrupt_tx, _) = mpsc::channel();
+        let encrypted = |peer: &TestPeer, addr: &str| {
+            let data = peer.data.lock().unwrap();
+            let conn = data.connections[addr].lock().unwrap();
+            conn.is_encrypted()
+        };
+
+        // B doesn't try encryption, A's connection to B is encrypted
+        b.data.lock().unwrap().encryption = false;
+        add_peer(
+            b.node.clone(),
+            b.data.clone(),
+            miner_interrupt_tx.clone(),
+            &a.addr(),
+        )
+        .unwrap();
+        add_peer(
+            a.node.clone(),
+            a.data.clone(),
+            miner_interrupt_tx,
+            &b.addr(),
+        )
+        .unwrap();
+        assert!(!encrypted(&b, &a.addr()));
+        assert!(encrypted(&a, &b.addr()));
+        assert_eq!(b.latest_block_hash(), Some(block.hash));
+
+        let block = a.mine();
+        publish(a.data.clone(), Inventory::block(&block.hash)).unwrap();
+        assert_eq!(b.latest_block_hash(), Some(block.hash));
+
+        // A pinned B's identity, a peer announcing another key is refused
+        let b_key = b
+            .data
+            .lock()
+            .unwrap()
+            .identity
+            .public_key(&Secp256k1::new());
+        let a_key = a
+            .data
+            .lock()
+            .unwrap()
+            .identity
+            .public_key(&Secp256k1::new());
+        assert_eq!(a.data.lock().unwrap().peer_keys[&b.addr()], b_key);
+        {
+            let mut data = a.data.lock().unwrap();
+            data.connections.clear();
+            data.peer_keys.insert(b.addr(), a_key);
+        }
+        assert!(connect(&a.data, &b.addr()).is_err());
+
+        // Pinned peers are always encrypted
+        {
+            let mut data = b.data.lock().unwrap();
+            data.connections.clear();
+            data.peer_keys.insert(a.addr(), a_key);
+        }
+        connect(&b.data, &a.addr()).unwrap();
+        assert!(encrypted(&b, &a.addr()));
+    }
+
+    #[test]
+    fn test_encryption_downgrade_is_refused() {
+        let a = TestPeer::start();
+        let b = TestPeer::start();
+        let version = VersionMessage::local(&b.addr(), b.node.lock().unwrap().store.as_ref());
+        let version = serde_json::to_string(&version.unwrap()).unwrap();
+
+        // Someone in between answers ENCRYPT with an error on B's behalf
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            while let Some(msg) = connection::read_message(&mut reader).unwrap() {
+                let resp = if msg.starts_with(MESSAGE_ENCRYPT) {
+                    connection::error_response("Unknown message")
+                } else if msg.starts_with(MESSAGE_VERSION) {
+                    version.clone()
+                } else {
+                    "OK".to_string()
+                };
+                connection::write_message(&mut writer, &resp).unwrap();
+            }
+        });
+
+        let error = connect(&a.data, &addr).unwrap_err();
+        assert!(error.to_string().contains("refused"));
+        assert!(a.data.lock().unwrap().connections.is_empty());
+    }
+
+    #[test]
+    fn test_inventory_relay() {
+        let a = TestPeer::start();
+        let b = TestPeer::start();
+        let (miner_interrupt_tx, _) = mpsc::channel();
+        add_peer(
+            a.node.clone(),
+            a.data.clone(),
+            miner_interrupt_tx,
+            &b.addr(),
+        )
+        .unwrap();
+
+        // B fetches the announced block from A and relays it on
+        let block = a.mine();
+        let block_inv = Inventory::block(&block.hash);
+        publish(a.data.clone(), block_inv.clone()).unwrap();
+
+        assert_eq!(b.latest_block_hash(), Some(block.hash.clone()));
+        assert_eq!(b.block_rx.try_recv().unwrap().hash, block.hash);
+        assert!(a.data.lock().unwrap().is_known(&b.addr(), &block_inv));
+        // B won't announce the block back to A
+        assert!(b.data.lock().unwrap().is_known(&a.addr(), &block_inv));
+
+        let to = b.node.lock().unwrap().address();
+        let tx = a
+            .node
+            .lock()
+            .unwrap()
+            .send_tx(to, 10, SignatureScheme::Ecdsa)
+            .unwrap();
+        publish(a.data.clone(), Inventory::transaction(tx.tx_id())).unwrap();
+
+        assert!(b.node.lock().unwrap().mempool.contains_key(&tx.tx_id()));
+    }
+}
@@ -0,0 +1,330 @@
+//! Encrypted transport in the spirit of BIP324. The connecting side sends
+//! ENCRYPT with an ephemeral public key and its node identity key as its first
+//! message and the other side answers with its own, both then derive
+//! ChaCha20-Poly1305 keys from the ECDH secrets of their ephemeral keys and of
+//! each one's ephemeral key with the other's identity key. Only the owner of an
+//! announced identity key can derive them, peers we connect to are pinned to
+//! the key they first announced or the configured one. Every following message
+//! is framed as a 4 byte length and its ciphertext. Peers that don't know
+//! ENCRYPT answer with an error and the connection stays plaintext, unless
+//! their VERSION advertises `NODE_ENCRYPTED` and the connection is dropped.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use hkdf::Hkdf;
+use secp256k1::{ecdh::SharedSecret, rand, PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+/// Largest encrypted message accepted, a block of `MAX_BLOCK_TRANSACTIONS`
+/// or `MAX_HEADERS` headers with room to spare
+pub const MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+const KEY_SALT: &[u8] = b"bitcoin-simple/transport";
+
+/// One direction of an encrypted connection, each message gets the next nonce
+struct Cipher {
+    aead: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl Cipher {
+    fn new(key: &[u8; 32]) -> Self {
+        Self {
+            aead: ChaCha20Poly1305::new(key.into()),
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> Nonce {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        nonce.into()
+    }
+}
+
+/// Keys of an encrypted connection, messages fail to decrypt if any was
+/// altered, dropped or reordered.
+pub struct Session {
+    send: Cipher,
+    recv: Cipher,
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session").finish_non_exhaustive()
+    }
+}
+
+/// Identity key a peer must announce, see `transport`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PeerKey {
+    pub addr: String,
+    pub key: PublicKey,
+}
+
+/// Loads the node identity key from the data directory, creating it on first start
+pub fn load_identity(data_dir: &str) -> Result<SecretKey, Box<dyn std::error::Error>> {
+    let path = format!("{}/node_key", data_dir);
+    if Path::new(&path).exists() {
+        return Ok(fs::read_to_string(path)?.trim().parse()?);
+    }
+
+    let identity = SecretKey::new(&mut rand::thread_rng());
+    fs::write(&path, identity.display_secret().to_string())?;
+
+    Ok(identity)
+}
+
+/// Our half of the key exchange
+pub struct Handshake {
+    ephemeral: SecretKey,
+    identity: SecretKey,
+}
+
+impl Handshake {
+    pub fn new(identity: &SecretKey) -> Self {
+        Self {
+            ephemeral: SecretKey::new(&mut rand::thread_rng()),
+            identity: *identity,
+        }
+    }
+
+    /// Public keys announced to the peer, the ephemeral one then the identity
+    pub fn keys(&self) -> String {
+        let secp = Secp256k1::new();
+        format!(
+            "{},{}",
+            self.ephemeral.public_key(&secp),
+            self.identity.public_key(&secp)
+        )
+    }
+
+    /// Derives the session keys from the `remote` keys the peer announced,
+    /// `initiator` being whether we opened the connection. Returns the peer's
+    /// identity key, the session only works if the peer owns it.
+    pub fn finish(self, remote: &str, initiator: bool) -> Result<(PublicKey, Session), String> {
+        let (remote_ephemeral, remote_identity) = remote
+            .split_once(',')
+            .ok_or("Invalid keys: Expected ephemeral and identity keys")?;
+        let remote_ephemeral: PublicKey = remote_ephemeral
+            .parse()
+            .map_err(|e| format!("Invalid key: {e}"))?;
+        let remote_identity: PublicKey = remote_identity
+            .parse()
+            .map_err(|e| format!("Invalid key: {e}"))?;
+
+        let ecdh =
+            |public_key, secret_key| SharedSecret::new(public_key, secret_key).secret_bytes();
+        // The initiator's ephemeral key with the responder's identity and the
+        // other way around, each side computing them from its own secrets
+        let (ephemeral_identity, identity_ephemeral) = if initiator {
+            (
+                ecdh(&remote_identity, &self.ephemeral),
+                ecdh(&remote_ephemeral, &self.identity),
+            )
+        } else {
+            (
+                ecdh(&remote_ephemeral, &self.identity),
+                ecdh(&remote_identity, &self.ephemeral),
+            )
+        };
+        let secret = [
+            ecdh(&remote_ephemeral, &self.ephemeral),
+            ephemeral_identity,
+            identity_ephemeral,
+        ]
+        .concat();
+
+        // Both sides' keys are bound to the session
+        let transcript = if initiator {
+            format!("{}/{remote}", self.keys())
+        } else {
+            format!("{remote}/{}", self.keys())
+        };
+        let hkdf = Hkdf::<Sha256>::new(Some(KEY_SALT), &secret);
+        let key = |role: &str| {
+            let mut key = [0; 32];
+            hkdf.expand(format!("{role}/{transcript}").as_bytes(), &mut key)
+                .expect("Valid key length");
+            Cipher::new(&key)
+        };
+
+        let (initiator_key, responder_key) = (key("initiator"), key("responder"));
+        let session = if initiator {
+            Session {
+                send: initiator_key,
+                recv: responder_key,
+            }
+        } else {
+            Session {
+                send: responder_key,
+                recv: initiator_key,
+            }
+        };
+
+        Ok((remote_identity, session))
+    }
+}
+
+/// Answers an ENCRYPT message carrying the initiator's keys, returns our keys
+/// to send back, the initiator's identity and the session used from then on.
+pub fn accept(remote: &str, identity: &SecretKey) -> Result<(String, PublicKey, Session), String> {
+    let handshake = Handshake::new(identity);
+    let keys = handshake.keys();
+    let (remote_identity, session) = handshake.finish(remote, false)?;
+
+    Ok((keys, remote_identity, session))
+}
+
+impl Session {
+    pub fn write_message(&mut self, stream: &mut impl Write, msg: &str) -> io::Result<()> {
+        let nonce = self.send.next_nonce();
+        let ciphertext = self
+            .send
+            .aead
+            .encrypt(&nonce, msg.as_bytes())
+            .map_err(|_| invalid_data("Encryption failed"))?;
+
+        stream.write_all(&(ciphertext.len() as u32).to_be_bytes())?;
+        stream.write_all(&ciphertext)?;
+        stream.flush()
+    }
+
+    /// Reads and decrypts the next message, returning `None` once the peer
+    /// closed the connection.
+    pub fn read_message(&mut self, reader: &mut impl Read) -> io::Result<Option<String>> {
+        let mut len = [0; 4];
+        match reader.read_exact(&mut len) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(invalid_data("Message too large"));
+        }
+        // Grown as bytes arrive rather than trusting the unauthenticated length
+        let mut ciphertext = Vec::new();
+        reader.take(len as u64).read_to_end(&mut ciphertext)?;
+        if ciphertext.len() < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let nonce = self.recv.next_nonce();
+        let plaintext = self
+            .recv
+            .aead
+            .decrypt(&nonce, ciphertext.as_slice())
+            .map_err(|_| invalid_data("Message authentication failed"))?;
+
+        String::from_utf8(plaintext)
+            .map(Some)
+            .map_err(|_| invalid_data("Invalid UTF-8"))
+    }
+}
+
+fn invalid_data(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity() -> SecretKey {
+        SecretKey::new(&mut rand::thread_rng())
+    }
+
+    fn session_pair() -> (Session, Session) {
+        let initiator = Handshake::new(&identity());
+        let (keys, _, responder) = accept(&initiator.keys(), &identity()).unwrap();
+        (initiator.finish(&keys, true).unwrap().1, responder)
+    }
+
+    #[test]
+    fn test_messages_round_trip() {
+        let (mut initiator, mut responder) = session_pair();
+        let mut wire = vec![];
+        initiator.write_message(&mut wire, "PING").unwrap();
+        initiator.write_message(&mut wire, "GET_BLOCKS").unwrap();
+        assert!(!wire.windows(4).any(|w| w == b"PING"));
+
+        let mut reader = wire.as_slice();
+        assert_eq!(
+            responder.read_message(&mut reader).unwrap().unwrap(),
+            "PING"
+        );
+        assert_eq!(
+            responder.read_message(&mut reader).unwrap().unwrap(),
+            "GET_BLOCKS"
+        );
+        assert!(responder.read_message(&mut reader).unwrap().is_none());
+
+        let mut wire = vec![];
+        responder.write_message(&mut wire, "OK").unwrap();
+        assert_eq!(
+            initiator
+                .read_message(&mut wire.as_slice())
+                .unwrap()
+                .unwrap(),
+            "OK"
+        );
+    }
+
+    #[test]
+    fn test_tampered_messages_are_rejected() {
+        let (mut initiator, mut responder) = session_pair();
+        let mut wire = vec![];
+        initiator.write_message(&mut wire, "PING").unwrap();
+        *wire.last_mut().unwrap() ^= 1;
+        assert!(responder.read_message(&mut wire.as_slice()).is_err());
+
+        // Replaying a message reuses a nonce the receiver is past
+        let (mut initiator, mut responder) = session_pair();
+        let mut wire = vec![];
+        initiator.write_message(&mut wire, "PING").unwrap();
+        responder.read_message(&mut wire.as_slice()).unwrap();
+        assert!(responder.read_message(&mut wire.as_slice()).is_err());
+
+        assert!(accept("not a key", &identity()).is_err());
+
+        // Lengths over the limit are refused before reading the message
+        let (_, mut responder) = session_pair();
+        let wire = (MAX_FRAME_SIZE as u32 + 1).to_be_bytes();
+        assert!(responder.read_message(&mut wire.as_slice()).is_err());
+        let wire = 16u32.to_be_bytes();
+        assert!(responder.read_message(&mut wire.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_identity_is_authenticated() {
+        let responder_identity = identity();
+        let initiator = Handshake::new(&identity());
+        let (keys, _, mut responder) = accept(&initiator.keys(), &responder_identity).unwrap();
+        let (remote_identity, mut session) = initiator.finish(&keys, true).unwrap();
+        assert_eq!(
+            remote_identity,
+            responder_identity.public_key(&Secp256k1::new())
+        );
+        let mut wire = vec![];
+        responder.write_message(&mut wire, "OK").unwrap();
+        assert!(session.read_message(&mut wire.as_slice()).is_ok());
+
+        // Announcing someone else's identity key, without its secret, gives
+        // keys that don't match the initiator's
+        let initiator = Handshake::new(&identity());
+        let (keys, _, mut responder) = accept(&initiator.keys(), &identity()).unwrap();
+        let ephemeral = keys.split_once(',').unwrap().0;
+        let impersonated = format!("{ephemeral},{}", remote_identity);
+        let (_, mut session) = initiator.finish(&impersonated, true).unwrap();
+        let mut wire = vec![];
+        responder.write_message(&mut wire, "OK").unwrap();
+        assert!(session.read_message(&mut wire.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{rpc, storage::ChainStore};
+
+/// The node can serve the full block chain
+pub const NODE_NETWORK: u64 = 1;
+
+/// The node relays new blocks as compact blocks, see `compact`
+pub const NODE_COMPACT_BLOCKS: u64 = 2;
+
+/// The node accepts the encrypted transport, see `transport`
+pub const NODE_ENCRYPTED: u64 = 4;
+
+/// Services this node advertises to its peers
+pub const LOCAL_SERVICES: u64 = NODE_NETWORK | NODE_COMPACT_BLOCKS | NODE_ENCRYPTED;
+
+pub const USER_AGENT: &str = concat!("/bitcoind:", env!("CARGO_PKG_VERSION"), "/");
+
+/// Sent by each side when a connection is opened, before any other message.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct VersionMessage {
+    pub version: String,
+    pub services: u64,
+    pub best_height: u32,
+    /// `None` while the node has no blocks yet and can join any network
+    pub genesis_hash: Option<String>,
+    pub user_agent: String,
+    /// Address the sender is listening on for P2P connections
+    pub addr_from: String,
+}
+
+impl VersionMessage {
+    pub fn local(host_addr: &str, store: &dyn ChainStore) -> Result<Self, String> {
+        Ok(Self {
+            version: rpc::protocol_version(),
+            services: LOCAL_SERVICES,
+            best_height: store.get_latest_block_number()?,
+            genesis_hash: store.get_block_hash(1)?,
+            user_agent: USER_AGENT.to_string(),
+            addr_from: host_addr.to_string(),
+        })
+    }
+
+    /// Checks that a peer speaks a compatible protocol on the same chain and can
+    /// serve us blocks.
+    pub fn check_compatible(&self, remote: &VersionMessage) -> Result<(), String> {
+        self.check_protocol(remote)?;
+
+        if remote.services & NODE_NETWORK == 0 {
+            return Err("Handshake failed: Peer doesn't serve blocks".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Checks that a peer speaks a compatible protocol on the same chain, light
+    /// clients connecting to us don't serve blocks.
+    pub fn check_protocol(&self, remote: &VersionMessage) -> Result<(), String> {
+        if major_version(&self.version) != major_version(&remote.version) {
+            return Err(format!(
+                "Handshake failed: Unsupported protocol version {}",
+                remote.version
+            ));
+        }
+
+        if let (Some(local), Some(remote)) = (&self.genesis_hash, &remote.genesis_hash) {
+            if local != remote {
+                return Err(format!("Handshake failed: Genesis mismatch {}", remote));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks a peer advertising the encrypted transport isn't on a plaintext
+    /// connection after we asked for it, someone in between must have refused
+    /// ENCRYPT on its behalf.
+    pub fn check_encryption(&self, encrypted: bool) -> Result<(), String> {
+        if !encrypted && self.services & NODE_ENCRYPTED != 0 {
+            return Err("Handshake failed: Peer advertises encryption but refused it".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Services both sides support and may use on this connection
+    pub fn negotiated_services(&self, remote: &VersionMessage) -> u64 {
+        self.services & remote.services
+    }
+}
+
+fn major_version(version: &str) -> &str {
+    version.split('.').next().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(version: &str, genesis_hash: Option<&str>) -> VersionMessage {
+        VersionMessage {
+            version: version.to_string(),
+            services: LOCAL_SERVICES,
+            best_height: 0,
+            genesis_hash: genesis_hash.map(String::from),
+            user_agent: USER_AGENT.to_string(),
+            addr_from: "127.0.0.1:8333".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_compatible_versions() {
+        let local = version("1.0.0", Some("00ab"));
+
+        assert!(local
+            .check_compatible(&version("1.2.0", Some("00ab")))
+            .is_ok());
+        assert!(local.check_compatible(&version("1.0.0", None)).is_ok());
+        assert!(local
+            .check_compatible(&version("2.0.0", Some("00ab")))
+            .is_err());
+        assert!(local
+            .check_compatible(&version("1.0.0", Some("00cd")))
+            .is_err());
+
+        let mut no_network = version("1.0.0", Some("00ab"));
+        no_network.services = 0;
+        assert!(local.check_compatible(&no_network).is_err());
+        assert!(local.check_protocol(&no_network).is_ok());
+
+        assert!(local.check_encryption(true).is_ok());
+        assert!(local.check_encryption(false).is_err());
+        assert!(no_network.check_encryption(false).is_ok());
+    }
+}
@@ -0,0 +1,315 @@
+//! Who may call the RPC server: credentials, source addresses and request rates.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    net::IpAddr,
+    path::Path,
+    sync::Mutex,
+    time::Instant,
+};
+
+use secp256k1::rand::{self, RngCore};
+
+/// Written to the data directory when no RPC password is configured, like Bitcoin's
+pub const COOKIE_FILE: &str = ".cookie";
+pub const COOKIE_USER: &str = "__cookie__";
+
+/// Methods signing with the node's keys or changing its state, refused in read-only mode
+pub const MUTATING_METHODS: [&str; 13] = [
+    "send",
+    "sendmany",
+    "bumpfee",
+    "newpubkey",
+    "newaddress",
+    "createlockedtx",
+    "sendrawtransaction",
+    "spendscript",
+    "exportblocks",
+    "importblocks",
+    "dumpchainstate",
+    "loadchainstate",
+    "stop",
+];
+
+/// Client addresses allowed when none are configured
+pub fn default_allow_ips() -> Vec<String> {
+    vec!["127.0.0.1".to_string(), "::1".to_string()]
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    user: String,
+    password: String,
+}
+
+impl Credentials {
+    pub fn new(user: String, password: String) -> Self {
+        Self { user, password }
+    }
+
+    /// Random credentials written to the cookie file of `data_dir`, readable
+    /// by the node's user only
+    pub fn cookie(data_dir: &str) -> Result<Self, String> {
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let credentials = Self::new(COOKIE_USER.to_string(), hex::encode(secret));
+
+        let path = Path::new(data_dir).join(COOKIE_FILE);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(&path)
+            .and_then(|mut file| write!(file, "{}:{}", credentials.user, credentials.password))
+            .map_err(|e| format!("Can't write {}: {e}", path.display()))?;
+
+        Ok(credentials)
+    }
+
+    /// Whether an `Authorization` header value carries these credentials
+    pub fn check(&self, authorization: &str) -> bool {
+        let Some(encoded) = authorization.strip_prefix("Basic ") else {
+            return false;
+        };
+        let Ok(decoded) = base64::decode(encoded.trim()) else {
+            return false;
+        };
+        let expected = format!("{}:{}", self.user, self.password);
+
+        // Compared in constant time so the password can't be guessed byte by byte
+        decoded.len() == expected.len()
+            && decoded
+                .iter()
+                .zip(expected.as_bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+pub fn remove_cookie(data_dir: &str) -> io::Result<()> {
+    match fs::remove_file(Path::new(data_dir).join(COOKIE_FILE)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// An address or a CIDR subnet such as `10.0.0.0/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+impl Subnet {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("Invalid RPC allowed address {s}"))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("Invalid RPC allowed subnet {s}"))?,
+            None => max_len,
+        };
+
+        Ok(Self { addr, prefix_len })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual stack listener show up as mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(net).into(),
+                u32::from(ip).into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(net.into(), ip.into(), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, bits: u32, prefix_len: u32) -> bool {
+    let shift = bits - prefix_len;
+    shift == bits || net >> shift == ip >> shift
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets per client address, refilled at `per_second` requests per
+/// second and holding a second's worth of them
+pub struct RateLimiter {
+    per_second: u32,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+/// Above this many tracked clients, the ones with full buckets are forgotten
+const MAX_TRACKED_CLIENTS: usize = 1024;
+
+impl RateLimiter {
+    pub fn new(per_second: u32) -> Self {
+        Self {
+            per_second,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from the client's bucket, false when it is empty
+    pub fn check(&self, ip: IpAddr, now: Instant) -> bool {
+        let capacity = f64::from(self.per_second);
+        let refilled = |bucket: &Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            (bucket.tokens + elapsed * capacity).min(capacity)
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|_, bucket| refilled(bucket) < capacity);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = refilled(bucket);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+
+        true
+    }
+}
+
+/// Access rules of the RPC server
+pub struct RpcAccess {
+    pub credentials: Credentials,
+    pub allowed: Vec<Subnet>,
+    pub rate_limiter: Option<RateLimiter>,
+    pub read_only: bool,
+}
+
+impl RpcAccess {
+    pub fn new(
+        credentials: Credentials,
+        allow_ips: &[String],
+        rate_limit: Option<u32>,
+        read_only: bool,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            credentials,
+            allowed: allow_ips
+                .iter()
+                .map(|s| Subnet::parse(s))
+                .collect::<Result<_, _>>()?,
+            rate_limiter: rate_limit.filter(|r| *r > 0).map(RateLimiter::new),
+            read_only,
+        })
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        self.allowed.iter().any(|subnet| subnet.contains(ip))
+    }
+
+    pub fn is_method_enabled(&self, method: &str) -> bool {
+        !self.read_only || !MUTATING_METHODS.contains(&method)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_subnets() {
+        let subnet = Subnet::parse("10.1.0.0/16").unwrap();
+        assert!(subnet.contains(ip("10.1.200.3")));
+        assert!(subnet.contains(ip("::ffff:10.1.0.1")));
+        assert!(!subnet.contains(ip("10.2.0.1")));
+        assert!(!subnet.contains(ip("::1")));
+
+        assert!(Subnet::parse("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+        assert!(Subnet::parse("::1").unwrap().contains(ip("::1")));
+        assert!(!Subnet::parse("127.0.0.1")
+            .unwrap()
+            .contains(ip("127.0.0.2")));
+        assert!(Subnet::parse("10.0.0.0/33").is_err());
+        assert!(Subnet::parse("localhost").is_err());
+    }
+
+    #[test]
+    fn test_credentials() {
+        let credentials = Credentials::new("alice".to_string(), "secret".to_string());
+        assert!(credentials.check(&format!("Basic {}", base64::encode("alice:secret"))));
+        assert!(!credentials.check(&format!("Basic {}", base64::encode("alice:secreT"))));
+        assert!(!credentials.check(&format!("Bearer {}", base64::encode("alice:secret"))));
+        assert!(!credentials.check("Basic not base64!"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().to_str().unwrap();
+        let cookie = Credentials::cookie(data_dir).unwrap();
+        let contents = fs::read_to_string(dir.path().join(COOKIE_FILE)).unwrap();
+        assert!(contents.starts_with("__cookie__:"));
+        assert!(cookie.check(&format!("Basic {}", base64::encode(contents))));
+        assert_ne!(Credentials::cookie(data_dir).unwrap(), cookie);
+
+        remove_cookie(data_dir).unwrap();
+        assert!(!dir.path().join(COOKIE_FILE).exists());
+        remove_cookie(data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(2);
+        let start = Instant::now();
+        let (a, b) = (ip("10.0.0.1"), ip("10.0.0.2"));
+
+        assert!(limiter.check(a, start));
+        assert!(limiter.check(a, start));
+        assert!(!limiter.check(a, start));
+        assert!(limiter.check(b, start));
+
+        assert!(limiter.check(a, start + Duration::from_millis(500)));
+        assert!(!limiter.check(a, start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_read_only() {
+        let credentials = Credentials::new("user".to_string(), "pass".to_string());
+        let access = RpcAccess::new(credentials, &default_allow_ips(), None, true).unwrap();
+
+        assert!(access.is_allowed(ip("127.0.0.1")));
+        assert!(!access.is_allowed(ip("192.168.1.1")));
+        assert!(access.is_method_enabled("getbalance"));
+        assert!(!access.is_method_enabled("send"));
+    }
+}
@@ -0,0 +1,315 @@
+//! JSON-RPC over HTTP, served from the listener directly so access rules can
+//! use the client's address.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Take, Write},
+    net::{IpAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use jsonrpc_core::{MetaIoHandler, Middleware};
+use log::{debug, warn};
+
+use crate::{
+    p2p::{connection::ConnectionLimit, ResultUnit},
+    shutdown::Shutdown,
+};
+
+use super::access::RpcAccess;
+
+/// Larger request bodies are refused before reading them
+pub const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+
+/// Request line and headers together, reading stops past it
+pub const MAX_HEADER_SIZE: u64 = 8 * 1024;
+
+/// Connections served at once, more are refused until one closes
+pub const MAX_RPC_CONNECTIONS: usize = 16;
+
+/// Slows down password guessing, like Bitcoin's
+const AUTH_FAILURE_DELAY: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Default)]
+struct Request {
+    method: String,
+    /// Names are lowercase
+    headers: HashMap<String, String>,
+    body: String,
+}
+
+/// Reads a line of the request line and headers, failing once they outgrow
+/// `MAX_HEADER_SIZE`
+fn read_header_line(reader: &mut Take<impl BufRead>, line: &mut String) -> Result<usize, String> {
+    let len = reader.read_line(line).map_err(|e| e.to_string())?;
+    if reader.limit() == 0 && !line.ends_with('\n') {
+        return Err("Request headers are too large".to_string());
+    }
+
+    Ok(len)
+}
+
+fn read_request(stream: impl Read) -> Result<Request, String> {
+    let mut reader = BufReader::new(stream).take(MAX_HEADER_SIZE);
+    let mut request_line = String::new();
+    read_header_line(&mut reader, &mut request_line)?;
+
+    let mut request = Request {
+        method: request_line
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+        ..Default::default()
+    };
+    let mut header = String::new();
+    while read_header_line(&mut reader, &mut header)? > 2 {
+        if let Some((name, value)) = header.split_once(':') {
+            request
+                .headers
+                .insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+        header.clear();
+    }
+
+    let len = match request.headers.get("content-length") {
+        Some(len) => len.parse().map_err(|_| "Invalid Content-Length")?,
+        None => 0,
+    };
+    if len > MAX_BODY_SIZE {
+        return Err(format!("Request body of {len} bytes is too large"));
+    }
+    let mut body = vec![0u8; len];
+    reader
+        .into_inner()
+        .read_exact(&mut body)
+        .map_err(|e| e.to_string())?;
+    request.body = String::from_utf8(body).map_err(|e| e.to_string())?;
+
+    Ok(request)
+}
+
+/// Status, extra headers and body of the HTTP response to `request`
+fn respond<S: Middleware<()>>(
+    request: &Request,
+    ip: IpAddr,
+    access: &RpcAccess,
+    io: &MetaIoHandler<(), S>,
+) -> (&'static str, &'static str, String) {
+    if let Some(limiter) = &access.rate_limiter {
+        if !limiter.check(ip, Instant::now()) {
+            return ("429 Too Many Requests", "Retry-After: 1\r\n", String::new());
+        }
+    }
+
+    let authorized = request
+        .headers
+        .get("authorization")
+        .is_some_and(|authorization| access.credentials.check(authorization));
+    if !authorized {
+        warn!("Rejected RPC credentials client={ip}");
+        thread::sleep(AUTH_FAILURE_DELAY);
+        return (
+            "401 Unauthorized",
+            "WWW-Authenticate: Basic realm=\"jsonrpc\"\r\n",
+            String::new(),
+        );
+    }
+
+    if request.method != "POST" {
+        return ("405 Method Not Allowed", "Allow: POST\r\n", String::new());
+    }
+
+    match io.handle_request_sync(&request.body, ()) {
+        Some(response) => ("200 OK", "", response),
+        // Only notifications, which have no response
+        None => ("204 No Content", "", String::new()),
+    }
+}
+
+fn handle_connection<S: Middleware<()>>(
+    stream: TcpStream,
+    ip: IpAddr,
+    access: &RpcAccess,
+    io: &MetaIoHandler<(), S>,
+) -> ResultUnit {
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let (status, headers, body) = match read_request(&stream) {
+        Ok(request) => respond(&request, ip, access, io),
+        Err(e) => ("400 Bad Request", "", format!("{e}\n")),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\n{headers}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    (&stream).write_all(response.as_bytes())?;
+
+    Ok(())
+}
+
+/// Serves `io` on `listener` until `shutdown` is requested, one thread per
+/// connection and at most `MAX_RPC_CONNECTIONS` of them
+pub fn serve<S: Middleware<()>>(
+    listener: TcpListener,
+    io: MetaIoHandler<(), S>,
+    access: RpcAccess,
+    shutdown: &Shutdown,
+) -> ResultUnit {
+    shutdown.wake_listener(listener.local_addr()?);
+    let io = Arc::new(io);
+    let access = Arc::new(access);
+    let connections = ConnectionLimit::new(MAX_RPC_CONNECTIONS);
+
+    for stream in listener.incoming() {
+        if shutdown.is_requested() {
+            break;
+        }
+
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("RPC connection failed error={e}");
+                continue;
+            }
+        };
+        let Ok(ip) = stream.peer_addr().map(|addr| addr.ip()) else {
+            continue;
+        };
+        if !access.is_allowed(ip) {
+            debug!("Refused RPC connection client={ip}");
+            let _ = (&stream).write_all(
+                b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            );
+            continue;
+        }
+        let Some(slot) = connections.acquire() else {
+            warn!("Refused RPC connection, too many connections client={ip}");
+            let _ = (&stream).write_all(
+                b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            );
+            continue;
+        };
+
+        let io = io.clone();
+        let access = access.clone();
+        thread::spawn(move || {
+            let _slot = slot;
+            if let Err(e) = handle_connection(stream, ip, &access, &io) {
+                debug!("RPC connection closed client={ip} error={e}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonrpc_core::Value;
+
+    use super::*;
+    use crate::rpc::access::{Credentials, RpcAccess};
+
+    fn io() -> MetaIoHandler<()> {
+        let mut io = MetaIoHandler::default();
+        io.add_sync_method("ping", |_| Ok(Value::String("pong".to_string())));
+        io
+    }
+
+    fn request(authorization: Option<&str>, body: &str) -> Request {
+        let mut request = Request {
+            method: "POST".to_string(),
+            body: body.to_string(),
+            ..Default::default()
+        };
+        if let Some(authorization) = authorization {
+            let encoded = format!("Basic {}", base64::encode(authorization));
+            request.headers.insert("authorization".to_string(), encoded);
+        }
+        request
+    }
+
+    #[test]
+    fn test_respond() {
+        let credentials = Credentials::new("user".to_string(), "pass".to_string());
+        let access = RpcAccess::new(credentials, &[], Some(2), false).unwrap();
+        let ip = "127.0.0.1".parse().unwrap();
+        let ping = r#"{"jsonrpc":"2.0","method":"ping","id":1}"#;
+
+        let (status, _, body) = respond(&request(Some("user:pass"), ping), ip, &access, &io());
+        assert_eq!(status, "200 OK");
+        assert!(body.contains("pong"));
+
+        let (status, headers, _) = respond(&request(Some("user:nope"), ping), ip, &access, &io());
+        assert_eq!(status, "401 Unauthorized");
+        assert!(headers.starts_with("WWW-Authenticate"));
+
+        let (status, _, _) = respond(&request(Some("user:pass"), ping), ip, &access, &io());
+        assert_eq!(status, "429 Too Many Requests");
+    }
+
+    #[test]
+    fn test_read_request() {
+        let body = r#"{"jsonrpc":"2.0","method":"ping","id":1}"#;
+        let raw = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        let request = read_request(raw.as_bytes()).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.body, body);
+
+        // Headers are only read up to the limit, even without a line break
+        let endless = "X".repeat(MAX_HEADER_SIZE as usize * 2);
+        let error = read_request(endless.as_bytes()).unwrap_err();
+        assert_eq!(error, "Request headers are too large");
+        let many = format!("POST / HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(2000));
+        let error = read_request(many.as_bytes()).unwrap_err();
+        assert_eq!(error, "Request headers are too large");
+    }
+
+    #[test]
+    fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let credentials = Credentials::new("user".to_string(), "pass".to_string());
+        let access = RpcAccess::new(credentials, &["127.0.0.1".to_string()], None, false).unwrap();
+        let shutdown = Shutdown::new();
+        let server = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || {
+                serve(listener, io(), access, &shutdown).map_err(|e| e.to_string())
+            })
+        };
+
+        let body = r#"{"jsonrpc":"2.0","method":"ping","id":1}"#;
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\nAuthorization: Basic {}\r\nContent-Length: {}\r\n\r\n{body}",
+            base64::encode("user:pass"),
+            body.len()
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with(r#"{"jsonrpc":"2.0","result":"pong","id":1}"#));
+
+        // Connections past the limit are refused while the others are open
+        let open: Vec<_> = (0..MAX_RPC_CONNECTIONS)
+            .map(|_| TcpStream::connect(addr).unwrap())
+            .collect();
+        let mut response = String::new();
+        let mut refused = TcpStream::connect(addr).unwrap();
+        refused.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"));
+        drop(open);
+
+        shutdown.request();
+        server.join().unwrap().unwrap();
+    }
+}
@@ -1,25 +1,74 @@
+pub mod access;
+pub mod http;
 mod model;
 
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::BTreeMap,
+    net::TcpListener,
+    sync::{Arc, Mutex},
+};
 
-use colored::Colorize;
-use jsonrpc_core::IoHandler;
-use jsonrpc_http_server::ServerBuilder;
+use jsonrpc_core::MetaIoHandler;
+use log::info;
 
-use crate::{node::Node, p2p::ResultUnit, rpc::model::RpcInstance};
+use crate::{
+    metrics::RpcTimer,
+    node::Node,
+    p2p::ResultUnit,
+    rpc::{access::RpcAccess, model::RpcInstance},
+    shutdown::Shutdown,
+};
 
 use jsonrpc_core::Result;
 use jsonrpc_derive::rpc;
 
-use crate::{block::Block, crypto, tx::SignedTransaction};
+use crate::{
+    block::{merkle::TxOutProof, Block},
+    crypto::{self, musig::AggregatedKey},
+    node::{AddressTransaction, MempoolEntry, SnapshotInfo, SupplyInfo, TxOutSetInfo},
+    script::Script,
+    tx::{RelativeLock, SignedTransaction},
+};
+
+pub const PROTOCOL_VERSION: &str = "1.0.0";
+
+/// Protocol version shared by the RPC interface and the P2P handshake
+pub fn protocol_version() -> String {
+    PROTOCOL_VERSION.to_string()
+}
 
 #[rpc]
 pub trait Rpc {
     #[rpc(name = "protocolVersion")]
     fn protocol_version(&self) -> Result<String>;
 
+    /// Signs with ECDSA unless `scheme` says otherwise. Pays no fee by
+    /// default, `replaceable` transactions can be fee bumped with `bumpfee`.
     #[rpc(name = "send")]
-    fn send(&self, pubkey: crypto::key::PublicKey, amount: u32) -> Result<SignedTransaction>;
+    fn send(
+        &self,
+        address: crypto::Address,
+        amount: u32,
+        scheme: Option<crypto::SignatureScheme>,
+        fee: Option<u32>,
+        replaceable: Option<bool>,
+    ) -> Result<SignedTransaction>;
+
+    /// Sends `amounts` to their addresses in one transaction paying `fee`,
+    /// all of them or none. With `dry_run` it is returned without being sent.
+    #[rpc(name = "sendmany")]
+    fn sendmany(
+        &self,
+        amounts: BTreeMap<crypto::Address, u32>,
+        scheme: Option<crypto::SignatureScheme>,
+        fee: Option<u32>,
+        dry_run: Option<bool>,
+    ) -> Result<SignedTransaction>;
+
+    /// Replaces our replaceable mempool transaction by one paying `fee`,
+    /// twice the original fee by default
+    #[rpc(name = "bumpfee")]
+    fn bumpfee(&self, tx_id: String, fee: Option<u32>) -> Result<SignedTransaction>;
 
     #[rpc(name = "newpubkey")]
     fn newpubkey(&self) -> Result<String>;
@@ -27,36 +76,139 @@ pub trait Rpc {
     #[rpc(name = "getpubkey")]
     fn getpubkey(&self) -> Result<String>;
 
+    #[rpc(name = "newaddress")]
+    fn newaddress(&self) -> Result<crypto::Address>;
+
+    #[rpc(name = "getaddress")]
+    fn getaddress(&self) -> Result<crypto::Address>;
+
+    /// Key and address of the multi-party account of `public_keys`, in signing order
+    #[rpc(name = "aggregatekeys")]
+    fn aggregatekeys(&self, public_keys: Vec<crypto::key::PublicKey>) -> Result<AggregatedKey>;
+
     #[rpc(name = "blockheight")]
     fn blockheight(&self) -> Result<u32>;
 
+    /// Fails for the heights whose blocks are pruned
     #[rpc(name = "getblock")]
     fn getblock(&self, block_number: u32) -> Result<Option<Block>>;
 
     #[rpc(name = "balances")]
-    fn balances(&self) -> Result<std::collections::HashMap<crypto::key::PublicKey, u32>>;
+    fn balances(&self) -> Result<std::collections::HashMap<crypto::Address, u32>>;
 
     #[rpc(name = "getbalance")]
-    fn getbalance(&self, pubkey: crypto::key::PublicKey) -> Result<u32>;
+    fn getbalance(&self, address: crypto::Address) -> Result<u32>;
+
+    /// Confirmed transactions to or from `address`, newest first, `count`
+    /// of them (10 by default) after skipping the `skip` newest
+    #[rpc(name = "listtransactions")]
+    fn listtransactions(
+        &self,
+        address: crypto::Address,
+        count: Option<u32>,
+        skip: Option<u32>,
+    ) -> Result<Vec<AddressTransaction>>;
 
     #[rpc(name = "mempool")]
     fn mempool(&self) -> Result<Vec<SignedTransaction>>;
+
+    /// Mempool transaction with the unconfirmed ones it is mined along with
+    #[rpc(name = "getmempoolentry")]
+    fn getmempoolentry(&self, tx_id: String) -> Result<Option<MempoolEntry>>;
+
+    /// Signs a time locked transfer without broadcasting it, see `sendrawtransaction`
+    #[rpc(name = "createlockedtx")]
+    fn createlockedtx(
+        &self,
+        address: crypto::Address,
+        amount: u32,
+        lock_time: u32,
+        relative_lock: Option<RelativeLock>,
+    ) -> Result<SignedTransaction>;
+
+    #[rpc(name = "sendrawtransaction")]
+    fn sendrawtransaction(&self, tx: SignedTransaction) -> Result<String>;
+
+    #[rpc(name = "scriptaddress")]
+    fn scriptaddress(&self, script: Script) -> Result<crypto::Address>;
+
+    /// Spends from a script address, `unlocking` items being script pushes or
+    /// `sig` for the node's signature of the transaction
+    #[rpc(name = "spendscript")]
+    fn spendscript(
+        &self,
+        script: Script,
+        unlocking: Vec<String>,
+        address: crypto::Address,
+        amount: u32,
+    ) -> Result<SignedTransaction>;
+
+    #[rpc(name = "gettxoutproof")]
+    fn gettxoutproof(&self, tx_id: String) -> Result<TxOutProof>;
+
+    #[rpc(name = "verifytxoutproof")]
+    fn verifytxoutproof(&self, proof: TxOutProof) -> Result<String>;
+
+    #[rpc(name = "gettxoutsetinfo")]
+    fn gettxoutsetinfo(&self) -> Result<TxOutSetInfo>;
+
+    #[rpc(name = "getsupply")]
+    fn getsupply(&self) -> Result<SupplyInfo>;
+
+    /// Writes the active chain to the new block file `name` in the node's
+    /// export directory
+    #[rpc(name = "exportblocks")]
+    fn exportblocks(&self, name: String) -> Result<u32>;
+
+    /// Validates and connects the blocks of the block file at `path`
+    #[rpc(name = "importblocks")]
+    fn importblocks(&self, path: String) -> Result<u32>;
+
+    /// Writes a snapshot of the chain state at `height`, the tip by default,
+    /// to the new file `name` in the node's export directory, returning the
+    /// hash other nodes configure to load it
+    #[rpc(name = "dumpchainstate")]
+    fn dumpchainstate(&self, name: String, height: Option<u32>) -> Result<SnapshotInfo>;
+
+    /// Starts an empty chain from the snapshot at `path`, if it matches the
+    /// configured `chainstate_hash`
+    #[rpc(name = "loadchainstate")]
+    fn loadchainstate(&self, path: String) -> Result<SnapshotInfo>;
+
+    /// Shuts the node down, like Ctrl-C
+    #[rpc(name = "stop")]
+    fn stop(&self) -> Result<String>;
 }
 
-pub fn run_server(node: Arc<Mutex<Node>>, host: String, port: u32) -> ResultUnit {
-    let mut io = IoHandler::new();
-    let rpc = RpcInstance::new(node);
-    io.extend_with(rpc.to_delegate());
+/// Serves RPC to the clients `access` lets in until `shutdown` is requested.
+/// In read-only mode the mutating methods aren't registered at all.
+pub fn run_server(
+    node: Arc<Mutex<Node>>,
+    host: String,
+    port: u32,
+    access: RpcAccess,
+    shutdown: Shutdown,
+) -> ResultUnit {
+    let rpc = RpcInstance::new(node, shutdown.clone());
+    let methods: Vec<_> = rpc
+        .to_delegate()
+        .into_iter()
+        .filter(|(method, _)| access.is_method_enabled(method))
+        .collect();
+    let mut io = MetaIoHandler::with_middleware(RpcTimer::new(
+        methods.iter().map(|(method, _)| method.clone()),
+    ));
+    io.extend_with(methods);
 
     let rpc_path = format!("{host}:{port}");
-
-    let server = ServerBuilder::new(io)
-        .threads(3)
-        .start_http(&rpc_path.parse()?)?;
-
-    println!("{} Listening on {:?}:{:?}\n", "RPC".green(), host, rpc_path);
-
-    server.wait();
+    let listener = TcpListener::bind(&rpc_path)?;
+    info!(
+        "RPC listening addr={rpc_path} read_only={}",
+        access.read_only
+    );
+
+    http::serve(listener, io, access, &shutdown)?;
+    info!("RPC server stopped");
 
     Ok(())
 }
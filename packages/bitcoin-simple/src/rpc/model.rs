@@ -1,42 +1,102 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     sync::{Arc, Mutex},
 };
 
-use jsonrpc_core::Result;
+use jsonrpc_core::{Error, Result};
 
-use crate::{crypto, node::Node, storage, tx::SignedTransaction};
+use crate::{
+    block::merkle::TxOutProof,
+    crypto::{
+        self,
+        musig::{AggregatedKey, KeyAggContext},
+    },
+    node::{
+        AddressTransaction, MempoolEntry, Node, SnapshotInfo, SupplyInfo, TxOutSetInfo,
+        DEFAULT_LIST_COUNT,
+    },
+    script::{Instruction, Script},
+    shutdown::Shutdown,
+    storage::SharedStore,
+    tx::{self, RelativeLock, SignedTransaction},
+};
 
 use super::Rpc;
 
 pub struct RpcInstance {
     node: Arc<Mutex<Node>>,
+    store: SharedStore,
+    shutdown: Shutdown,
 }
 
 impl RpcInstance {
-    pub fn new(node: Arc<Mutex<Node>>) -> Self {
-        Self { node }
-    }
-
-    pub fn data_dir(&self) -> String {
-        self.node.lock().unwrap().data_dir.clone()
+    pub fn new(node: Arc<Mutex<Node>>, shutdown: Shutdown) -> Self {
+        let store = node.lock().unwrap().store.clone();
+        Self {
+            node,
+            store,
+            shutdown,
+        }
     }
 }
 
 impl Rpc for RpcInstance {
     fn protocol_version(&self) -> Result<String> {
-        Ok("1.0.0".to_string())
+        Ok(super::protocol_version())
     }
 
-    fn send(&self, pubkey: crypto::key::PublicKey, amount: u32) -> Result<SignedTransaction> {
+    fn send(
+        &self,
+        address: crypto::Address,
+        amount: u32,
+        scheme: Option<crypto::SignatureScheme>,
+        fee: Option<u32>,
+        replaceable: Option<bool>,
+    ) -> Result<SignedTransaction> {
         let mut node = self.node.lock().unwrap();
-        Ok(node.send_tx(pubkey, amount).unwrap())
+        node.send_tx_with_fee(
+            address,
+            amount,
+            scheme.unwrap_or_default(),
+            fee.unwrap_or_default(),
+            replaceable.unwrap_or_default(),
+        )
+        .map_err(Error::invalid_params)
+    }
+
+    fn sendmany(
+        &self,
+        amounts: BTreeMap<crypto::Address, u32>,
+        scheme: Option<crypto::SignatureScheme>,
+        fee: Option<u32>,
+        dry_run: Option<bool>,
+    ) -> Result<SignedTransaction> {
+        let mut node = self.node.lock().unwrap();
+        node.send_many(
+            &amounts,
+            scheme.unwrap_or_default(),
+            fee.unwrap_or_default(),
+            dry_run.unwrap_or_default(),
+        )
+        .map_err(Error::invalid_params)
+    }
+
+    fn bumpfee(&self, tx_id: String, fee: Option<u32>) -> Result<SignedTransaction> {
+        let mut node = self.node.lock().unwrap();
+        node.bump_fee(&tx_id, fee).map_err(Error::invalid_params)
+    }
+
+    fn aggregatekeys(&self, public_keys: Vec<crypto::key::PublicKey>) -> Result<AggregatedKey> {
+        let network = self.node.lock().unwrap().network;
+        let key_agg = KeyAggContext::new(public_keys).map_err(Error::invalid_params)?;
+        Ok(AggregatedKey {
+            public_key: key_agg.aggregated_key(),
+            address: key_agg.address(network),
+        })
     }
 
     fn blockheight(&self) -> Result<u32> {
-        let block_height =
-            storage::get_latest_block_number(&storage::db::blocks_metadata(true, &self.data_dir()))
-                .unwrap();
+        let block_height = self.store.get_latest_block_number().unwrap();
         Ok(block_height)
     }
 
@@ -50,32 +110,61 @@ impl Rpc for RpcInstance {
         Ok(random_key.public_key.to_string())
     }
 
+    fn newaddress(&self) -> Result<crypto::Address> {
+        let random_key = crypto::KeyPair::new();
+        let network = self.node.lock().unwrap().network;
+        Ok(crypto::Address::from_public_key(
+            &random_key.public_key,
+            network,
+        ))
+    }
+
+    fn getaddress(&self) -> Result<crypto::Address> {
+        Ok(self.node.lock().unwrap().address())
+    }
+
     fn getblock(&self, block_number: u32) -> Result<Option<crate::block::Block>> {
-        let block_hash = storage::get_block_hash(
-            &storage::db::blocks_metadata(true, &self.data_dir()),
-            block_number,
-        )
-        .unwrap();
-        let block = block_hash.and_then(|b| {
-            storage::get_block(&storage::db::blocks(true, &self.data_dir()), &b).unwrap()
-        });
+        if (1..=self.store.get_prune_height().unwrap()).contains(&block_number) {
+            return Err(Error::invalid_params(format!(
+                "Block at height {block_number} is pruned"
+            )));
+        }
+        let block_hash = self.store.get_block_hash(block_number).unwrap();
+        let block = block_hash.and_then(|b| self.store.get_block(&b).unwrap());
 
         Ok(block)
     }
 
-    fn balances(&self) -> Result<HashMap<crypto::key::PublicKey, u32>> {
-        let balances =
-            storage::get_balances(&storage::db::balances(true, &self.data_dir())).unwrap();
+    fn balances(&self) -> Result<HashMap<crypto::Address, u32>> {
+        let balances = self.store.get_balances().unwrap();
 
         Ok(balances)
     }
 
-    fn getbalance(&self, pubkey: crypto::key::PublicKey) -> Result<u32> {
-        let balance =
-            storage::get_balance(&storage::db::balances(true, &self.data_dir()), pubkey).unwrap();
+    fn getbalance(&self, address: crypto::Address) -> Result<u32> {
+        let network = self.node.lock().unwrap().network;
+        address
+            .require_network(network)
+            .map_err(Error::invalid_params)?;
+        let balance = self.store.get_balance(address).unwrap();
         Ok(balance.unwrap_or_default())
     }
 
+    fn listtransactions(
+        &self,
+        address: crypto::Address,
+        count: Option<u32>,
+        skip: Option<u32>,
+    ) -> Result<Vec<AddressTransaction>> {
+        let node = self.node.lock().unwrap();
+        node.list_transactions(
+            address,
+            count.map_or(DEFAULT_LIST_COUNT, |c| c as usize),
+            skip.unwrap_or_default() as usize,
+        )
+        .map_err(Error::invalid_params)
+    }
+
     fn mempool(&self) -> Result<Vec<SignedTransaction>> {
         let mempool = self
             .node
@@ -87,4 +176,123 @@ impl Rpc for RpcInstance {
             .collect();
         Ok(mempool)
     }
+
+    fn getmempoolentry(&self, tx_id: String) -> Result<Option<MempoolEntry>> {
+        Ok(self.node.lock().unwrap().mempool_entry(&tx_id))
+    }
+
+    fn createlockedtx(
+        &self,
+        address: crypto::Address,
+        amount: u32,
+        lock_time: u32,
+        relative_lock: Option<RelativeLock>,
+    ) -> Result<SignedTransaction> {
+        let node = self.node.lock().unwrap();
+        node.create_locked_tx(address, amount, lock_time, relative_lock)
+            .map_err(Error::invalid_params)
+    }
+
+    fn sendrawtransaction(&self, tx: SignedTransaction) -> Result<String> {
+        let mut node = self.node.lock().unwrap();
+        node.receive_tx(&tx).map_err(Error::invalid_params)?;
+
+        Ok(tx.tx_id())
+    }
+
+    fn scriptaddress(&self, script: Script) -> Result<crypto::Address> {
+        let network = self.node.lock().unwrap().network;
+        Ok(crypto::Address::from_script(&script, network))
+    }
+
+    fn spendscript(
+        &self,
+        script: Script,
+        unlocking: Vec<String>,
+        address: crypto::Address,
+        amount: u32,
+    ) -> Result<SignedTransaction> {
+        // `None` stands for the node's signature, only known once the tx is built
+        let unlocking = unlocking
+            .iter()
+            .map(|item| match item.as_str() {
+                "sig" => Ok(None),
+                item => item.parse::<Instruction>().map(Some),
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(Error::invalid_params)?;
+
+        let mut node = self.node.lock().unwrap();
+        let tx = tx::create_script_spend(&node.keypair, script, address, amount, |tx| {
+            Script::new(
+                unlocking
+                    .into_iter()
+                    .map(|item| item.unwrap_or_else(|| tx::script_sig(&node.keypair, tx)))
+                    .collect(),
+            )
+        });
+        node.receive_tx(&tx).map_err(Error::invalid_params)?;
+
+        Ok(tx)
+    }
+
+    fn gettxoutproof(&self, tx_id: String) -> Result<TxOutProof> {
+        self.store
+            .find_transaction_block(&tx_id)
+            .map_err(Error::invalid_params)?
+            .and_then(|block| TxOutProof::new(&block, &tx_id))
+            .ok_or_else(|| Error::invalid_params("Transaction not found in the chain"))
+    }
+
+    fn verifytxoutproof(&self, proof: TxOutProof) -> Result<String> {
+        let tx_id = proof.verify().map_err(Error::invalid_params)?;
+
+        // The proof is only valid for blocks of our active chain
+        let block_hash = self
+            .store
+            .get_block_height(&proof.header.hash)
+            .map_err(Error::invalid_params)?
+            .and_then(|height| self.store.get_block_hash(height).ok().flatten());
+        if block_hash.as_ref() != Some(&proof.header.hash) {
+            return Err(Error::invalid_params("Block not found in the chain"));
+        }
+
+        Ok(tx_id.to_string())
+    }
+
+    fn gettxoutsetinfo(&self) -> Result<TxOutSetInfo> {
+        let node = self.node.lock().unwrap();
+        node.txoutset_info().map_err(Error::invalid_params)
+    }
+
+    fn getsupply(&self) -> Result<SupplyInfo> {
+        let node = self.node.lock().unwrap();
+        node.supply_info().map_err(Error::invalid_params)
+    }
+
+    fn exportblocks(&self, name: String) -> Result<u32> {
+        let node = self.node.lock().unwrap();
+        node.export_blocks(&name).map_err(Error::invalid_params)
+    }
+
+    fn importblocks(&self, path: String) -> Result<u32> {
+        let mut node = self.node.lock().unwrap();
+        node.import_blocks(&path).map_err(Error::invalid_params)
+    }
+
+    fn dumpchainstate(&self, name: String, height: Option<u32>) -> Result<SnapshotInfo> {
+        let node = self.node.lock().unwrap();
+        node.dump_chain_state(&name, height)
+            .map_err(Error::invalid_params)
+    }
+
+    fn loadchainstate(&self, path: String) -> Result<SnapshotInfo> {
+        let mut node = self.node.lock().unwrap();
+        node.load_chain_state(&path).map_err(Error::invalid_params)
+    }
+
+    fn stop(&self) -> Result<String> {
+        self.shutdown.request();
+        Ok("Bitcoind stopping".to_string())
+    }
 }
@@ -0,0 +1,589 @@
+//! Minimal stack-based script language for spending conditions.
+//!
+//! Coins sent to a script address can only be spent by revealing the locking
+//! script and an unlocking script of data pushes that makes it succeed, the way
+//! Bitcoin's P2SH works. Scripts are written and serialized in their ASM form,
+//! e.g. `OP_SHA256 0x<hash> OP_EQUALVERIFY 0x<pubkey> OP_CHECKSIG`.
+
+use std::fmt::{Debug, Display};
+use std::str::FromStr;
+
+use secp256k1::{ecdsa::Signature, Message, PublicKey, Secp256k1};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::crypto::address::hash160;
+
+const MAX_STACK_SIZE: usize = 1_000;
+const MAX_OPS: usize = 201;
+const MAX_MULTISIG_KEYS: i64 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Dup,
+    Drop,
+    Swap,
+    If,
+    NotIf,
+    Else,
+    EndIf,
+    Verify,
+    Equal,
+    EqualVerify,
+    Sha256,
+    Hash160,
+    CheckSig,
+    CheckSigVerify,
+    CheckMultisig,
+    /// Fails unless the spending block is at least at the height on top of the stack
+    CheckLockTimeVerify,
+}
+
+const OPCODES: [(Opcode, &str); 16] = [
+    (Opcode::Dup, "OP_DUP"),
+    (Opcode::Drop, "OP_DROP"),
+    (Opcode::Swap, "OP_SWAP"),
+    (Opcode::If, "OP_IF"),
+    (Opcode::NotIf, "OP_NOTIF"),
+    (Opcode::Else, "OP_ELSE"),
+    (Opcode::EndIf, "OP_ENDIF"),
+    (Opcode::Verify, "OP_VERIFY"),
+    (Opcode::Equal, "OP_EQUAL"),
+    (Opcode::EqualVerify, "OP_EQUALVERIFY"),
+    (Opcode::Sha256, "OP_SHA256"),
+    (Opcode::Hash160, "OP_HASH160"),
+    (Opcode::CheckSig, "OP_CHECKSIG"),
+    (Opcode::CheckSigVerify, "OP_CHECKSIGVERIFY"),
+    (Opcode::CheckMultisig, "OP_CHECKMULTISIG"),
+    (Opcode::CheckLockTimeVerify, "OP_CHECKLOCKTIMEVERIFY"),
+];
+
+impl Opcode {
+    pub fn name(&self) -> &'static str {
+        OPCODES.iter().find(|(op, _)| op == self).unwrap().1
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    Op(Opcode),
+    /// Data push, written as `0x<hex>`
+    Push(Vec<u8>),
+    /// Number push, written in decimal
+    Num(i64),
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Op(op) => write!(f, "{}", op.name()),
+            Self::Push(data) => write!(f, "0x{}", hex::encode(data)),
+            Self::Num(n) => write!(f, "{n}"),
+        }
+    }
+}
+
+impl FromStr for Instruction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(data) = s.strip_prefix("0x") {
+            return hex::decode(data)
+                .map(Self::Push)
+                .map_err(|e| format!("Invalid script push {s}: {e}"));
+        }
+
+        if let Ok(n) = s.parse::<i64>() {
+            return Ok(Self::Num(n));
+        }
+
+        OPCODES
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(op, _)| Self::Op(*op))
+            .ok_or_else(|| format!("Unknown opcode {s}"))
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Script(pub Vec<Instruction>);
+
+impl Script {
+    pub fn new(instructions: Vec<Instruction>) -> Self {
+        Self(instructions)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Unlocking scripts may only push data, so they can't alter the locking script's logic
+    pub fn is_push_only(&self) -> bool {
+        self.0.iter().all(|i| !matches!(i, Instruction::Op(_)))
+    }
+
+    /// Hash committed to by the script's address
+    pub fn hash(&self) -> [u8; 32] {
+        Sha256::digest(self.to_string().as_bytes()).into()
+    }
+
+    /// Pay to public key, spent with a signature alone
+    pub fn pay_to_pubkey(public_key: &PublicKey) -> Self {
+        Self(vec![
+            Instruction::Push(public_key.serialize().to_vec()),
+            Instruction::Op(Opcode::CheckSig),
+        ])
+    }
+
+    /// `m` of the public keys must sign, in the same order
+    pub fn multisig(m: u8, public_keys: &[PublicKey]) -> Self {
+        let mut instructions = vec![Instruction::Num(m.into())];
+        instructions.extend(
+            public_keys
+                .iter()
+                .map(|k| Instruction::Push(k.serialize().to_vec())),
+        );
+        instructions.push(Instruction::Num(public_keys.len() as i64));
+        instructions.push(Instruction::Op(Opcode::CheckMultisig));
+
+        Self(instructions)
+    }
+
+    /// Hashed timelock contract: `receiver` can claim with the SHA256 preimage of
+    /// `hash`, or `sender` can take the coins back from block `timeout` on.
+    pub fn htlc(hash: &[u8], receiver: &PublicKey, sender: &PublicKey, timeout: u32) -> Self {
+        Self(vec![
+            Instruction::Op(Opcode::If),
+            Instruction::Op(Opcode::Sha256),
+            Instruction::Push(hash.to_vec()),
+            Instruction::Op(Opcode::EqualVerify),
+            Instruction::Push(receiver.serialize().to_vec()),
+            Instruction::Op(Opcode::Else),
+            Instruction::Num(timeout.into()),
+            Instruction::Op(Opcode::CheckLockTimeVerify),
+            Instruction::Op(Opcode::Drop),
+            Instruction::Push(sender.serialize().to_vec()),
+            Instruction::Op(Opcode::EndIf),
+            Instruction::Op(Opcode::CheckSig),
+        ])
+    }
+}
+
+impl Display for Script {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let asm: Vec<_> = self.0.iter().map(|i| i.to_string()).collect();
+        write!(f, "{}", asm.join(" "))
+    }
+}
+
+impl Debug for Script {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Script({self})")
+    }
+}
+
+impl FromStr for Script {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split_whitespace()
+            .map(Instruction::from_str)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl Serialize for Script {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Script {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// What a script is checked against
+pub struct ScriptContext<'a> {
+    /// Hash of the spending transaction, which signatures commit to
+    pub tx_hash: &'a [u8],
+    /// Height of the block the transaction is included in
+    pub height: u32,
+}
+
+/// Runs the unlocking script then the locking script on its stack, the spend
+/// is valid if the locking script ends with a true value on top.
+pub fn verify(unlocking: &Script, locking: &Script, context: &ScriptContext) -> Result<(), String> {
+    if !unlocking.is_push_only() {
+        return Err("Script failed: Unlocking script must only push data".to_string());
+    }
+
+    let mut stack = vec![];
+    execute(unlocking, &mut stack, context)?;
+    execute(locking, &mut stack, context)?;
+
+    match stack.last() {
+        Some(top) if is_true(top) => Ok(()),
+        _ => Err("Script failed: Evaluated to false".to_string()),
+    }
+}
+
+fn execute(
+    script: &Script,
+    stack: &mut Vec<Vec<u8>>,
+    context: &ScriptContext,
+) -> Result<(), String> {
+    // Whether each enclosing IF branch is being executed
+    let mut branches: Vec<bool> = vec![];
+    let mut ops = 0;
+
+    for instruction in &script.0 {
+        let executing = branches.iter().all(|b| *b);
+
+        let op = match instruction {
+            Instruction::Push(data) if executing => {
+                push(stack, data.clone())?;
+                continue;
+            }
+            Instruction::Num(n) if executing => {
+                push(stack, encode_num(*n))?;
+                continue;
+            }
+            Instruction::Op(op) => *op,
+            _ => continue,
+        };
+
+        ops += 1;
+        if ops > MAX_OPS {
+            return Err("Script failed: Too many operations".to_string());
+        }
+
+        match op {
+            Opcode::If | Opcode::NotIf => {
+                let condition = if executing {
+                    let top = pop(stack)?;
+                    is_true(&top) == (op == Opcode::If)
+                } else {
+                    false
+                };
+                branches.push(condition);
+            }
+            Opcode::Else => {
+                let branch = branches
+                    .last_mut()
+                    .ok_or("Script failed: OP_ELSE without OP_IF")?;
+                *branch = !*branch;
+            }
+            Opcode::EndIf => {
+                branches
+                    .pop()
+                    .ok_or("Script failed: OP_ENDIF without OP_IF")?;
+            }
+            _ if !executing => {}
+            Opcode::Dup => {
+                let top = stack
+                    .last()
+                    .ok_or("Script failed: Stack underflow")?
+                    .clone();
+                push(stack, top)?;
+            }
+            Opcode::Drop => {
+                pop(stack)?;
+            }
+            Opcode::Swap => {
+                let a = pop(stack)?;
+                let b = pop(stack)?;
+                push(stack, a)?;
+                push(stack, b)?;
+            }
+            Opcode::Verify => verify_top(stack, "OP_VERIFY")?,
+            Opcode::Equal | Opcode::EqualVerify => {
+                let a = pop(stack)?;
+                let b = pop(stack)?;
+                push(stack, encode_bool(a == b))?;
+                if op == Opcode::EqualVerify {
+                    verify_top(stack, "OP_EQUALVERIFY")?;
+                }
+            }
+            Opcode::Sha256 => {
+                let top = pop(stack)?;
+                push(stack, Sha256::digest(top).to_vec())?;
+            }
+            Opcode::Hash160 => {
+                let top = pop(stack)?;
+                push(stack, hash160(&top).to_vec())?;
+            }
+            Opcode::CheckSig | Opcode::CheckSigVerify => {
+                let public_key = pop(stack)?;
+                let sig = pop(stack)?;
+                push(
+                    stack,
+                    encode_bool(check_sig(&sig, &public_key, context.tx_hash)),
+                )?;
+                if op == Opcode::CheckSigVerify {
+                    verify_top(stack, "OP_CHECKSIGVERIFY")?;
+                }
+            }
+            Opcode::CheckMultisig => check_multisig(stack, context)?,
+            Opcode::CheckLockTimeVerify => {
+                let locktime = decode_num(stack.last().ok_or("Script failed: Stack underflow")?)?;
+                if locktime < 0 || locktime > i64::from(context.height) {
+                    return Err("Script failed: Locktime not reached".to_string());
+                }
+            }
+        }
+    }
+
+    if !branches.is_empty() {
+        return Err("Script failed: Unbalanced OP_IF".to_string());
+    }
+
+    Ok(())
+}
+
+/// Pops `n` public keys and `m` signatures, each signature must match one of the
+/// keys after the previous signature's key.
+fn check_multisig(stack: &mut Vec<Vec<u8>>, context: &ScriptContext) -> Result<(), String> {
+    let n = decode_num(&pop(stack)?)?;
+    if !(0..=MAX_MULTISIG_KEYS).contains(&n) {
+        return Err("Script failed: Invalid multisig key count".to_string());
+    }
+    let public_keys = (0..n).map(|_| pop(stack)).collect::<Result<Vec<_>, _>>()?;
+
+    let m = decode_num(&pop(stack)?)?;
+    if !(0..=n).contains(&m) {
+        return Err("Script failed: Invalid multisig signature count".to_string());
+    }
+    let sigs = (0..m).map(|_| pop(stack)).collect::<Result<Vec<_>, _>>()?;
+
+    // Popped in reverse, both lists are matched from their last item
+    let mut keys = public_keys.iter();
+    let valid = sigs.iter().all(|sig| {
+        keys.by_ref()
+            .any(|public_key| check_sig(sig, public_key, context.tx_hash))
+    });
+    push(stack, encode_bool(valid))?;
+
+    Ok(())
+}
+
+fn check_sig(sig: &[u8], public_key: &[u8], tx_hash: &[u8]) -> bool {
+    let (Ok(sig), Ok(public_key), Ok(message)) = (
+        Signature::from_der(sig),
+        PublicKey::from_slice(public_key),
+        Message::from_digest_slice(tx_hash),
+    ) else {
+        return false;
+    };
+
+    Secp256k1::verification_only()
+        .verify_ecdsa(&message, &sig, &public_key)
+        .is_ok()
+}
+
+/// Checked before every push so no script grows the stack past the limit
+fn push(stack: &mut Vec<Vec<u8>>, item: Vec<u8>) -> Result<(), String> {
+    if stack.len() >= MAX_STACK_SIZE {
+        return Err("Script failed: Stack overflow".to_string());
+    }
+    stack.push(item);
+
+    Ok(())
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, String> {
+    stack
+        .pop()
+        .ok_or_else(|| "Script failed: Stack underflow".to_string())
+}
+
+fn verify_top(stack: &mut Vec<Vec<u8>>, op: &str) -> Result<(), String> {
+    if !is_true(&pop(stack)?) {
+        return Err(format!("Script failed: {op}"));
+    }
+
+    Ok(())
+}
+
+fn is_true(value: &[u8]) -> bool {
+    value.iter().any(|b| *b != 0)
+}
+
+fn encode_bool(value: bool) -> Vec<u8> {
+    if value {
+        vec![1]
+    } else {
+        vec![]
+    }
+}
+
+/// Numbers are little endian with the sign in the top bit, like Bitcoin's script numbers
+pub fn encode_num(n: i64) -> Vec<u8> {
+    let mut abs = n.unsigned_abs();
+    let mut bytes = vec![];
+    while abs > 0 {
+        bytes.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+
+    if let Some(last) = bytes.last_mut() {
+        if *last & 0x80 != 0 {
+            bytes.push(if n < 0 { 0x80 } else { 0 });
+        } else if n < 0 {
+            *last |= 0x80;
+        }
+    }
+
+    bytes
+}
+
+pub fn decode_num(bytes: &[u8]) -> Result<i64, String> {
+    if bytes.len() > 8 {
+        return Err("Script failed: Number overflow".to_string());
+    }
+
+    let Some((last, rest)) = bytes.split_last() else {
+        return Ok(0);
+    };
+    let abs = rest
+        .iter()
+        .rev()
+        .fold(u64::from(last & 0x7f), |n, b| (n << 8) | u64::from(*b));
+    let n = i64::try_from(abs).map_err(|_| "Script failed: Number overflow")?;
+
+    Ok(if last & 0x80 != 0 { -n } else { n })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::KeyPair;
+
+    use super::*;
+
+    const TX_HASH: [u8; 32] = [7; 32];
+
+    fn context(height: u32) -> ScriptContext<'static> {
+        ScriptContext {
+            tx_hash: &TX_HASH,
+            height,
+        }
+    }
+
+    fn sig(keypair: &KeyPair) -> Instruction {
+        Instruction::Push(keypair.sign(&TX_HASH).serialize_der().to_vec())
+    }
+
+    #[test]
+    fn test_script_asm_roundtrip() {
+        let keypair = KeyPair::new();
+        let script = Script::htlc(&[1; 32], &keypair.public_key, &keypair.public_key, 100);
+        let asm = script.to_string();
+
+        assert!(asm.starts_with("OP_IF OP_SHA256 0x0101"));
+        assert_eq!(asm.parse::<Script>(), Ok(script));
+        assert!("OP_NOPE".parse::<Script>().is_err());
+    }
+
+    #[test]
+    fn test_script_numbers() {
+        for n in [
+            0,
+            1,
+            -1,
+            127,
+            128,
+            -128,
+            255,
+            256,
+            100_000,
+            i64::from(u32::MAX),
+        ] {
+            assert_eq!(decode_num(&encode_num(n)), Ok(n), "{n}");
+        }
+        assert_eq!(encode_num(128), vec![0x80, 0]);
+        assert_eq!(encode_num(-1), vec![0x81]);
+    }
+
+    #[test]
+    fn test_pay_to_pubkey() {
+        let keypair = KeyPair::new();
+        let locking = Script::pay_to_pubkey(&keypair.public_key);
+
+        let unlocking = Script::new(vec![sig(&keypair)]);
+        assert!(verify(&unlocking, &locking, &context(1)).is_ok());
+
+        let unlocking = Script::new(vec![sig(&KeyPair::new())]);
+        assert!(verify(&unlocking, &locking, &context(1)).is_err());
+
+        // Unlocking scripts can't run opcodes
+        let unlocking = Script::new(vec![Instruction::Num(1), Instruction::Op(Opcode::Dup)]);
+        assert!(verify(&unlocking, &locking, &context(1)).is_err());
+    }
+
+    #[test]
+    fn test_multisig() {
+        let keys: Vec<_> = (0..3).map(|_| KeyPair::new()).collect();
+        let public_keys: Vec<_> = keys.iter().map(|k| k.public_key).collect();
+        let locking = Script::multisig(2, &public_keys);
+
+        let unlocking = Script::new(vec![sig(&keys[0]), sig(&keys[2])]);
+        assert!(verify(&unlocking, &locking, &context(1)).is_ok());
+
+        // Signatures must follow the keys' order
+        let unlocking = Script::new(vec![sig(&keys[2]), sig(&keys[0])]);
+        assert!(verify(&unlocking, &locking, &context(1)).is_err());
+
+        let unlocking = Script::new(vec![sig(&keys[1]), sig(&keys[1])]);
+        assert!(verify(&unlocking, &locking, &context(1)).is_err());
+
+        let unlocking = Script::new(vec![sig(&keys[1])]);
+        assert!(verify(&unlocking, &locking, &context(1)).is_err());
+    }
+
+    #[test]
+    fn test_htlc() {
+        let (receiver, sender) = (KeyPair::new(), KeyPair::new());
+        let preimage = b"secret".to_vec();
+        let hash = Sha256::digest(&preimage);
+        let locking = Script::htlc(&hash, &receiver.public_key, &sender.public_key, 100);
+
+        let claim = Script::new(vec![
+            sig(&receiver),
+            Instruction::Push(preimage),
+            Instruction::Num(1),
+        ]);
+        assert!(verify(&claim, &locking, &context(1)).is_ok());
+
+        let wrong_preimage = Script::new(vec![
+            sig(&receiver),
+            Instruction::Push(b"guess".to_vec()),
+            Instruction::Num(1),
+        ]);
+        assert!(verify(&wrong_preimage, &locking, &context(1)).is_err());
+
+        let refund = Script::new(vec![sig(&sender), Instruction::Num(0)]);
+        assert!(verify(&refund, &locking, &context(99)).is_err());
+        assert!(verify(&refund, &locking, &context(100)).is_ok());
+
+        let stolen = Script::new(vec![sig(&receiver), Instruction::Num(0)]);
+        assert!(verify(&stolen, &locking, &context(100)).is_err());
+    }
+
+    #[test]
+    fn test_stack_size_is_limited() {
+        let pushes = |n| Script::new(vec![Instruction::Num(1); n]);
+        assert!(verify(&pushes(MAX_STACK_SIZE), &Script::default(), &context(1)).is_ok());
+
+        let result = verify(&pushes(MAX_STACK_SIZE + 1), &Script::default(), &context(1));
+        assert_eq!(result, Err("Script failed: Stack overflow".to_string()));
+
+        // Pushes in the locking script count towards the same stack
+        let locking = Script::new(vec![Instruction::Num(1); 2]);
+        assert!(verify(&pushes(MAX_STACK_SIZE - 1), &locking, &context(1)).is_err());
+        let dup = Script::new(vec![Instruction::Op(Opcode::Dup)]);
+        assert!(verify(&pushes(MAX_STACK_SIZE), &dup, &context(1)).is_err());
+    }
+}
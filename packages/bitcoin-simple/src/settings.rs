@@ -1,5 +1,10 @@
+use std::fmt;
+
 use config::{Config, Environment};
-use serde::{Deserialize, Serialize};
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{crypto::Network, node::Checkpoint, p2p::transport::PeerKey, rpc::access};
 
 pub const CONFIG_NAME: &str = "config";
 pub const CONFIG_FILE_NAME: &str = "config.yaml";
@@ -21,6 +26,92 @@ pub struct ConfigInfo {
     pub data_dir: String,
     pub miner_enabled: bool,
     pub bootstrap_nodes: Vec<String>,
+    /// Network addresses are valid on, mainnet when unset
+    #[serde(default)]
+    pub network: Network,
+    /// Most verbose level logged: off, error, warn, info, debug or trace
+    #[serde(
+        default = "default_log_level",
+        deserialize_with = "deserialize_log_level"
+    )]
+    pub log_level: LevelFilter,
+    /// Port of the Prometheus metrics endpoint, disabled when unset
+    #[serde(default)]
+    pub metrics_port: Option<u32>,
+    /// Blocks below the tip whose transactions are kept, older ones are
+    /// deleted. At least `MIN_PRUNE_DEPTH`, everything is kept when unset.
+    #[serde(default)]
+    pub prune_depth: Option<u32>,
+    /// Whether outbound P2P connections try the encrypted transport, peers
+    /// not supporting it stay plaintext
+    #[serde(default = "default_p2p_encryption")]
+    pub p2p_encryption: bool,
+    /// Identity keys peers must announce in the encrypted transport, as
+    /// `addr` and `key` pairs. Others are pinned to the first key they announce.
+    #[serde(default)]
+    pub p2p_peer_keys: Vec<PeerKey>,
+    /// Directory the export RPCs write to, `exports` in the data directory
+    /// when unset
+    #[serde(default)]
+    pub export_dir: Option<String>,
+    /// Hash of the chain state snapshot `loadchainstate` accepts, snapshots
+    /// can't be loaded when unset
+    #[serde(default)]
+    pub chainstate_hash: Option<String>,
+    /// Blocks the chain must include, as `height` and `hash` pairs. Forks
+    /// below the last one reached are refused.
+    #[serde(default)]
+    pub checkpoints: Vec<Checkpoint>,
+    /// Block whose ancestors are synced without checking their signatures
+    #[serde(default)]
+    pub assume_valid: Option<String>,
+    /// Port streaming chain and mempool events as JSON lines, disabled when unset
+    #[serde(default)]
+    pub events_port: Option<u32>,
+    /// RPC credentials, a cookie file in the data directory is used unless
+    /// both are set
+    #[serde(default)]
+    pub rpc_user: Option<String>,
+    #[serde(default)]
+    pub rpc_password: Option<Secret>,
+    /// Client addresses or subnets like `10.0.0.0/8` allowed to call RPC,
+    /// localhost when unset
+    #[serde(default = "access::default_allow_ips")]
+    pub rpc_allow_ips: Vec<String>,
+    /// Requests per second allowed to each RPC client, unlimited when unset
+    #[serde(default)]
+    pub rpc_rate_limit: Option<u32>,
+    /// Disables the RPC methods that sign with the wallet or change the node's state
+    #[serde(default)]
+    pub rpc_read_only: bool,
+}
+
+/// A setting kept out of the logs, its Debug output is redacted
+#[derive(Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"<redacted>\"")
+    }
+}
+
+fn default_log_level() -> LevelFilter {
+    LevelFilter::Info
+}
+
+fn default_p2p_encryption() -> bool {
+    true
+}
+
+/// Levels are plain strings in config files and environment variables
+fn deserialize_log_level<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<LevelFilter, D::Error> {
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
 }
 
 impl Settings {
@@ -0,0 +1,128 @@
+use std::{
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+use log::{info, warn};
+
+/// Stop signal shared by the node's threads, raised by Ctrl-C, SIGTERM or the
+/// `stop` RPC. Threads check it between units of work and return once it is set.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn request(&self) {
+        let (requested, condvar) = &*self.requested;
+        *requested.lock().unwrap() = true;
+        condvar.notify_all();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.requested.0.lock().unwrap()
+    }
+
+    /// Blocks until shutdown is requested
+    pub fn wait(&self) {
+        let (requested, condvar) = &*self.requested;
+        let _requested = condvar
+            .wait_while(requested.lock().unwrap(), |requested| !*requested)
+            .unwrap();
+    }
+
+    /// Sleeps for `timeout` unless shutdown is requested meanwhile, returns
+    /// whether it was, for loops that poll between iterations.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (requested, condvar) = &*self.requested;
+        let (requested, _) = condvar
+            .wait_timeout_while(requested.lock().unwrap(), timeout, |requested| !*requested)
+            .unwrap();
+        *requested
+    }
+
+    /// Unblocks the accept loop of the listener at `addr` on shutdown, the
+    /// loop is expected to check `is_requested` after every connection.
+    pub fn wake_listener(&self, addr: SocketAddr) {
+        let shutdown = self.clone();
+        thread::spawn(move || {
+            shutdown.wait();
+            let _ = TcpStream::connect(addr);
+        });
+    }
+}
+
+/// Requests shutdown on Ctrl-C or SIGTERM
+pub fn handle_signals(shutdown: Shutdown) -> std::io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    thread::spawn(move || {
+        runtime.block_on(async {
+            #[cfg(unix)]
+            {
+                use tokio::signal::unix::{signal, SignalKind};
+
+                let mut terminate = match signal(SignalKind::terminate()) {
+                    Ok(terminate) => terminate,
+                    Err(e) => {
+                        warn!("Can't listen for SIGTERM error={e}");
+                        let _ = tokio::signal::ctrl_c().await;
+                        return;
+                    }
+                };
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            #[cfg(not(unix))]
+            let _ = tokio::signal::ctrl_c().await;
+        });
+
+        info!("Shutdown signal received");
+        shutdown.request();
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_request_wakes_waiters() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.wait_timeout(Duration::from_millis(10)));
+
+        let waiter = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || shutdown.wait())
+        };
+        shutdown.request();
+        waiter.join().unwrap();
+
+        assert!(shutdown.is_requested());
+        assert!(shutdown.wait_timeout(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_wake_listener() {
+        let shutdown = Shutdown::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        shutdown.wake_listener(listener.local_addr().unwrap());
+
+        shutdown.request();
+        assert!(listener.incoming().next().is_some());
+    }
+}
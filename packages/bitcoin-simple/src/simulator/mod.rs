@@ -0,0 +1,315 @@
+//! In-process network of nodes talking P2P over localhost, for integration tests.
+//!
+//! Every node runs its P2P server and relay threads like `bitcoind` does, with
+//! its own data directory, while mining and sending are driven by the test.
+
+use std::{
+    fs,
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+
+use crate::{
+    block::Block,
+    crypto::{self, Address},
+    miner,
+    node::Node,
+    p2p::{self, server::P2pData, ResultUnit},
+    shutdown::Shutdown,
+    tx::SignedTransaction,
+};
+
+static NEXT_NETWORK_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub struct SimNode {
+    pub node: Arc<Mutex<Node>>,
+    pub p2p_data: Arc<Mutex<P2pData>>,
+    pub addr: String,
+    pub data_dir: String,
+    miner_interrupt_tx: mpsc::Sender<()>,
+    shutdown: Shutdown,
+}
+
+impl SimNode {
+    fn start(data_dir: String) -> Self {
+        fs::create_dir_all(&data_dir).expect("Can't create data directory");
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .expect("No free port")
+            .to_string();
+
+        let (block_tx, block_rx) = mpsc::channel();
+        let (transaction_tx, transaction_rx) = mpsc::channel();
+        let (miner_interrupt_tx, _) = mpsc::channel();
+
+        let mut node = Node::new(
+            block_tx,
+            transaction_tx,
+            &data_dir,
+            crypto::Network::Regtest,
+        );
+        // Scenarios spend mining rewards right away
+        node.coinbase_maturity = 0;
+        let p2p_data = Arc::new(Mutex::new(P2pData::new(&addr, node.store.clone())));
+        let node = Arc::new(Mutex::new(node));
+
+        let shutdown = Shutdown::new();
+        let receiver_p2p_data = p2p_data.clone();
+        let receiver_shutdown = shutdown.clone();
+        thread::spawn(move || {
+            if let Err(e) = p2p::run_receiver(
+                receiver_p2p_data,
+                block_rx,
+                transaction_rx,
+                receiver_shutdown,
+            ) {
+                warn!("Simulated node receiver stopped error={e}");
+            }
+        });
+
+        let server_node = node.clone();
+        let server_p2p_data = p2p_data.clone();
+        let server_addr = addr.clone();
+        let server_miner_interrupt_tx = miner_interrupt_tx.clone();
+        let server_shutdown = shutdown.clone();
+        thread::spawn(move || {
+            if let Err(e) = p2p::run(
+                server_node,
+                server_p2p_data,
+                server_addr,
+                server_miner_interrupt_tx,
+                server_shutdown,
+            ) {
+                warn!("Simulated node P2P server stopped error={e}");
+            }
+        });
+
+        while TcpStream::connect(&addr).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        Self {
+            node,
+            p2p_data,
+            addr,
+            data_dir,
+            miner_interrupt_tx,
+            shutdown,
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        self.node.lock().unwrap().address()
+    }
+
+    pub fn tip(&self) -> Option<String> {
+        let node = self.node.lock().unwrap();
+        node.get_latest_block().unwrap().map(|b| b.hash)
+    }
+
+    pub fn height(&self) -> u32 {
+        let node = self.node.lock().unwrap();
+        node.store.get_latest_block_number().unwrap()
+    }
+
+    pub fn balance(&self, address: Address) -> u32 {
+        let node = self.node.lock().unwrap();
+        node.store.get_balance(address).unwrap().unwrap_or_default()
+    }
+
+    pub fn has_tx(&self, tx_id: &str) -> bool {
+        self.node.lock().unwrap().mempool.contains_key(tx_id)
+    }
+}
+
+/// A set of nodes connected to each other over localhost.
+pub struct Network {
+    pub nodes: Vec<SimNode>,
+    root_dir: PathBuf,
+}
+
+impl Network {
+    /// Starts `size` nodes with empty chains and no peers.
+    pub fn new(size: usize) -> Self {
+        let root_dir = std::env::temp_dir().join(format!(
+            "bitcoind-sim-{}-{}",
+            std::process::id(),
+            NEXT_NETWORK_ID.fetch_add(1, Ordering::SeqCst)
+        ));
+
+        let nodes = (0..size)
+            .map(|i| SimNode::start(format!("{}/node{}/", root_dir.display(), i)))
+            .collect();
+
+        Self { nodes, root_dir }
+    }
+
+    pub fn node(&self, i: usize) -> &SimNode {
+        &self.nodes[i]
+    }
+
+    /// Connects `a` to `b` the way a bootstrap node is joined, syncing both ways.
+    pub fn connect(&self, a: usize, b: usize) -> ResultUnit {
+        let (a, b) = (&self.nodes[a], &self.nodes[b]);
+        p2p::init_node(
+            a.node.clone(),
+            a.p2p_data.clone(),
+            a.miner_interrupt_tx.clone(),
+            &b.addr,
+            &a.addr,
+        )
+    }
+
+    pub fn connect_all(&self) -> ResultUnit {
+        for a in 0..self.nodes.len() {
+            for b in a + 1..self.nodes.len() {
+                self.connect(a, b)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Mines a block on top of the node's chain and relays it like the miner does.
+    pub fn mine(&self, i: usize) -> Result<Block, String> {
+        let mut node = self.nodes[i].node.lock().unwrap();
+        let block = miner::mine(node.get_proposed_block()?);
+        node.receive_block(&block)?;
+
+        Ok(block)
+    }
+
+    pub fn send(&self, from: usize, to: usize, amount: u32) -> Result<SignedTransaction, String> {
+        let to = self.nodes[to].address();
+        self.nodes[from]
+            .node
+            .lock()
+            .unwrap()
+            .send_tx(to, amount, crypto::SignatureScheme::Ecdsa)
+    }
+
+    /// Splits the network so nodes only reach the ones in their own group.
+    pub fn partition(&self, groups: &[&[usize]]) {
+        let group_of = |i: usize| groups.iter().position(|g| g.contains(&i));
+
+        for (a, node_a) in self.nodes.iter().enumerate() {
+            for (b, node_b) in self.nodes.iter().enumerate() {
+                if a != b && group_of(a) != group_of(b) {
+                    p2p::ban_peer(&node_a.p2p_data, &node_b.addr);
+                }
+            }
+        }
+    }
+
+    /// Lifts all partitions and reconnects every pair of nodes.
+    pub fn heal(&self) -> ResultUnit {
+        for node_a in &self.nodes {
+            for node_b in &self.nodes {
+                p2p::unban_peer(&node_a.p2p_data, &node_b.addr);
+            }
+        }
+
+        self.connect_all()
+    }
+
+    /// Waits until every node has the same chain tip, which is returned.
+    pub fn wait_for_convergence(&self, timeout: Duration) -> Result<Option<String>, String> {
+        self.wait_until(timeout, || {
+            let tip = self.nodes[0].tip();
+            self.nodes.iter().all(|n| n.tip() == tip)
+        })?;
+
+        Ok(self.nodes[0].tip())
+    }
+
+    pub fn wait_until(
+        &self,
+        timeout: Duration,
+        condition: impl Fn() -> bool,
+    ) -> Result<(), String> {
+        let started = Instant::now();
+        while !condition() {
+            if started.elapsed() > timeout {
+                let tips: Vec<_> = self.nodes.iter().map(|n| (n.height(), n.tip())).collect();
+                return Err(format!("Timed out waiting for the network: {tips:?}"));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Network {
+    fn drop(&mut self) {
+        self.nodes.iter().for_each(|n| n.shutdown.request());
+        let _ = fs::remove_dir_all(&self.root_dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn test_blocks_and_transactions_propagate() {
+        let network = Network::new(3);
+        network.mine(0).unwrap();
+        network.connect_all().unwrap();
+        network.wait_for_convergence(TIMEOUT).unwrap();
+
+        let tx = network.send(0, 1, 100).unwrap();
+        network
+            .wait_until(TIMEOUT, || network.node(2).has_tx(&tx.tx_id()))
+            .unwrap();
+
+        let block = network.mine(2).unwrap();
+        let tip = network.wait_for_convergence(TIMEOUT).unwrap();
+
+        assert_eq!(tip, Some(block.hash));
+        for node in &network.nodes {
+            assert_eq!(node.balance(network.node(1).address()), 100);
+            assert!(!node.has_tx(&tx.tx_id()));
+        }
+    }
+
+    #[test]
+    fn test_partitioned_network_converges_on_longest_chain() {
+        let network = Network::new(3);
+        network.mine(0).unwrap();
+        network.connect_all().unwrap();
+        network.wait_for_convergence(TIMEOUT).unwrap();
+
+        network.partition(&[&[0], &[1, 2]]);
+        network.mine(0).unwrap();
+        network.mine(1).unwrap();
+        let longest = network.mine(1).unwrap();
+        network
+            .wait_until(TIMEOUT, || {
+                network.node(2).tip() == Some(longest.hash.clone())
+            })
+            .unwrap();
+        assert_eq!(network.node(0).height(), 2);
+
+        network.heal().unwrap();
+        let tip = network.wait_for_convergence(TIMEOUT).unwrap();
+
+        assert_eq!(tip, Some(longest.hash));
+        // Node 0's orphaned coinbase is gone from every node's balances
+        let reward = network.node(0).node.lock().unwrap().get_block_reward(1);
+        for node in &network.nodes {
+            assert_eq!(node.height(), 3);
+            assert_eq!(node.balance(network.node(0).address()), reward);
+        }
+    }
+}
@@ -5,6 +5,7 @@ use super::Store;
 static BLOCKS_DB_PATH: &str = "./blocks";
 static BLOCKS_METADATA_DB_PATH: &str = "./blocksmetadata";
 static BALANCES_DB_PATH: &str = "./balances";
+static ADDRESS_TXS_DB_PATH: &str = "./addresstxs";
 
 pub fn blocks(read_only: bool, data_dir: &str) -> Store {
     open(BLOCKS_DB_PATH, read_only, data_dir)
@@ -18,6 +19,10 @@ pub fn balances(read_only: bool, data_dir: &str) -> Store {
     open(BALANCES_DB_PATH, read_only, data_dir)
 }
 
+pub fn address_txs(read_only: bool, data_dir: &str) -> Store {
+    open(ADDRESS_TXS_DB_PATH, read_only, data_dir)
+}
+
 fn open(path: &str, read_only: bool, data_dir: &str) -> Store {
     let full_path = format!("{}{}", data_dir, path);
 
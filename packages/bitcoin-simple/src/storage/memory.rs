@@ -0,0 +1,233 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+use crate::{
+    block::{Block, BlockHeader},
+    crypto::Address,
+};
+
+use super::{ChainStore, TxLocation};
+
+#[derive(Default)]
+struct MemoryChain {
+    blocks: HashMap<String, Block>,
+    /// Headers of pruned blocks
+    headers: HashMap<String, BlockHeader>,
+    prune_height: u32,
+    block_heights: HashMap<String, u32>,
+    block_hashes: BTreeMap<u32, String>,
+    latest_block_hash: Option<String>,
+    balances: HashMap<Address, u32>,
+    supply: u64,
+    address_txs: HashMap<Address, BTreeMap<(u32, u32), TxLocation>>,
+    tx_heights: HashMap<String, u32>,
+    spends: HashMap<(Address, String), String>,
+    index_version: u32,
+}
+
+/// Chain state kept in memory only, for tests and throwaway nodes.
+#[derive(Default)]
+pub struct MemoryStore {
+    chain: RwLock<MemoryChain>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ChainStore for MemoryStore {
+    fn get_block(&self, block_hash: &str) -> Result<Option<Block>, String> {
+        Ok(self.chain.read().unwrap().blocks.get(block_hash).cloned())
+    }
+
+    fn add_block(&self, block: &Block) -> Result<(), String> {
+        let mut chain = self.chain.write().unwrap();
+        chain.blocks.insert(block.hash.clone(), block.clone());
+
+        Ok(())
+    }
+
+    fn get_header(&self, block_hash: &str) -> Result<Option<BlockHeader>, String> {
+        let chain = self.chain.read().unwrap();
+        Ok(chain
+            .blocks
+            .get(block_hash)
+            .map(Block::header)
+            .or_else(|| chain.headers.get(block_hash).cloned()))
+    }
+
+    fn add_header(&self, header: &BlockHeader) -> Result<(), String> {
+        let mut chain = self.chain.write().unwrap();
+        chain.headers.insert(header.hash.clone(), header.clone());
+
+        Ok(())
+    }
+
+    fn prune_block(&self, block_hash: &str) -> Result<(), String> {
+        let mut chain = self.chain.write().unwrap();
+        if let Some(block) = chain.blocks.remove(block_hash) {
+            chain.headers.insert(block.hash.clone(), block.header());
+        }
+
+        Ok(())
+    }
+
+    fn get_prune_height(&self) -> Result<u32, String> {
+        Ok(self.chain.read().unwrap().prune_height)
+    }
+
+    fn set_prune_height(&self, height: u32) -> Result<(), String> {
+        self.chain.write().unwrap().prune_height = height;
+
+        Ok(())
+    }
+
+    fn get_block_hash(&self, block_number: u32) -> Result<Option<String>, String> {
+        let chain = self.chain.read().unwrap();
+        Ok(chain.block_hashes.get(&block_number).cloned())
+    }
+
+    fn get_block_hashes(&self) -> Result<Vec<String>, String> {
+        let chain = self.chain.read().unwrap();
+        Ok(chain.block_hashes.values().cloned().collect())
+    }
+
+    fn get_block_height(&self, block_hash: &str) -> Result<Option<u32>, String> {
+        let chain = self.chain.read().unwrap();
+        Ok(chain.block_heights.get(block_hash).copied())
+    }
+
+    fn get_latest_block_hash(&self) -> Result<Option<String>, String> {
+        Ok(self.chain.read().unwrap().latest_block_hash.clone())
+    }
+
+    fn set_latest_block_hash(&self, block_hash: &str, height: u32) -> Result<(), String> {
+        let mut chain = self.chain.write().unwrap();
+        chain.latest_block_hash = Some(block_hash.to_string());
+        chain.block_heights.insert(block_hash.to_string(), height);
+        chain.block_hashes.insert(height, block_hash.to_string());
+
+        Ok(())
+    }
+
+    fn unset_latest_block_hash(
+        &self,
+        block_hash: &str,
+        height: u32,
+        prev_block_hash: Option<&str>,
+    ) -> Result<(), String> {
+        let mut chain = self.chain.write().unwrap();
+        chain.latest_block_hash = prev_block_hash.map(String::from);
+        chain.block_heights.remove(block_hash);
+        chain.block_hashes.remove(&height);
+
+        Ok(())
+    }
+
+    fn get_balance(&self, address: Address) -> Result<Option<u32>, String> {
+        Ok(self.chain.read().unwrap().balances.get(&address).copied())
+    }
+
+    fn set_balance(&self, address: Address, balance: u32) -> Result<(), String> {
+        let mut chain = self.chain.write().unwrap();
+        chain.balances.insert(address, balance);
+
+        Ok(())
+    }
+
+    fn get_balances(&self) -> Result<HashMap<Address, u32>, String> {
+        Ok(self.chain.read().unwrap().balances.clone())
+    }
+
+    fn get_supply(&self) -> Result<u64, String> {
+        Ok(self.chain.read().unwrap().supply)
+    }
+
+    fn set_supply(&self, supply: u64) -> Result<(), String> {
+        self.chain.write().unwrap().supply = supply;
+
+        Ok(())
+    }
+
+    fn add_address_tx(&self, address: Address, location: &TxLocation) -> Result<(), String> {
+        let mut chain = self.chain.write().unwrap();
+        chain
+            .address_txs
+            .entry(address)
+            .or_default()
+            .insert((location.height, location.index), location.clone());
+
+        Ok(())
+    }
+
+    fn remove_address_tx(&self, address: Address, location: &TxLocation) -> Result<(), String> {
+        let mut chain = self.chain.write().unwrap();
+        if let Some(txs) = chain.address_txs.get_mut(&address) {
+            txs.remove(&(location.height, location.index));
+        }
+
+        Ok(())
+    }
+
+    fn get_address_txs(&self, address: Address) -> Result<Vec<TxLocation>, String> {
+        let chain = self.chain.read().unwrap();
+        let locations = chain
+            .address_txs
+            .get(&address)
+            .into_iter()
+            .flat_map(|txs| txs.values().cloned())
+            .collect();
+
+        Ok(locations)
+    }
+
+    fn get_tx_height(&self, tx_id: &str) -> Result<Option<u32>, String> {
+        Ok(self.chain.read().unwrap().tx_heights.get(tx_id).copied())
+    }
+
+    fn add_tx_height(&self, tx_id: &str, height: u32) -> Result<(), String> {
+        let mut chain = self.chain.write().unwrap();
+        chain.tx_heights.insert(tx_id.to_string(), height);
+
+        Ok(())
+    }
+
+    fn remove_tx_height(&self, tx_id: &str) -> Result<(), String> {
+        self.chain.write().unwrap().tx_heights.remove(tx_id);
+
+        Ok(())
+    }
+
+    fn get_spend(&self, sender: Address, spend_id: &str) -> Result<Option<String>, String> {
+        let chain = self.chain.read().unwrap();
+        Ok(chain.spends.get(&(sender, spend_id.to_string())).cloned())
+    }
+
+    fn add_spend(&self, sender: Address, spend_id: &str, tx_id: &str) -> Result<(), String> {
+        let mut chain = self.chain.write().unwrap();
+        chain
+            .spends
+            .insert((sender, spend_id.to_string()), tx_id.to_string());
+
+        Ok(())
+    }
+
+    fn remove_spend(&self, sender: Address, spend_id: &str) -> Result<(), String> {
+        let mut chain = self.chain.write().unwrap();
+        chain.spends.remove(&(sender, spend_id.to_string()));
+
+        Ok(())
+    }
+
+    fn get_index_version(&self) -> Result<u32, String> {
+        Ok(self.chain.read().unwrap().index_version)
+    }
+
+    fn set_index_version(&self, version: u32) -> Result<(), String> {
+        self.chain.write().unwrap().index_version = version;
+
+        Ok(())
+    }
+}
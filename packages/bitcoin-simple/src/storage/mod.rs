@@ -1,13 +1,186 @@
 use std::collections::HashMap;
-
 use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
 
-use crate::{block::Block, crypto::key::PublicKey};
+use crate::{
+    block::{Block, BlockHeader},
+    crypto::Address,
+};
 
 pub mod db;
+pub mod memory;
+pub mod rocks;
+
+pub use memory::MemoryStore;
+pub use rocks::RocksStore;
 
 pub type Store = rocksdb::DB;
 
+/// Blocks metadata key of the issued supply
+const TOTAL_SUPPLY_KEY: &str = "total_supply";
+/// Blocks metadata key of the height blocks are pruned up to
+const PRUNE_HEIGHT_KEY: &str = "prune_height";
+/// Address index key of its format version
+const INDEX_VERSION_KEY: &str = "index_version";
+
+/// Position of a transaction in the active chain, an entry of the address index
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TxLocation {
+    pub height: u32,
+    /// Position in the block, 0 being the coinbase
+    pub index: u32,
+    pub tx_id: String,
+    /// What the transaction spends, on the entry of the address sending it,
+    /// see `Transaction::spend_id`
+    pub spends: Option<String>,
+}
+
+/// Handle to the chain state shared by the node, RPC and P2P.
+pub type SharedStore = Arc<dyn ChainStore>;
+
+/// Storage backend for blocks, chain metadata and balances.
+pub trait ChainStore: Send + Sync {
+    fn get_block(&self, block_hash: &str) -> Result<Option<Block>, String>;
+
+    fn add_block(&self, block: &Block) -> Result<(), String>;
+
+    /// Header of a stored block, including pruned ones
+    fn get_header(&self, block_hash: &str) -> Result<Option<BlockHeader>, String>;
+
+    /// Stores the header of a block whose transactions aren't kept, as if pruned
+    fn add_header(&self, header: &BlockHeader) -> Result<(), String>;
+
+    /// Deletes the block's transactions, keeping its header
+    fn prune_block(&self, block_hash: &str) -> Result<(), String>;
+
+    /// Height the active chain's blocks are pruned up to, 0 when none are
+    fn get_prune_height(&self) -> Result<u32, String>;
+
+    fn set_prune_height(&self, height: u32) -> Result<(), String>;
+
+    fn get_block_hash(&self, block_number: u32) -> Result<Option<String>, String>;
+
+    /// Hashes of the blocks in the active chain, in height order
+    fn get_block_hashes(&self) -> Result<Vec<String>, String>;
+
+    fn get_block_height(&self, block_hash: &str) -> Result<Option<u32>, String>;
+
+    fn get_latest_block_hash(&self) -> Result<Option<String>, String>;
+
+    fn set_latest_block_hash(&self, block_hash: &str, height: u32) -> Result<(), String>;
+
+    /// Moves the chain tip back from `block_hash` at `height` to its parent.
+    fn unset_latest_block_hash(
+        &self,
+        block_hash: &str,
+        height: u32,
+        prev_block_hash: Option<&str>,
+    ) -> Result<(), String>;
+
+    fn get_balance(&self, address: Address) -> Result<Option<u32>, String>;
+
+    fn set_balance(&self, address: Address, balance: u32) -> Result<(), String>;
+
+    fn get_balances(&self) -> Result<HashMap<Address, u32>, String>;
+
+    /// Coins issued by the coinbases of the active chain
+    fn get_supply(&self) -> Result<u64, String>;
+
+    fn set_supply(&self, supply: u64) -> Result<(), String>;
+
+    /// Records that the transaction at `location` sends to or from `address`
+    fn add_address_tx(&self, address: Address, location: &TxLocation) -> Result<(), String>;
+
+    fn remove_address_tx(&self, address: Address, location: &TxLocation) -> Result<(), String>;
+
+    /// Transactions of the active chain touching `address`, oldest first
+    fn get_address_txs(&self, address: Address) -> Result<Vec<TxLocation>, String>;
+
+    /// Height of the active chain's block including the transaction, kept
+    /// when the block is pruned
+    fn get_tx_height(&self, tx_id: &str) -> Result<Option<u32>, String>;
+
+    fn add_tx_height(&self, tx_id: &str, height: u32) -> Result<(), String>;
+
+    fn remove_tx_height(&self, tx_id: &str) -> Result<(), String>;
+
+    /// Id of the transaction of the active chain spending `spend_id` from
+    /// `sender`, see `Transaction::spend_id`. Kept when the block is pruned.
+    fn get_spend(&self, sender: Address, spend_id: &str) -> Result<Option<String>, String>;
+
+    fn add_spend(&self, sender: Address, spend_id: &str, tx_id: &str) -> Result<(), String>;
+
+    fn remove_spend(&self, sender: Address, spend_id: &str) -> Result<(), String>;
+
+    /// Format of the address and transaction height indexes, 0 when they
+    /// predate it being recorded
+    fn get_index_version(&self) -> Result<u32, String>;
+
+    fn set_index_version(&self, version: u32) -> Result<(), String>;
+
+    /// Persists pending writes, called on shutdown
+    fn flush(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn get_latest_block_number(&self) -> Result<u32, String> {
+        let latest_block_hash = match self.get_latest_block_hash()? {
+            Some(hash) => hash,
+            None => return Ok(0),
+        };
+
+        self.get_block_height(&latest_block_hash)
+            .map(|h| h.unwrap_or_default())
+    }
+
+    fn get_latest_block(&self) -> Result<Option<Block>, String> {
+        match self.get_latest_block_hash()? {
+            Some(block_hash) => self.get_block(&block_hash),
+            None => Ok(None),
+        }
+    }
+
+    /// Block of the active chain including the transaction, searched from the tip
+    fn find_transaction_block(&self, tx_id: &str) -> Result<Option<Block>, String> {
+        for block_number in (1..=self.get_latest_block_number()?).rev() {
+            let block = match self.get_block_hash(block_number)? {
+                Some(block_hash) => self.get_block(&block_hash)?,
+                None => None,
+            };
+
+            if let Some(block) =
+                block.filter(|b| b.transactions.iter().any(|tx| tx.tx_id() == tx_id))
+            {
+                return Ok(Some(block));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Headers of the active chain above `start_height`, at most `limit` of them
+    fn get_headers(&self, start_height: u32, limit: u32) -> Result<Vec<BlockHeader>, String> {
+        let end_height = self
+            .get_latest_block_number()?
+            .min(start_height.saturating_add(limit));
+
+        let mut headers = vec![];
+        for block_number in start_height + 1..=end_height {
+            let block_hash = self
+                .get_block_hash(block_number)?
+                .ok_or_else(|| format!("Missing block at height {block_number}"))?;
+            let header = self
+                .get_header(&block_hash)?
+                .ok_or_else(|| format!("Missing block {block_hash}"))?;
+            headers.push(header);
+        }
+
+        Ok(headers)
+    }
+}
+
 pub fn get_block_hash(db: &Store, block_number: u32) -> Result<Option<String>, String> {
     db.get(block_number.to_string())
         .map_err(|e| e.to_string())
@@ -23,7 +196,8 @@ pub fn get_block_hashes(db: &Store) -> Result<Vec<String>, String> {
         let block_hash =
             String::from_utf8(iter.key().unwrap().to_vec()).map_err(|e| e.to_string())?;
 
-        if block_hash != "latest_block_hash" {
+        if !["latest_block_hash", TOTAL_SUPPLY_KEY, PRUNE_HEIGHT_KEY].contains(&block_hash.as_str())
+        {
             let block_number_s =
                 String::from_utf8(iter.value().unwrap().to_vec()).map_err(|e| e.to_string())?;
             if let Ok(block_number) = block_number_s.parse::<u32>() {
@@ -34,7 +208,7 @@ pub fn get_block_hashes(db: &Store) -> Result<Vec<String>, String> {
         iter.next();
     }
 
-    blocks.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    blocks.sort_unstable_by_key(|a| a.0);
 
     let block_hashes = blocks.iter().map(|x| x.1.clone()).collect();
 
@@ -51,6 +225,37 @@ pub fn get_block(db: &Store, block_hash: &str) -> Result<Option<Block>, String>
     }
 }
 
+/// Blocks key of a pruned block's header
+fn header_key(block_hash: &str) -> String {
+    format!("header/{block_hash}")
+}
+
+pub fn get_header(db: &Store, block_hash: &str) -> Result<Option<BlockHeader>, String> {
+    if let Some(block) = get_block(db, block_hash)? {
+        return Ok(Some(block.header()));
+    }
+
+    match db.get(header_key(block_hash))? {
+        Some(header) => serde_json::from_slice(&header).map_err(|e| e.to_string()),
+        None => Ok(None),
+    }
+}
+
+pub fn add_header(db: &Store, header: &BlockHeader) -> Result<(), String> {
+    let header_json = serde_json::to_string(header).map_err(|e| e.to_string())?;
+    db.put(header_key(&header.hash), header_json)
+        .map_err(|e| e.to_string())
+}
+
+pub fn prune_block(db: &Store, block_hash: &str) -> Result<(), String> {
+    let Some(block) = get_block(db, block_hash)? else {
+        return Ok(());
+    };
+
+    add_header(db, &block.header())?;
+    db.delete(block_hash).map_err(|e| e.to_string())
+}
+
 pub fn get_latest_block_hash(db: &Store) -> Result<Option<String>, String> {
     db.get(b"latest_block_hash")
         .map(|hash| hash.map(|b| String::from_utf8(b).unwrap()))
@@ -76,44 +281,215 @@ pub fn set_latest_block_hash(db: &Store, block_hash: &str, height: u32) -> Resul
     Ok(())
 }
 
+/// Moves the chain tip back from `block_hash` at `height` to its parent.
+pub fn unset_latest_block_hash(
+    db: &Store,
+    block_hash: &str,
+    height: u32,
+    prev_block_hash: Option<&str>,
+) -> Result<(), String> {
+    match prev_block_hash {
+        Some(prev_block_hash) => db.put(b"latest_block_hash", prev_block_hash),
+        None => db.delete(b"latest_block_hash"),
+    }
+    .map_err(|e| e.to_string())?;
+    db.delete(block_hash).map_err(|e| e.to_string())?;
+    db.delete(height.to_string()).map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
 pub fn get_block_height(db: &Store, block: &str) -> Result<Option<u32>, String> {
     db.get(block)
         .map(|hash| hash.and_then(|b| String::from_utf8(b).unwrap().parse::<u32>().ok()))
         .map_err(|e| e.to_string())
 }
 
-pub fn set_balance(db: &Store, public_key: PublicKey, balance: u32) -> Result<(), String> {
-    db.put(public_key.to_string(), balance.to_string())
+pub fn set_balance(db: &Store, address: Address, balance: u32) -> Result<(), String> {
+    db.put(address.to_string(), balance.to_string())
         .map_err(|e| e.to_string())?;
 
     Ok(())
 }
 
-pub fn get_balance(db: &Store, public_key: PublicKey) -> Result<Option<u32>, String> {
-    db.get(public_key.to_string())
+pub fn get_balance(db: &Store, address: Address) -> Result<Option<u32>, String> {
+    db.get(address.to_string())
         .map(|hash| hash.and_then(|b| String::from_utf8(b).unwrap().parse::<u32>().ok()))
         .map_err(|e| e.to_string())
 }
 
-pub fn get_balances(db: &Store) -> Result<HashMap<PublicKey, u32>, String> {
+pub fn get_balances(db: &Store) -> Result<HashMap<Address, u32>, String> {
     let mut balances = HashMap::new();
     let mut iter = db.raw_iterator();
     iter.seek_to_first();
 
     while iter.valid() {
-        let public_key =
-            String::from_utf8(iter.key().unwrap().to_vec()).map_err(|e| e.to_string())?;
+        let address = String::from_utf8(iter.key().unwrap().to_vec()).map_err(|e| e.to_string())?;
         let balance =
             String::from_utf8(iter.value().unwrap().to_vec()).map_err(|e| e.to_string())?;
         let balance = balance.parse::<u32>().unwrap();
 
-        balances.insert(PublicKey::from_str(&public_key).unwrap(), balance);
+        balances.insert(Address::from_str(&address)?, balance);
         iter.next();
     }
 
     Ok(balances)
 }
 
+pub fn get_supply(db: &Store) -> Result<u64, String> {
+    match db.get(TOTAL_SUPPLY_KEY).map_err(|e| e.to_string())? {
+        Some(supply) => String::from_utf8(supply)
+            .map_err(|e| e.to_string())?
+            .parse()
+            .map_err(|e: std::num::ParseIntError| e.to_string()),
+        None => Ok(0),
+    }
+}
+
+pub fn set_supply(db: &Store, supply: u64) -> Result<(), String> {
+    db.put(TOTAL_SUPPLY_KEY, supply.to_string())
+        .map_err(|e| e.to_string())
+}
+
+pub fn get_prune_height(db: &Store) -> Result<u32, String> {
+    match db.get(PRUNE_HEIGHT_KEY).map_err(|e| e.to_string())? {
+        Some(height) => String::from_utf8(height)
+            .map_err(|e| e.to_string())?
+            .parse()
+            .map_err(|e: std::num::ParseIntError| e.to_string()),
+        None => Ok(0),
+    }
+}
+
+pub fn set_prune_height(db: &Store, height: u32) -> Result<(), String> {
+    db.put(PRUNE_HEIGHT_KEY, height.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Keys sort by height then position, so an address' entries are contiguous and in chain order
+fn address_tx_key(address: Address, location: &TxLocation) -> String {
+    format!("{address}/{:010}/{:010}", location.height, location.index)
+}
+
+/// Values are the transaction id, followed by what it spends on the sender's entry
+pub fn add_address_tx(db: &Store, address: Address, location: &TxLocation) -> Result<(), String> {
+    let value = match &location.spends {
+        Some(spends) => format!("{}/{spends}", location.tx_id),
+        None => location.tx_id.clone(),
+    };
+    db.put(address_tx_key(address, location), value)
+        .map_err(|e| e.to_string())
+}
+
+pub fn remove_address_tx(
+    db: &Store,
+    address: Address,
+    location: &TxLocation,
+) -> Result<(), String> {
+    db.delete(address_tx_key(address, location))
+        .map_err(|e| e.to_string())
+}
+
+pub fn get_address_txs(db: &Store, address: Address) -> Result<Vec<TxLocation>, String> {
+    let prefix = format!("{address}/");
+    let mut locations = Vec::new();
+    let mut iter = db.raw_iterator();
+    iter.seek(&prefix);
+
+    while iter.valid() {
+        let key = String::from_utf8(iter.key().unwrap().to_vec()).map_err(|e| e.to_string())?;
+        let Some(position) = key.strip_prefix(&prefix) else {
+            break;
+        };
+        let (height, index) = position
+            .split_once('/')
+            .and_then(|(height, index)| Some((height.parse().ok()?, index.parse().ok()?)))
+            .ok_or_else(|| format!("Invalid address index key {key}"))?;
+        let value = String::from_utf8(iter.value().unwrap().to_vec()).map_err(|e| e.to_string())?;
+        let (tx_id, spends) = match value.split_once('/') {
+            Some((tx_id, spends)) => (tx_id.to_string(), Some(spends.to_string())),
+            None => (value, None),
+        };
+
+        locations.push(TxLocation {
+            height,
+            index,
+            tx_id,
+            spends,
+        });
+        iter.next();
+    }
+
+    Ok(locations)
+}
+
+/// Transaction heights share the address index's database, no address is "tx"
+fn tx_height_key(tx_id: &str) -> String {
+    format!("tx/{tx_id}")
+}
+
+pub fn get_tx_height(db: &Store, tx_id: &str) -> Result<Option<u32>, String> {
+    match db.get(tx_height_key(tx_id)).map_err(|e| e.to_string())? {
+        Some(height) => String::from_utf8(height)
+            .map_err(|e| e.to_string())?
+            .parse()
+            .map(Some)
+            .map_err(|e: std::num::ParseIntError| e.to_string()),
+        None => Ok(None),
+    }
+}
+
+pub fn add_tx_height(db: &Store, tx_id: &str, height: u32) -> Result<(), String> {
+    db.put(tx_height_key(tx_id), height.to_string())
+        .map_err(|e| e.to_string())
+}
+
+pub fn remove_tx_height(db: &Store, tx_id: &str) -> Result<(), String> {
+    db.delete(tx_height_key(tx_id)).map_err(|e| e.to_string())
+}
+
+/// Spends also share it, no address is "spend" either
+fn spend_key(sender: Address, spend_id: &str) -> String {
+    format!("spend/{sender}/{spend_id}")
+}
+
+pub fn get_spend(db: &Store, sender: Address, spend_id: &str) -> Result<Option<String>, String> {
+    match db
+        .get(spend_key(sender, spend_id))
+        .map_err(|e| e.to_string())?
+    {
+        Some(tx_id) => String::from_utf8(tx_id)
+            .map(Some)
+            .map_err(|e| e.to_string()),
+        None => Ok(None),
+    }
+}
+
+pub fn add_spend(db: &Store, sender: Address, spend_id: &str, tx_id: &str) -> Result<(), String> {
+    db.put(spend_key(sender, spend_id), tx_id)
+        .map_err(|e| e.to_string())
+}
+
+pub fn remove_spend(db: &Store, sender: Address, spend_id: &str) -> Result<(), String> {
+    db.delete(spend_key(sender, spend_id))
+        .map_err(|e| e.to_string())
+}
+
+pub fn get_index_version(db: &Store) -> Result<u32, String> {
+    match db.get(INDEX_VERSION_KEY).map_err(|e| e.to_string())? {
+        Some(version) => String::from_utf8(version)
+            .map_err(|e| e.to_string())?
+            .parse()
+            .map_err(|e: std::num::ParseIntError| e.to_string()),
+        None => Ok(0),
+    }
+}
+
+pub fn set_index_version(db: &Store, version: u32) -> Result<(), String> {
+    db.put(INDEX_VERSION_KEY, version.to_string())
+        .map_err(|e| e.to_string())
+}
+
 pub fn get_latest_block_number(db: &Store) -> Result<u32, String> {
     let latest_block_hash = match get_latest_block_hash(db)? {
         Some(hash) => hash,
@@ -122,3 +498,133 @@ pub fn get_latest_block_number(db: &Store) -> Result<u32, String> {
 
     get_block_height(db, &latest_block_hash).map(|h| h.unwrap_or_default())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        crypto::{Address, KeyPair, Network},
+        tx,
+    };
+
+    use super::*;
+
+    fn block(hash: &str, prev_block: &str) -> Block {
+        let keypair = KeyPair::new();
+        Block {
+            hash: hash.to_string(),
+            prev_block: prev_block.to_string(),
+            timestamp: 0,
+            nonce: 0,
+            transactions: vec![tx::create_signed(
+                &keypair,
+                Address::from_public_key(&keypair.public_key, Network::Mainnet),
+                512,
+            )],
+        }
+    }
+
+    fn check_store(store: &dyn ChainStore) {
+        assert_eq!(store.get_latest_block_number(), Ok(0));
+        assert!(store.get_latest_block().unwrap().is_none());
+
+        for (height, (hash, prev)) in [("aa", "00"), ("bb", "aa")].into_iter().enumerate() {
+            store.add_block(&block(hash, prev)).unwrap();
+            store
+                .set_latest_block_hash(hash, height as u32 + 1)
+                .unwrap();
+        }
+
+        assert_eq!(store.get_latest_block_number(), Ok(2));
+        assert_eq!(store.get_latest_block().unwrap().unwrap().hash, "bb");
+        assert_eq!(store.get_block_hash(1), Ok(Some("aa".to_string())));
+        assert_eq!(store.get_block_height("bb"), Ok(Some(2)));
+        assert_eq!(store.get_block_hashes(), Ok(vec!["aa".into(), "bb".into()]));
+
+        store.unset_latest_block_hash("bb", 2, Some("aa")).unwrap();
+        assert_eq!(store.get_latest_block_number(), Ok(1));
+        assert_eq!(store.get_block_hashes(), Ok(vec!["aa".into()]));
+        // Disconnected blocks are kept
+        assert!(store.get_block("bb").unwrap().is_some());
+
+        let header = store.get_block("aa").unwrap().unwrap().header();
+        assert_eq!(store.get_prune_height(), Ok(0));
+        store.prune_block("aa").unwrap();
+        store.set_prune_height(1).unwrap();
+        assert!(store.get_block("aa").unwrap().is_none());
+        assert_eq!(store.get_header("aa"), Ok(Some(header.clone())));
+        assert_eq!(store.get_headers(0, 10), Ok(vec![header]));
+        assert_eq!(store.get_prune_height(), Ok(1));
+        assert_eq!(store.get_header("cc"), Ok(None));
+        let header = block("cc", "bb").header();
+        store.add_header(&header).unwrap();
+        assert!(store.get_block("cc").unwrap().is_none());
+        assert_eq!(store.get_header("cc"), Ok(Some(header)));
+
+        let address = Address::from_public_key(&KeyPair::new().public_key, Network::Mainnet);
+        assert_eq!(store.get_balance(address), Ok(None));
+        store.set_balance(address, 42).unwrap();
+        assert_eq!(store.get_balance(address), Ok(Some(42)));
+        assert_eq!(store.get_balances().unwrap().get(&address), Some(&42));
+
+        let other = Address::from_public_key(&KeyPair::new().public_key, Network::Mainnet);
+        let location = |height, index| TxLocation {
+            height,
+            index,
+            tx_id: format!("{height}-{index}"),
+            spends: (index > 0).then(|| format!("spend-{height}")),
+        };
+        for (height, index) in [(10, 0), (2, 1), (2, 0)] {
+            store
+                .add_address_tx(address, &location(height, index))
+                .unwrap();
+        }
+        store.add_address_tx(other, &location(3, 0)).unwrap();
+        assert_eq!(
+            store.get_address_txs(address),
+            Ok(vec![location(2, 0), location(2, 1), location(10, 0)])
+        );
+        store.remove_address_tx(address, &location(2, 1)).unwrap();
+        assert_eq!(
+            store.get_address_txs(address),
+            Ok(vec![location(2, 0), location(10, 0)])
+        );
+        assert_eq!(store.get_address_txs(other), Ok(vec![location(3, 0)]));
+        store.add_address_tx(address, &location(2, 1)).unwrap();
+
+        assert_eq!(store.get_spend(address, "spend-2"), Ok(None));
+        store.add_spend(address, "spend-2", "2-1").unwrap();
+        assert_eq!(store.get_spend(address, "spend-2"), Ok(Some("2-1".into())));
+        assert_eq!(store.get_spend(other, "spend-2"), Ok(None));
+        assert_eq!(store.get_address_txs(address).unwrap().len(), 3);
+        store.remove_spend(address, "spend-2").unwrap();
+        assert_eq!(store.get_spend(address, "spend-2"), Ok(None));
+
+        assert_eq!(store.get_tx_height("2-1"), Ok(None));
+
+        assert_eq!(store.get_index_version(), Ok(0));
+        store.set_index_version(1).unwrap();
+        assert_eq!(store.get_index_version(), Ok(1));
+        assert_eq!(store.get_address_txs(address).unwrap().len(), 3);
+        store.add_tx_height("2-1", 2).unwrap();
+        assert_eq!(store.get_tx_height("2-1"), Ok(Some(2)));
+        assert_eq!(store.get_address_txs(address).unwrap().len(), 3);
+        store.remove_tx_height("2-1").unwrap();
+        assert_eq!(store.get_tx_height("2-1"), Ok(None));
+
+        assert_eq!(store.get_supply(), Ok(0));
+        store.set_supply(1024).unwrap();
+        assert_eq!(store.get_supply(), Ok(1024));
+        assert_eq!(store.get_block_hashes(), Ok(vec!["aa".into()]));
+    }
+
+    #[test]
+    fn test_memory_store() {
+        check_store(&MemoryStore::new());
+    }
+
+    #[test]
+    fn test_rocks_store() {
+        let dir = tempfile::tempdir().unwrap();
+        check_store(&RocksStore::open(&format!("{}/", dir.path().display())));
+    }
+}
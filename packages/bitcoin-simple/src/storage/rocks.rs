@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+
+use crate::{
+    block::{Block, BlockHeader},
+    crypto::Address,
+};
+
+use super::{db, ChainStore, Store, TxLocation};
+
+/// Chain state persisted in RocksDB databases under the node's data directory.
+pub struct RocksStore {
+    blocks: Store,
+    blocks_metadata: Store,
+    balances: Store,
+    address_txs: Store,
+}
+
+impl RocksStore {
+    pub fn open(data_dir: &str) -> Self {
+        Self {
+            blocks: db::blocks(false, data_dir),
+            blocks_metadata: db::blocks_metadata(false, data_dir),
+            balances: db::balances(false, data_dir),
+            address_txs: db::address_txs(false, data_dir),
+        }
+    }
+}
+
+impl ChainStore for RocksStore {
+    fn get_block(&self, block_hash: &str) -> Result<Option<Block>, String> {
+        super::get_block(&self.blocks, block_hash)
+    }
+
+    fn add_block(&self, block: &Block) -> Result<(), String> {
+        super::add_block(&self.blocks, block)
+    }
+
+    fn get_header(&self, block_hash: &str) -> Result<Option<BlockHeader>, String> {
+        super::get_header(&self.blocks, block_hash)
+    }
+
+    fn add_header(&self, header: &BlockHeader) -> Result<(), String> {
+        super::add_header(&self.blocks, header)
+    }
+
+    fn prune_block(&self, block_hash: &str) -> Result<(), String> {
+        super::prune_block(&self.blocks, block_hash)
+    }
+
+    fn get_prune_height(&self) -> Result<u32, String> {
+        super::get_prune_height(&self.blocks_metadata)
+    }
+
+    fn set_prune_height(&self, height: u32) -> Result<(), String> {
+        super::set_prune_height(&self.blocks_metadata, height)
+    }
+
+    fn get_block_hash(&self, block_number: u32) -> Result<Option<String>, String> {
+        super::get_block_hash(&self.blocks_metadata, block_number)
+    }
+
+    fn get_block_hashes(&self) -> Result<Vec<String>, String> {
+        super::get_block_hashes(&self.blocks_metadata)
+    }
+
+    fn get_block_height(&self, block_hash: &str) -> Result<Option<u32>, String> {
+        super::get_block_height(&self.blocks_metadata, block_hash)
+    }
+
+    fn get_latest_block_hash(&self) -> Result<Option<String>, String> {
+        super::get_latest_block_hash(&self.blocks_metadata)
+    }
+
+    fn set_latest_block_hash(&self, block_hash: &str, height: u32) -> Result<(), String> {
+        super::set_latest_block_hash(&self.blocks_metadata, block_hash, height)
+    }
+
+    fn unset_latest_block_hash(
+        &self,
+        block_hash: &str,
+        height: u32,
+        prev_block_hash: Option<&str>,
+    ) -> Result<(), String> {
+        super::unset_latest_block_hash(&self.blocks_metadata, block_hash, height, prev_block_hash)
+    }
+
+    fn get_balance(&self, address: Address) -> Result<Option<u32>, String> {
+        super::get_balance(&self.balances, address)
+    }
+
+    fn set_balance(&self, address: Address, balance: u32) -> Result<(), String> {
+        super::set_balance(&self.balances, address, balance)
+    }
+
+    fn get_balances(&self) -> Result<HashMap<Address, u32>, String> {
+        super::get_balances(&self.balances)
+    }
+
+    fn get_supply(&self) -> Result<u64, String> {
+        super::get_supply(&self.blocks_metadata)
+    }
+
+    fn set_supply(&self, supply: u64) -> Result<(), String> {
+        super::set_supply(&self.blocks_metadata, supply)
+    }
+
+    fn add_address_tx(&self, address: Address, location: &TxLocation) -> Result<(), String> {
+        super::add_address_tx(&self.address_txs, address, location)
+    }
+
+    fn remove_address_tx(&self, address: Address, location: &TxLocation) -> Result<(), String> {
+        super::remove_address_tx(&self.address_txs, address, location)
+    }
+
+    fn get_address_txs(&self, address: Address) -> Result<Vec<TxLocation>, String> {
+        super::get_address_txs(&self.address_txs, address)
+    }
+
+    fn get_tx_height(&self, tx_id: &str) -> Result<Option<u32>, String> {
+        super::get_tx_height(&self.address_txs, tx_id)
+    }
+
+    fn add_tx_height(&self, tx_id: &str, height: u32) -> Result<(), String> {
+        super::add_tx_height(&self.address_txs, tx_id, height)
+    }
+
+    fn remove_tx_height(&self, tx_id: &str) -> Result<(), String> {
+        super::remove_tx_height(&self.address_txs, tx_id)
+    }
+
+    fn get_spend(&self, sender: Address, spend_id: &str) -> Result<Option<String>, String> {
+        super::get_spend(&self.address_txs, sender, spend_id)
+    }
+
+    fn add_spend(&self, sender: Address, spend_id: &str, tx_id: &str) -> Result<(), String> {
+        super::add_spend(&self.address_txs, sender, spend_id, tx_id)
+    }
+
+    fn remove_spend(&self, sender: Address, spend_id: &str) -> Result<(), String> {
+        super::remove_spend(&self.address_txs, sender, spend_id)
+    }
+
+    fn get_index_version(&self) -> Result<u32, String> {
+        super::get_index_version(&self.address_txs)
+    }
+
+    fn set_index_version(&self, version: u32) -> Result<(), String> {
+        super::set_index_version(&self.address_txs, version)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        for db in [
+            &self.blocks,
+            &self.blocks_metadata,
+            &self.balances,
+            &self.address_txs,
+        ] {
+            db.flush().map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,34 +1,137 @@
 pub mod model;
 
-use std::time::{SystemTime, UNIX_EPOCH};
-
 pub use model::*;
 
-use crate::crypto::{self, key};
+use crate::crypto::{self, musig::KeyAggContext, Address, SignatureScheme};
+use crate::script::{Instruction, Script};
+
+pub fn create_signed(keypair: &crypto::KeyPair, to: Address, amount: u32) -> SignedTransaction {
+    create_signed_with(keypair, to, amount, SignatureScheme::Ecdsa)
+}
 
-pub fn create_signed(
+pub fn create_signed_with(
     keypair: &crypto::KeyPair,
-    to: key::PublicKey,
+    to: Address,
     amount: u32,
+    scheme: SignatureScheme,
 ) -> SignedTransaction {
-    let from = keypair.public_key;
-    let created_at = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64;
-
-    let tx = Transaction {
-        tx_id: Transaction::generate_tx_id(from, to, amount, created_at),
-        from,
-        to,
-        amount,
-        created_at,
-    };
+    let mut tx = Transaction::new(keypair.public_key, to, amount);
+    tx.scheme = scheme;
+    sign(keypair, tx, |_| Script::default())
+}
+
+/// Transfer paying `fee` to the miner, which can be fee bumped while
+/// unconfirmed when `replaceable`
+pub fn create_with_fee(
+    keypair: &crypto::KeyPair,
+    to: Address,
+    amount: u32,
+    scheme: SignatureScheme,
+    fee: u32,
+    replaceable: bool,
+) -> SignedTransaction {
+    let mut tx = Transaction::new(keypair.public_key, to, amount);
+    tx.scheme = scheme;
+    tx.fee = fee;
+    tx.replaceable = replaceable;
+    sign(keypair, tx, |_| Script::default())
+}
 
-    let sig = keypair.sign(&tx.hash());
+/// Transfer paying every one of `outputs` along with `to` in one transaction
+pub fn create_with_outputs(
+    keypair: &crypto::KeyPair,
+    to: Address,
+    amount: u32,
+    outputs: Vec<Output>,
+    scheme: SignatureScheme,
+    fee: u32,
+) -> SignedTransaction {
+    let mut tx = Transaction::new(keypair.public_key, to, amount);
+    tx.outputs = outputs;
+    tx.scheme = scheme;
+    tx.fee = fee;
+    sign(keypair, tx, |_| Script::default())
+}
+
+/// Same transfer as `original` paying `fee` instead, conflicting with it.
+/// It stays replaceable so it can be bumped again.
+pub fn create_replacement(
+    keypair: &crypto::KeyPair,
+    original: &Transaction,
+    fee: u32,
+) -> SignedTransaction {
+    let mut tx = Transaction::new(keypair.public_key, original.to, original.amount);
+    tx.outputs = original.outputs.clone();
+    tx.lock_time = original.lock_time;
+    tx.relative_lock = original.relative_lock.clone();
+    tx.scheme = original.scheme;
+    tx.fee = fee;
+    tx.replaceable = true;
+    tx.replaces = Some(original.spend_id().to_string());
+    sign(keypair, tx, |_| Script::default())
+}
+
+/// Unsigned spend from the address of an aggregated key, its signers sign
+/// `hash()` together, see `crypto::musig`.
+pub fn create_aggregate_spend(key_agg: &KeyAggContext, to: Address, amount: u32) -> Transaction {
+    let mut tx = Transaction::new(key_agg.aggregated_key(), to, amount);
+    tx.scheme = SignatureScheme::Schnorr;
+    tx.tx_id = tx.serialize();
+    tx
+}
+
+/// Transfer that can't be included in a block before its locks expire
+pub fn create_locked(
+    keypair: &crypto::KeyPair,
+    to: Address,
+    amount: u32,
+    lock_time: u32,
+    relative_lock: Option<RelativeLock>,
+) -> SignedTransaction {
+    let mut tx = Transaction::new(keypair.public_key, to, amount);
+    tx.lock_time = lock_time;
+    tx.relative_lock = relative_lock;
+
+    sign(keypair, tx, |_| Script::default())
+}
+
+/// Spends from the address of `script`, `unlock` builds the unlocking script
+/// once the transaction to sign is known.
+pub fn create_script_spend(
+    keypair: &crypto::KeyPair,
+    script: Script,
+    to: Address,
+    amount: u32,
+    unlock: impl FnOnce(&Transaction) -> Script,
+) -> SignedTransaction {
+    let mut tx = Transaction::new(keypair.public_key, to, amount);
+    tx.script = Some(script);
+
+    sign(keypair, tx, unlock)
+}
+
+/// Signature of the transaction to push in an unlocking script
+pub fn script_sig(keypair: &crypto::KeyPair, tx: &Transaction) -> Instruction {
+    Instruction::Push(keypair.sign(&tx.hash()).serialize_der().to_vec())
+}
+
+/// Sets the transaction's id and signs it
+pub fn sign(
+    keypair: &crypto::KeyPair,
+    mut tx: Transaction,
+    unlock: impl FnOnce(&Transaction) -> Script,
+) -> SignedTransaction {
+    tx.tx_id = tx.serialize();
+
+    let sig = match tx.scheme {
+        SignatureScheme::Ecdsa => keypair.sign(&tx.hash()).to_string(),
+        SignatureScheme::Schnorr => keypair.sign_schnorr(&tx.hash()).to_string(),
+    };
+    let unlocking_script = unlock(&tx);
 
     SignedTransaction {
         transaction: tx,
-        sig: sig.to_string(),
+        sig,
+        unlocking_script,
     }
 }
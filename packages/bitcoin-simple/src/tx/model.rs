@@ -1,18 +1,69 @@
 use std::fmt::{Debug, Display};
+use std::iter;
 use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use secp256k1::{ecdsa::Signature, Message, PublicKey, Secp256k1};
+use secp256k1::{ecdsa::Signature, schnorr, Message, PublicKey, Secp256k1};
 use serde::{Deserialize, Serialize};
 
-use crate::crypto;
+use crate::crypto::{self, Address, SignatureScheme};
+use crate::script::{self, Script, ScriptContext};
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Transaction {
     pub tx_id: String,
     pub from: PublicKey,
-    pub to: PublicKey,
+    pub to: Address,
     pub amount: u32,
+    /// Further recipients paid along with `to`, all of them or none
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<Output>,
     pub created_at: u64,
+    /// Locking script of the script address the amount is spent from, `None`
+    /// when spending from the signer's own address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<Script>,
+    /// Earliest block height, or unix time in seconds from `LOCKTIME_THRESHOLD`
+    /// on, the transaction can be included at. 0 for none.
+    #[serde(default)]
+    pub lock_time: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relative_lock: Option<RelativeLock>,
+    /// Scheme of `sig`, Schnorr signatures verify against `from`'s x-only key,
+    /// which can be an aggregated key
+    #[serde(default, skip_serializing_if = "SignatureScheme::is_ecdsa")]
+    pub scheme: SignatureScheme,
+    /// Paid to the miner on top of `amount`
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub fee: u32,
+    /// Opts in to being replaced by a higher fee spend while unconfirmed, like BIP125
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub replaceable: bool,
+    /// Spend this transaction replaces, see `spend_id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replaces: Option<String>,
+}
+
+/// A recipient of a transaction besides `to`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Output {
+    pub to: Address,
+    pub amount: u32,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+/// Lock times below it are block heights, the others unix times, like Bitcoin's nLockTime
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+/// Keeps a transaction out of the chain until `blocks` blocks after the one
+/// including transaction `tx_id`, e.g. a vault's unvaulting transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RelativeLock {
+    pub tx_id: String,
+    pub blocks: u32,
 }
 
 impl Debug for Transaction {
@@ -21,27 +72,142 @@ impl Debug for Transaction {
             .field("from", &format!("{}", self.from))
             .field("to", &format!("{}", self.to))
             .field("amount", &self.amount)
+            .field("fee", &self.fee)
             .finish()
     }
 }
 
 impl Transaction {
+    /// Unsigned transaction created now, its id is set when signing
+    pub fn new(from: PublicKey, to: Address, amount: u32) -> Self {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        Self {
+            tx_id: String::new(),
+            from,
+            to,
+            amount,
+            outputs: Vec::new(),
+            created_at,
+            script: None,
+            lock_time: 0,
+            relative_lock: None,
+            scheme: SignatureScheme::Ecdsa,
+            fee: 0,
+            replaceable: false,
+            replaces: None,
+        }
+    }
+
+    /// Everything but the id and signatures, which is what the id is made of.
+    /// Optional fields only add to it when set, so plain transfers keep their ids.
     pub fn serialize(&self) -> String {
-        Self::generate_tx_id(self.from, self.to, self.amount, self.created_at)
+        let mut serialized = format!(
+            "{}{}{}{}",
+            self.from,
+            self.to,
+            hex::encode(format!("{}", self.amount)),
+            hex::encode(format!("{}", self.created_at)),
+        );
+
+        if let Some(script) = &self.script {
+            serialized += &hex::encode(script.hash());
+        }
+        if self.lock_time != 0 {
+            serialized += &hex::encode(format!("lock_time{}", self.lock_time));
+        }
+        if let Some(lock) = &self.relative_lock {
+            serialized += &hex::encode(format!("relative_lock{}:{}", lock.tx_id, lock.blocks));
+        }
+        // The signature can't be passed off as another scheme's
+        if self.scheme == SignatureScheme::Schnorr {
+            serialized += &hex::encode("schnorr");
+        }
+        if self.fee != 0 {
+            serialized += &hex::encode(format!("fee{}", self.fee));
+        }
+        if self.replaceable {
+            serialized += &hex::encode("replaceable");
+        }
+        if let Some(spend_id) = &self.replaces {
+            serialized += &hex::encode(format!("replaces{spend_id}"));
+        }
+        for output in &self.outputs {
+            serialized += &hex::encode(format!("output{}:{}", output.to, output.amount));
+        }
+
+        serialized
+    }
+
+    /// Address the amount is taken from, on the receiver's network
+    pub fn sender(&self) -> Address {
+        match &self.script {
+            Some(script) => Address::from_script(script, self.to.network),
+            None => Address::from_public_key(&self.from, self.to.network),
+        }
+    }
+
+    /// What the transaction spends, its own id unless it replaces another
+    /// spend. Two transactions of a sender with the same spend id conflict,
+    /// like Bitcoin transactions spending the same output, so only one of
+    /// them can confirm.
+    pub fn spend_id(&self) -> &str {
+        self.replaces.as_deref().unwrap_or(&self.tx_id)
+    }
+
+    /// Recipients and the amounts paid to them, `to` first
+    pub fn payments(&self) -> impl Iterator<Item = (Address, u32)> + '_ {
+        iter::once((self.to, self.amount))
+            .chain(self.outputs.iter().map(|output| (output.to, output.amount)))
+    }
+
+    /// Paid to the recipients
+    pub fn total_amount(&self) -> u64 {
+        self.payments().map(|(_, amount)| u64::from(amount)).sum()
+    }
+
+    /// Taken from the sender's balance
+    pub fn debit(&self) -> u64 {
+        self.total_amount() + u64::from(self.fee)
     }
 
     pub fn hash(&self) -> Vec<u8> {
         crypto::sha256(self.serialize())
     }
 
-    pub fn generate_tx_id(from: PublicKey, to: PublicKey, amount: u32, created_at: u64) -> String {
-        format!(
-            "{}{}{}{}",
-            from,
-            to,
-            hex::encode(format!("{}", amount)),
-            hex::encode(format!("{}", created_at)),
-        )
+    /// Checks the time locks allow the transaction in a block at `height`,
+    /// `median_time_past` being the median time of the blocks before it.
+    /// `confirmed_at` finds the height of the block including a transaction.
+    pub fn verify_locks(
+        &self,
+        height: u32,
+        median_time_past: u64,
+        confirmed_at: impl Fn(&str) -> Result<Option<u32>, String>,
+    ) -> Result<(), String> {
+        let unlocked = if self.lock_time < LOCKTIME_THRESHOLD {
+            self.lock_time <= height
+        } else {
+            u64::from(self.lock_time) <= median_time_past
+        };
+        if !unlocked {
+            return Err(format!("Locked until {}", self.lock_time));
+        }
+
+        if let Some(lock) = &self.relative_lock {
+            let confirmed_at = confirmed_at(&lock.tx_id)?
+                .ok_or_else(|| format!("Locked until {} confirms", lock.tx_id))?;
+            if height < confirmed_at.saturating_add(lock.blocks) {
+                return Err(format!(
+                    "Locked until {} blocks after {}",
+                    lock.blocks, lock.tx_id
+                ));
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -49,6 +215,9 @@ impl Transaction {
 pub struct SignedTransaction {
     pub transaction: Transaction,
     pub sig: String,
+    /// Data satisfying the transaction's locking script
+    #[serde(default, skip_serializing_if = "Script::is_empty")]
+    pub unlocking_script: Script,
 }
 
 impl Display for SignedTransaction {
@@ -62,12 +231,47 @@ impl SignedTransaction {
         let secp = Secp256k1::verification_only();
         let unsigned_tx_hash = Message::from_digest_slice(self.transaction.hash().as_slice())
             .expect("Message must valid");
-        let sig = Signature::from_str(self.sig.as_str()).expect("Signature must valid");
-        secp.verify_ecdsa(&unsigned_tx_hash, &sig, &self.transaction.from)
-            .is_ok()
+        // Signatures come from peers and RPC clients, malformed ones are invalid
+        match self.transaction.scheme {
+            SignatureScheme::Ecdsa => Signature::from_str(&self.sig).is_ok_and(|sig| {
+                secp.verify_ecdsa(&unsigned_tx_hash, &sig, &self.transaction.from)
+                    .is_ok()
+            }),
+            SignatureScheme::Schnorr => schnorr::Signature::from_str(&self.sig).is_ok_and(|sig| {
+                let (key, _) = self.transaction.from.x_only_public_key();
+                secp.verify_schnorr(&sig, &unsigned_tx_hash, &key).is_ok()
+            }),
+        }
+    }
+
+    /// Checks the unlocking script satisfies the locking script of the spent
+    /// script address, for a transaction included at `height`.
+    pub fn verify_script(&self, height: u32) -> Result<(), String> {
+        let locking = match &self.transaction.script {
+            Some(script) => script,
+            None if self.unlocking_script.is_empty() => return Ok(()),
+            None => return Err("Unlocking script without a locking script".to_string()),
+        };
+
+        let context = ScriptContext {
+            tx_hash: &self.transaction.hash(),
+            height,
+        };
+        script::verify(&self.unlocking_script, locking, &context)
     }
 
     pub fn tx_id(&self) -> String {
         self.transaction.tx_id.clone()
     }
+
+    /// Hash of the whole signed transaction, which unlike the id covers the
+    /// signature and unlocking script. Blocks commit to it.
+    pub fn wtx_id(&self) -> String {
+        hex::encode(crypto::sha256(format!(
+            "{}{}{}",
+            self.transaction.serialize(),
+            self.sig,
+            self.unlocking_script
+        )))
+    }
 }